@@ -0,0 +1,118 @@
+use pyo3::prelude::*;
+use starlark::analysis::{EvalSeverity, Lint};
+
+use crate::codemap::{PyFileSpan, PyResolvedFileSpan};
+
+/// A standardised set of severities.
+#[pyclass(
+    module = "xingque",
+    name = "EvalSeverity",
+    rename_all = "SCREAMING_SNAKE_CASE",
+    frozen,
+    eq,
+    hash
+)]
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub(crate) enum PyEvalSeverity {
+    /// An error while the program was being parsed.
+    Error,
+    /// The program parsed, but might not execute without error.
+    Warning,
+    /// This could be changed, but is not a blocking problem.
+    Advice,
+    /// This should be ignored
+    Disabled,
+}
+
+impl From<EvalSeverity> for PyEvalSeverity {
+    fn from(value: EvalSeverity) -> Self {
+        match value {
+            EvalSeverity::Error => Self::Error,
+            EvalSeverity::Warning => Self::Warning,
+            EvalSeverity::Advice => Self::Advice,
+            EvalSeverity::Disabled => Self::Disabled,
+        }
+    }
+}
+
+impl From<PyEvalSeverity> for EvalSeverity {
+    fn from(value: PyEvalSeverity) -> Self {
+        match value {
+            PyEvalSeverity::Error => Self::Error,
+            PyEvalSeverity::Warning => Self::Warning,
+            PyEvalSeverity::Advice => Self::Advice,
+            PyEvalSeverity::Disabled => Self::Disabled,
+        }
+    }
+}
+
+/// A lint produced by `AstModule.lint`.
+#[pyclass(module = "xingque", name = "Lint", frozen)]
+pub(crate) struct PyLint(Lint);
+
+impl From<Lint> for PyLint {
+    fn from(value: Lint) -> Self {
+        Self(value)
+    }
+}
+
+#[pymethods]
+impl PyLint {
+    fn __repr__(slf: &Bound<'_, Self>) -> PyResult<String> {
+        let class_name = slf.get_type().qualname()?;
+        let me = slf.borrow();
+        Ok(format!(
+            "<{} {} at {}: {}>",
+            class_name,
+            me.0.short_name,
+            me.0.location.resolve(),
+            me.0.problem,
+        ))
+    }
+
+    fn __str__(&self) -> String {
+        self.0.to_string()
+    }
+
+    /// Which code location does this lint refer to.
+    #[getter]
+    fn location(&self) -> PyFileSpan {
+        self.0.location.clone().into()
+    }
+
+    /// The resolved code location this lint refers to.
+    #[getter]
+    fn resolved_location(&self) -> PyResolvedFileSpan {
+        self.0.location.resolve().into()
+    }
+
+    /// kebab-case constant describing this issue, e.g. `missing-return`.
+    #[getter]
+    fn short_name(&self) -> &str {
+        &self.0.short_name
+    }
+
+    #[getter]
+    fn severity(&self) -> PyEvalSeverity {
+        self.0.severity.into()
+    }
+
+    /// Is this code highly-likely to be wrong, rather than merely
+    /// stylistically non-ideal.
+    #[getter]
+    fn serious(&self) -> bool {
+        matches!(self.0.severity, EvalSeverity::Error | EvalSeverity::Warning)
+    }
+
+    /// A description of the underlying problem.
+    #[getter]
+    fn problem(&self) -> &str {
+        &self.0.problem
+    }
+
+    /// The source code at `location`.
+    #[getter]
+    fn original(&self) -> &str {
+        &self.0.original
+    }
+}
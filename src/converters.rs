@@ -0,0 +1,420 @@
+use std::cell::{Cell, RefCell};
+use std::rc::Rc;
+use std::sync::Mutex;
+
+use pyo3::exceptions::{PyRecursionError, PyTypeError, PyValueError};
+use pyo3::prelude::*;
+use pyo3::types::PyType;
+use starlark::typing::TyBasic;
+use starlark::values::enumeration::FrozenEnumType;
+use starlark::values::typing::TypeCompiled;
+use starlark::values::{
+    FrozenHeap, FrozenHeapRef, FrozenValue, Heap, OwnedFrozenValue, Value, ValueLike,
+};
+
+use crate::eval::bound_conversion_settings;
+use crate::values::PyFrozenValue;
+
+/// Converters registered for Python types, in registration order.
+///
+/// Like `copyreg` or `functools.singledispatch` registrations, converters are
+/// global to the process, as the Python types they are keyed on are.
+static CONVERTERS: Mutex<Vec<Converter>> = Mutex::new(Vec::new());
+
+/// How deeply converters may be nested in a single conversion, e.g. through
+/// converters returning values to be converted by other converters in turn.
+const MAX_CONVERTER_DEPTH: usize = 64;
+
+thread_local! {
+    static CONVERTER_DEPTH: Cell<usize> = const { Cell::new(0) };
+
+    /// The conversion settings of the evaluator doing the evaluation, if any.
+    static CONVERSION: RefCell<Option<Rc<ConversionSettings>>> = const { RefCell::new(None) };
+}
+
+/// How values are converted between Python and Starlark, as set for an
+/// evaluator.
+#[derive(Default)]
+pub(crate) struct ConversionSettings {
+    /// Whether Starlark values without a Python counterpart are rejected,
+    /// instead of being wrapped in `Value`s and `FrozenValue`s.
+    pub(crate) strict: bool,
+    /// Whether Python dataclass, `typing.NamedTuple`, attrs class and
+    /// `types.SimpleNamespace` instances and Starlark structs are converted
+    /// to each other.
+    pub(crate) structs: bool,
+    /// The Python type Starlark structs are converted to, if not
+    /// `types.SimpleNamespace`.
+    pub(crate) struct_type: Option<PyObject>,
+}
+
+impl ConversionSettings {
+    pub(crate) fn clone_ref(&self, py: Python<'_>) -> Self {
+        Self {
+            strict: self.strict,
+            structs: self.structs,
+            struct_type: self.struct_type.as_ref().map(|x| x.clone_ref(py)),
+        }
+    }
+}
+
+struct Converter {
+    py_type: Py<PyType>,
+    to_starlark: PyObject,
+    /// The name of the Starlark type converted back, and the converter.
+    from_starlark: Option<(String, PyObject)>,
+}
+
+/// Registers converters for marshalling instances of `py_type`, replacing the
+/// ones already registered for it, if any. Converters are global to the
+/// process, like the Python types they are registered for.
+///
+/// `to_starlark` is called with Python values of the type, or of a subclass
+/// (the converter of the closest base class winning), before trying the
+/// built-in conversions, so that e.g. `Decimal`s are not taken for floats.
+/// Its result is converted to Starlark in turn, which may involve other
+/// converters, up to a nesting depth of 64 converters.
+///
+/// `from_starlark` is called with Starlark values of the type named
+/// `sl_type`, as given by `type()` in Starlark, that would otherwise become
+/// opaque `Value`s or `FrozenValue`s in Python. It returns the Python value to
+/// use instead, or `NotImplemented` to leave the value to the other
+/// converters for the type. The converters registered last are tried first.
+#[pyfunction]
+#[pyo3(signature = (py_type, to_starlark, from_starlark = None, *, sl_type = None))]
+pub(crate) fn register_converter(
+    py_type: Bound<'_, PyType>,
+    to_starlark: PyObject,
+    from_starlark: Option<PyObject>,
+    sl_type: Option<String>,
+) -> PyResult<()> {
+    let from_starlark = match (from_starlark, sl_type) {
+        (Some(f), Some(sl_type)) => Some((sl_type, f)),
+        (None, None) => None,
+        (Some(_), None) => {
+            return Err(PyTypeError::new_err(
+                "sl_type is required along with from_starlark",
+            ))
+        }
+        (None, Some(_)) => {
+            return Err(PyTypeError::new_err(
+                "sl_type is only meaningful along with from_starlark",
+            ))
+        }
+    };
+
+    let mut converters = CONVERTERS.lock().unwrap();
+    // drop the replaced converters only after unlocking, as that may run
+    // arbitrary Python code
+    let replaced = take_converters_for(&mut converters, &py_type);
+    converters.push(Converter {
+        py_type: py_type.unbind(),
+        to_starlark,
+        from_starlark,
+    });
+    drop(converters);
+    drop(replaced);
+    Ok(())
+}
+
+/// Removes the converters registered for `py_type`, returning whether there
+/// were any.
+#[pyfunction]
+pub(crate) fn unregister_converter(py_type: Bound<'_, PyType>) -> bool {
+    let removed = take_converters_for(&mut CONVERTERS.lock().unwrap(), &py_type);
+    !removed.is_empty()
+}
+
+fn take_converters_for(
+    converters: &mut Vec<Converter>,
+    py_type: &Bound<'_, PyType>,
+) -> Vec<Converter> {
+    let (taken, kept) = converters.drain(..).partition(|x| x.py_type.is(py_type));
+    *converters = kept;
+    taken
+}
+
+/// Converts `value` with the converter registered for its type, if any, then
+/// converts the result to Starlark with `convert`.
+pub(crate) fn to_starlark_via_converter<'py, R>(
+    value: &Bound<'py, PyAny>,
+    convert: impl FnOnce(&Bound<'py, PyAny>) -> PyResult<R>,
+) -> PyResult<Option<R>> {
+    struct Leave;
+
+    impl Drop for Leave {
+        fn drop(&mut self) {
+            CONVERTER_DEPTH.with(|x| x.set(x.get() - 1));
+        }
+    }
+
+    let py = value.py();
+    if CONVERTERS.lock().unwrap().is_empty() {
+        return Ok(None);
+    }
+    let mro = value.get_type().mro();
+    let to_starlark = {
+        let converters = CONVERTERS.lock().unwrap();
+        let found = mro.iter().find_map(|cls| {
+            converters
+                .iter()
+                .find(|x| x.py_type.is(&cls))
+                .map(|x| (cls, x.to_starlark.clone_ref(py)))
+        });
+        match found {
+            Some(x) => x,
+            None => return Ok(None),
+        }
+    };
+
+    let (cls, to_starlark) = to_starlark;
+    if CONVERTER_DEPTH.with(Cell::get) >= MAX_CONVERTER_DEPTH {
+        return Err(PyRecursionError::new_err(format!(
+            "converters nested too deeply while converting {}",
+            cls
+        )));
+    }
+    CONVERTER_DEPTH.with(|x| x.set(x.get() + 1));
+    let _leave = Leave;
+
+    let result = to_starlark.bind(py).call1((value,))?;
+    if result.is_instance(&cls)? {
+        return Err(PyValueError::new_err(format!(
+            "the converter for {} returned another instance of it",
+            cls
+        )));
+    }
+    convert(&result).map(Some)
+}
+
+/// Returns the `from_starlark` converters for Starlark values of type
+/// `sl_type`, the last registered first.
+pub(crate) fn from_starlark_converters_for(py: Python<'_>, sl_type: &str) -> Vec<PyObject> {
+    let converters = CONVERTERS.lock().unwrap();
+    converters
+        .iter()
+        .rev()
+        .filter_map(|x| match &x.from_starlark {
+            Some((t, f)) if t == sl_type => Some(f.clone_ref(py)),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Converts an otherwise opaque Starlark value, already wrapped for Python as
+/// `value`, with the first of `converters` accepting it, if any.
+pub(crate) fn from_starlark_via_converters(
+    value: &Bound<'_, PyAny>,
+    converters: Vec<PyObject>,
+) -> PyResult<Option<PyObject>> {
+    let py = value.py();
+    for f in converters {
+        let result = f.bind(py).call1((value,))?;
+        if !result.is(&py.NotImplemented()) {
+            return Ok(Some(result.unbind()));
+        }
+    }
+    Ok(None)
+}
+
+/// Starlark record and enum types registered for Python types, in
+/// registration order.
+static SL_TYPES: Mutex<Vec<SlTypeEntry>> = Mutex::new(Vec::new());
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum SlTypeKind {
+    Record,
+    Enum,
+}
+
+impl SlTypeKind {
+    fn name(self) -> &'static str {
+        match self {
+            Self::Record => "record",
+            Self::Enum => "enum",
+        }
+    }
+}
+
+struct SlTypeEntry {
+    kind: SlTypeKind,
+    py_type: Py<PyType>,
+    sl_type: OwnedFrozenValue,
+    /// The type of the values, living on `_matcher_heap`.
+    matcher: TypeCompiled<FrozenValue>,
+    _matcher_heap: FrozenHeapRef,
+}
+
+/// Registers `record_type`, a frozen Starlark record type, for converting
+/// instances of `py_type` to, replacing the type already registered for it, if
+/// any.
+///
+/// Instances of `py_type` and its subclasses then become records instead of
+/// structs, whether struct conversion is enabled or not, and records of the
+/// type are converted back by calling `py_type` with their fields as keyword
+/// arguments.
+#[pyfunction]
+pub(crate) fn register_record_type(
+    py_type: Bound<'_, PyType>,
+    record_type: &Bound<'_, PyFrozenValue>,
+) -> PyResult<()> {
+    register_sl_type(SlTypeKind::Record, py_type, record_type)
+}
+
+/// Removes the record type registered for `py_type`, returning whether there
+/// was one.
+#[pyfunction]
+pub(crate) fn unregister_record_type(py_type: Bound<'_, PyType>) -> bool {
+    unregister_sl_type(SlTypeKind::Record, &py_type)
+}
+
+/// Registers `enum_type`, a frozen Starlark enum type, for converting the
+/// members of `py_type`, a Python enum, to, replacing the type already
+/// registered for it, if any.
+///
+/// Members are converted to the Starlark enum value of the same value, and
+/// Starlark enum values of the type are converted back to the member of the
+/// same value.
+#[pyfunction]
+pub(crate) fn register_enum_type(
+    py_type: Bound<'_, PyType>,
+    enum_type: &Bound<'_, PyFrozenValue>,
+) -> PyResult<()> {
+    register_sl_type(SlTypeKind::Enum, py_type, enum_type)
+}
+
+/// Removes the enum type registered for `py_type`, returning whether there
+/// was one.
+#[pyfunction]
+pub(crate) fn unregister_enum_type(py_type: Bound<'_, PyType>) -> bool {
+    unregister_sl_type(SlTypeKind::Enum, &py_type)
+}
+
+fn register_sl_type(
+    kind: SlTypeKind,
+    py_type: Bound<'_, PyType>,
+    sl_type: &Bound<'_, PyFrozenValue>,
+) -> PyResult<()> {
+    let sl_type = sl_type.borrow().0.clone();
+    let matcher_heap = FrozenHeap::new();
+    let is_enum_type = sl_type.value().downcast_ref::<FrozenEnumType>().is_some();
+    let matcher = match TypeCompiled::new(sl_type.value(), &Heap::new()) {
+        // record types are not exposed by starlark-rust, but of the values
+        // that are types, only enum and record types are of custom types
+        Ok(x)
+            if match kind {
+                SlTypeKind::Enum => is_enum_type,
+                SlTypeKind::Record => {
+                    !is_enum_type && matches!(x.as_ty().iter_union(), [TyBasic::Custom(_)])
+                }
+            } =>
+        {
+            x.to_frozen(&matcher_heap)
+        }
+        _ => {
+            return Err(PyTypeError::new_err(format!(
+                "expected a frozen {} type assigned to a global variable, got {}",
+                kind.name(),
+                sl_type.value()
+            )))
+        }
+    };
+
+    let mut sl_types = SL_TYPES.lock().unwrap();
+    let (replaced, kept) = sl_types
+        .drain(..)
+        .partition::<Vec<_>, _>(|x| x.py_type.is(&py_type));
+    *sl_types = kept;
+    sl_types.push(SlTypeEntry {
+        kind,
+        py_type: py_type.unbind(),
+        sl_type,
+        matcher,
+        _matcher_heap: matcher_heap.into_ref(),
+    });
+    // drop the replaced entries only after unlocking, as that may run
+    // arbitrary Python code
+    drop(sl_types);
+    drop(replaced);
+    Ok(())
+}
+
+fn unregister_sl_type(kind: SlTypeKind, py_type: &Bound<'_, PyType>) -> bool {
+    let mut sl_types = SL_TYPES.lock().unwrap();
+    let (removed, kept) = sl_types
+        .drain(..)
+        .partition::<Vec<_>, _>(|x| x.kind == kind && x.py_type.is(py_type));
+    *sl_types = kept;
+    drop(sl_types);
+    !removed.is_empty()
+}
+
+/// Runs `f` with the conversion settings of the evaluator doing the
+/// evaluation.
+pub(crate) fn with_conversion<R>(settings: &Rc<ConversionSettings>, f: impl FnOnce() -> R) -> R {
+    struct Restore(Option<Rc<ConversionSettings>>);
+
+    impl Drop for Restore {
+        fn drop(&mut self) {
+            CONVERSION.with(|x| *x.borrow_mut() = self.0.take());
+        }
+    }
+
+    let _restore = Restore(CONVERSION.with(|x| x.borrow_mut().replace(settings.clone())));
+    f()
+}
+
+/// Returns the conversion settings of the evaluator doing the evaluation, or
+/// of the innermost evaluator bound to this thread with `with` outside
+/// evaluations, if any.
+fn conversion_settings(py: Python<'_>) -> Rc<ConversionSettings> {
+    CONVERSION
+        .with(|x| x.borrow().clone())
+        .or_else(|| bound_conversion_settings(py))
+        .unwrap_or_default()
+}
+
+pub(crate) fn is_strict_conversion_enabled(py: Python<'_>) -> bool {
+    conversion_settings(py).strict
+}
+
+pub(crate) fn is_struct_conversion_enabled(py: Python<'_>) -> bool {
+    conversion_settings(py).structs
+}
+
+/// Returns the Python type Starlark structs are to be converted to.
+pub(crate) fn struct_type(py: Python<'_>) -> PyResult<PyObject> {
+    if let Some(x) = conversion_settings(py).struct_type.as_ref() {
+        return Ok(x.clone_ref(py));
+    }
+    Ok(py
+        .import_bound("types")?
+        .getattr("SimpleNamespace")?
+        .unbind())
+}
+
+/// Returns the Starlark type registered for the type of `value` or its
+/// closest base class, if any.
+pub(crate) fn sl_type_for(value: &Bound<'_, PyAny>) -> Option<(SlTypeKind, OwnedFrozenValue)> {
+    if SL_TYPES.lock().unwrap().is_empty() {
+        return None;
+    }
+    let mro = value.get_type().mro();
+    let sl_types = SL_TYPES.lock().unwrap();
+    mro.iter().find_map(|cls| {
+        sl_types
+            .iter()
+            .find(|x| x.py_type.is(&cls))
+            .map(|x| (x.kind, x.sl_type.clone()))
+    })
+}
+
+/// Returns the Python type registered for the Starlark type of `value`, if it
+/// is a record or enum value of a registered type.
+pub(crate) fn py_type_for(py: Python<'_>, value: Value<'_>) -> Option<Py<PyType>> {
+    let sl_types = SL_TYPES.lock().unwrap();
+    sl_types
+        .iter()
+        .find(|x| x.matcher.matches(value))
+        .map(|x| x.py_type.clone_ref(py))
+}
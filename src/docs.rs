@@ -0,0 +1,640 @@
+use std::collections::BTreeMap;
+
+use pyo3::exceptions::{PyTypeError, PyValueError};
+use pyo3::prelude::*;
+use pyo3::types::PyDict;
+use starlark::codemap::CodeMap;
+use starlark::codemap::Spanned;
+use starlark::collections::SmallMap;
+use starlark::docs::markdown::render_doc_item;
+use starlark::docs::{
+    DocFunction, DocItem, DocMember, DocModule, DocParam, DocParams, DocProperty, DocReturn,
+    DocString, DocStringKind, DocType, FmtParam,
+};
+use starlark::environment::Globals;
+use starlark::syntax::AstModule;
+use starlark::typing::{Ty, TypeRenderConfig};
+use starlark::values::typing::TypeCompiled;
+use starlark::values::{Heap, ValueLike};
+use starlark_syntax::syntax::ast::{
+    AssignTargetP, AstLiteral, AstNoPayload, AstStmt, AstTypeExpr, DefP, ExprP, ParameterP, StmtP,
+};
+use starlark_syntax::syntax::module::AstModuleFields;
+use starlark_syntax::syntax::top_level_stmts::top_level_stmts;
+use starlark_syntax::syntax::type_expr::{TypeExprUnpackP, TypePathP};
+
+use crate::environment::{PyFrozenModule, PyGlobals};
+use crate::py2sl::SlPyObject;
+use crate::typing::PyTy;
+
+/// The documentation provided by a user for a specific module, object, function, etc.
+#[pyclass(module = "xingque", name = "DocString", frozen)]
+#[derive(Clone)]
+pub(crate) struct PyDocString(DocString);
+
+impl From<DocString> for PyDocString {
+    fn from(value: DocString) -> Self {
+        Self(value)
+    }
+}
+
+#[pymethods]
+impl PyDocString {
+    fn __repr__(slf: &Bound<'_, Self>) -> PyResult<String> {
+        let class_name = slf.get_type().qualname()?;
+        Ok(format!("<{} {:?}>", class_name, slf.borrow().0.summary))
+    }
+
+    /// The first line of a doc string. This has whitespace trimmed from it.
+    #[getter]
+    fn summary(&self) -> &str {
+        &self.0.summary
+    }
+
+    /// The contents of a doc string that follow the summary, and a single blank line.
+    /// This also has whitespace trimmed from it, and it is dedented.
+    #[getter]
+    fn details(&self) -> Option<&str> {
+        self.0.details.as_deref()
+    }
+}
+
+fn py_doc_string(x: &Option<DocString>) -> Option<PyDocString> {
+    x.clone().map(PyDocString::from)
+}
+
+/// The kinds of `DocParam`.
+#[pyclass(
+    module = "xingque",
+    name = "DocParamKind",
+    rename_all = "SCREAMING_SNAKE_CASE",
+    frozen,
+    eq,
+    hash
+)]
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub(crate) enum PyDocParamKind {
+    /// A regular parameter that may or may not have a default value.
+    Arg,
+    /// Represents the "*" argument.
+    NoArgs,
+    /// Represents the "/" argument from [PEP 570](https://peps.python.org/pep-0570/).
+    OnlyPosBefore,
+    /// Represents the "*args" style of argument.
+    Args,
+    /// Represents the "**kwargs" style of argument.
+    Kwargs,
+}
+
+/// An argument accepted by a function, or one of the `*` and `/` markers.
+#[pyclass(module = "xingque", name = "DocParam", frozen)]
+#[derive(Clone)]
+pub(crate) struct PyDocParam {
+    kind: PyDocParamKind,
+    param: Option<DocParam>,
+}
+
+impl From<FmtParam<&DocParam>> for PyDocParam {
+    fn from(value: FmtParam<&DocParam>) -> Self {
+        let (kind, param) = match value {
+            FmtParam::Regular(x) => (PyDocParamKind::Arg, Some(x.clone())),
+            FmtParam::Args(x) => (PyDocParamKind::Args, Some(x.clone())),
+            FmtParam::Kwargs(x) => (PyDocParamKind::Kwargs, Some(x.clone())),
+            FmtParam::Slash => (PyDocParamKind::OnlyPosBefore, None),
+            FmtParam::Star => (PyDocParamKind::NoArgs, None),
+        };
+        Self { kind, param }
+    }
+}
+
+#[pymethods]
+impl PyDocParam {
+    fn __repr__(slf: &Bound<'_, Self>) -> PyResult<String> {
+        let class_name = slf.get_type().qualname()?;
+        let me = slf.borrow();
+        Ok(match me.kind {
+            PyDocParamKind::NoArgs => format!("<{} *>", class_name),
+            PyDocParamKind::OnlyPosBefore => format!("<{} />", class_name),
+            _ => format!("<{} {}>", class_name, me.name().unwrap_or_default()),
+        })
+    }
+
+    #[getter]
+    fn kind(&self) -> PyDocParamKind {
+        self.kind
+    }
+
+    /// Name of the parameter, with the leading `*` or `**` of `*args` and
+    /// `**kwargs`, or `None` for the `*` and `/` markers.
+    #[getter]
+    fn name(&self) -> Option<String> {
+        let name = &self.param.as_ref()?.name;
+        Some(match self.kind {
+            PyDocParamKind::Args => format!("*{}", name),
+            PyDocParamKind::Kwargs => format!("**{}", name),
+            _ => name.clone(),
+        })
+    }
+
+    #[getter]
+    fn docs(&self) -> Option<PyDocString> {
+        py_doc_string(&self.param.as_ref()?.docs)
+    }
+
+    #[getter]
+    fn typ(&self) -> Option<PyTy> {
+        Some(self.param.as_ref()?.typ.clone().into())
+    }
+
+    /// Representation of the default value, if any.
+    #[getter]
+    fn default_value(&self) -> Option<&str> {
+        self.param.as_ref()?.default_value.as_deref()
+    }
+}
+
+/// Details about the return value of a function.
+#[pyclass(module = "xingque", name = "DocReturn", frozen)]
+#[derive(Clone)]
+pub(crate) struct PyDocReturn(DocReturn);
+
+impl From<DocReturn> for PyDocReturn {
+    fn from(value: DocReturn) -> Self {
+        Self(value)
+    }
+}
+
+#[pymethods]
+impl PyDocReturn {
+    /// Extra semantic details around the returned value's meaning.
+    #[getter]
+    fn docs(&self) -> Option<PyDocString> {
+        py_doc_string(&self.0.docs)
+    }
+
+    #[getter]
+    fn typ(&self) -> PyTy {
+        self.0.typ.clone().into()
+    }
+}
+
+/// Documents a single function.
+#[pyclass(module = "xingque", name = "DocFunction", frozen)]
+#[derive(Clone)]
+pub(crate) struct PyDocFunction(DocFunction);
+
+impl From<DocFunction> for PyDocFunction {
+    fn from(value: DocFunction) -> Self {
+        Self(value)
+    }
+}
+
+#[pymethods]
+impl PyDocFunction {
+    /// Documentation for the function.
+    ///
+    /// If the docstring has sections for arguments or the return value, they
+    /// are moved to the corresponding `DocParam` and `DocReturn`.
+    #[getter]
+    fn docs(&self) -> Option<PyDocString> {
+        py_doc_string(&self.0.docs)
+    }
+
+    /// The parameters that this function takes. Docs for these parameters
+    /// should generally be extracted from the main docstring's details.
+    #[getter]
+    fn params(&self) -> Vec<PyDocParam> {
+        self.0.params.fmt_params().map(Into::into).collect()
+    }
+
+    /// Details about what this function returns.
+    #[getter]
+    fn ret(&self) -> PyDocReturn {
+        self.0.ret.clone().into()
+    }
+}
+
+/// A single property of an object. These are explicitly not functions (see `DocMember`).
+#[pyclass(module = "xingque", name = "DocProperty", frozen)]
+#[derive(Clone)]
+pub(crate) struct PyDocProperty(DocProperty);
+
+impl From<DocProperty> for PyDocProperty {
+    fn from(value: DocProperty) -> Self {
+        Self(value)
+    }
+}
+
+#[pymethods]
+impl PyDocProperty {
+    #[getter]
+    fn docs(&self) -> Option<PyDocString> {
+        py_doc_string(&self.0.docs)
+    }
+
+    #[getter]
+    fn typ(&self) -> PyTy {
+        self.0.typ.clone().into()
+    }
+}
+
+/// Documents a type, with its methods and properties.
+#[pyclass(module = "xingque", name = "DocType", frozen)]
+#[derive(Clone)]
+pub(crate) struct PyDocType(DocType);
+
+impl From<DocType> for PyDocType {
+    fn from(value: DocType) -> Self {
+        Self(value)
+    }
+}
+
+#[pymethods]
+impl PyDocType {
+    #[getter]
+    fn docs(&self) -> Option<PyDocString> {
+        py_doc_string(&self.0.docs)
+    }
+
+    /// Name and details of each member of this type, in definition order.
+    #[getter]
+    fn members<'py>(&self, py: Python<'py>) -> PyResult<Bound<'py, PyDict>> {
+        let result = PyDict::new_bound(py);
+        for (k, v) in self.0.members.iter() {
+            result.set_item(k, py_from_doc_member(py, v))?;
+        }
+        Ok(result)
+    }
+
+    #[getter]
+    fn ty(&self) -> PyTy {
+        self.0.ty.clone().into()
+    }
+
+    /// The documentation of the type's constructor, if it can be called.
+    #[getter]
+    fn constructor(&self) -> Option<PyDocFunction> {
+        self.0.constructor.clone().map(Into::into)
+    }
+}
+
+/// Documents a full module.
+#[pyclass(module = "xingque", name = "DocModule", frozen)]
+#[derive(Clone)]
+pub(crate) struct PyDocModule(pub(crate) DocModule);
+
+impl From<DocModule> for PyDocModule {
+    fn from(value: DocModule) -> Self {
+        Self(value)
+    }
+}
+
+#[pymethods]
+impl PyDocModule {
+    /// In some cases (e.g. Rust native modules) the docstring is not available.
+    #[getter]
+    fn docs(&self) -> Option<PyDocString> {
+        py_doc_string(&self.0.docs)
+    }
+
+    /// Name and details of each symbol of this module, in definition order.
+    ///
+    /// Namespaces are documented as nested `DocModule`s.
+    #[getter]
+    fn members<'py>(&self, py: Python<'py>) -> PyResult<Bound<'py, PyDict>> {
+        let result = PyDict::new_bound(py);
+        for (k, v) in self.0.members.iter() {
+            let v = match v {
+                DocItem::Module(x) => PyDocModule::from(x.clone()).into_py(py),
+                DocItem::Type(x) => PyDocType::from(x.clone()).into_py(py),
+                DocItem::Member(x) => py_from_doc_member(py, x),
+            };
+            result.set_item(k, v)?;
+        }
+        Ok(result)
+    }
+}
+
+fn py_from_doc_member(py: Python<'_>, x: &DocMember) -> PyObject {
+    match x {
+        DocMember::Property(x) => PyDocProperty::from(x.clone()).into_py(py),
+        DocMember::Function(x) => PyDocFunction::from(x.clone()).into_py(py),
+    }
+}
+
+/// Extracts the documentation of a parsed but not yet evaluated module.
+///
+/// Only the top-level `def`s and assignments are considered, and private
+/// symbols (starting with `_`) are skipped, like `FrozenModule.documentation`
+/// does. Type annotations are resolved against `globals`; annotations
+/// referring to anything else, such as types defined in the module itself,
+/// are documented as `typing.Any`.
+pub(crate) fn doc_module_from_ast(ast: &AstModule, globals: &Globals) -> DocModule {
+    let codemap = ast.codemap();
+    let stmt = ast.statement();
+
+    let mut members = SmallMap::new();
+    for x in top_level_stmts(stmt) {
+        let (name, member) = match &x.node {
+            StmtP::Def(def) => (
+                &def.name.ident,
+                DocItem::Member(DocMember::Function(doc_function_from_def(
+                    codemap, def, globals,
+                ))),
+            ),
+            StmtP::Assign(assign) => match &assign.lhs.node {
+                AssignTargetP::Identifier(name) => {
+                    let typ = assign
+                        .ty
+                        .as_ref()
+                        .map_or(Ty::any(), |ty| ty_from_type_expr(codemap, ty, globals));
+                    (
+                        &name.ident,
+                        DocItem::Member(DocMember::Property(DocProperty { docs: None, typ })),
+                    )
+                }
+                _ => continue,
+            },
+            _ => continue,
+        };
+        if !name.starts_with('_') {
+            members.insert(name.clone(), member);
+        }
+    }
+
+    DocModule {
+        docs: raw_docstring(stmt)
+            .and_then(|x| DocString::from_docstring(DocStringKind::Starlark, x)),
+        members,
+    }
+}
+
+fn doc_function_from_def(
+    codemap: &CodeMap,
+    def: &DefP<AstNoPayload>,
+    globals: &Globals,
+) -> DocFunction {
+    let ty = |x: &Option<Box<AstTypeExpr>>| {
+        x.as_ref()
+            .map_or(Ty::any(), |x| ty_from_type_expr(codemap, x, globals))
+    };
+
+    // parameters are positional-or-named until a `/` shows up, and
+    // named-only after `*` or `*args`
+    let mut params = DocParams::default();
+    let mut named_only = false;
+    for x in def.params.iter() {
+        match &x.node {
+            ParameterP::Normal(name, typ, default) => {
+                let param = DocParam {
+                    name: name.ident.clone(),
+                    docs: None,
+                    typ: ty(typ),
+                    default_value: default
+                        .as_ref()
+                        .map(|x| codemap.source_span(x.span).to_owned()),
+                };
+                if named_only {
+                    params.named_only.push(param);
+                } else {
+                    params.pos_or_named.push(param);
+                }
+            }
+            ParameterP::Slash => params.pos_only.append(&mut params.pos_or_named),
+            ParameterP::NoArgs => named_only = true,
+            ParameterP::Args(name, typ) => {
+                params.args = Some(DocParam {
+                    name: name.ident.clone(),
+                    docs: None,
+                    typ: ty(typ),
+                    default_value: None,
+                });
+                named_only = true;
+            }
+            ParameterP::KwArgs(name, typ) => {
+                params.kwargs = Some(DocParam {
+                    name: name.ident.clone(),
+                    docs: None,
+                    typ: ty(typ),
+                    default_value: None,
+                });
+            }
+        }
+    }
+
+    DocFunction::from_docstring(
+        DocStringKind::Starlark,
+        params,
+        ty(&def.return_type),
+        raw_docstring(&def.body),
+    )
+}
+
+/// The docstring of a module or function body, iff the first statement is a
+/// string literal.
+fn raw_docstring(body: &AstStmt) -> Option<&str> {
+    match &body.node {
+        StmtP::Statements(stmts) => match stmts.first().map(|x| &x.node) {
+            Some(StmtP::Expression(x)) => match &x.node {
+                ExprP::Literal(AstLiteral::String(s)) => Some(&s.node),
+                _ => None,
+            },
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/// Resolves a type annotation against `globals`, without evaluating it.
+///
+/// Only names, attributes of namespaces and structs, unions, tuples, and
+/// `list[...]`, `set[...]` and `dict[..., ...]` are understood; anything else
+/// is documented as `typing.Any`. No Starlark or Python code is run, as the
+/// only operations done on the values named by the annotation are attribute
+/// lookups on namespaces and structs, and compiling them to types, which
+/// `pyobject` values are exempt from.
+fn ty_from_type_expr(codemap: &CodeMap, x: &AstTypeExpr, globals: &Globals) -> Ty {
+    match TypeExprUnpackP::unpack(&x.node.expr, codemap) {
+        Ok(x) => ty_from_type_expr_unpack(&x.node, globals),
+        Err(_) => Ty::any(),
+    }
+}
+
+fn ty_from_type_expr_unpack(x: &TypeExprUnpackP<'_, AstNoPayload>, globals: &Globals) -> Ty {
+    let ty =
+        |x: &Spanned<TypeExprUnpackP<'_, AstNoPayload>>| ty_from_type_expr_unpack(&x.node, globals);
+    match x {
+        TypeExprUnpackP::Path(path) => ty_from_type_path(path, globals),
+        TypeExprUnpackP::Index(name, item) => match name.ident.as_str() {
+            "list" => Ty::list(ty(item)),
+            "set" => Ty::set(ty(item)),
+            _ => Ty::any(),
+        },
+        TypeExprUnpackP::Index2(path, key, value) if path.rem.is_empty() => {
+            match path.first.ident.as_str() {
+                "dict" => Ty::dict(ty(key), ty(value)),
+                // `tuple[T, ...]` has no public constructor
+                "tuple" => ty_from_type_path(&path.node, globals),
+                _ => Ty::any(),
+            }
+        }
+        TypeExprUnpackP::Union(xs) => Ty::unions(xs.iter().map(ty).collect()),
+        TypeExprUnpackP::Tuple(xs) => Ty::tuple(xs.iter().map(ty).collect()),
+        TypeExprUnpackP::Index2(..) | TypeExprUnpackP::List(_) | TypeExprUnpackP::Ellipsis => {
+            Ty::any()
+        }
+    }
+}
+
+fn ty_from_type_path(path: &TypePathP<'_, AstNoPayload>, globals: &Globals) -> Ty {
+    let first = path.first.ident.as_str();
+    if first == "None" && path.rem.is_empty() {
+        return Ty::none();
+    }
+    let value = match globals.iter().find(|(name, _)| *name == first) {
+        Some((_, x)) => x.to_value(),
+        None => return Ty::any(),
+    };
+
+    let heap = Heap::new();
+    let mut value = value;
+    for name in &path.rem {
+        // attributes of other values may be computed by arbitrary code
+        if !matches!(value.get_type(), "namespace" | "struct") {
+            return Ty::any();
+        }
+        value = match value.get_attr(name.node, &heap) {
+            Ok(Some(x)) => x,
+            _ => return Ty::any(),
+        };
+    }
+    // compiling reports errors using attributes of the value
+    if value.downcast_ref::<SlPyObject>().is_some() {
+        return Ty::any();
+    }
+    match TypeCompiled::new(value, &heap) {
+        Ok(t) => t.as_ty().clone(),
+        Err(_) => Ty::any(),
+    }
+}
+
+/// Renders the documentation of a `Globals` or `FrozenModule` to Markdown, in
+/// the same style as starlark-rust's own documentation generator.
+///
+/// Returns a mapping from page paths to page contents. The top-level symbols
+/// are documented in `{prefix}/globals.md`, and every namespace and type gets a
+/// page of its own, e.g. `{prefix}/foo.md` for `foo` and `{prefix}/foo/bar.md`
+/// for `foo.bar`. Types are also listed along with the other symbols where
+/// they are defined. Names whose pages would overwrite others, like a
+/// namespace named `globals`, are rejected with a `ValueError`.
+#[pyfunction]
+pub(crate) fn render_docs_markdown(
+    module: &Bound<'_, PyAny>,
+    prefix: &str,
+) -> PyResult<BTreeMap<String, String>> {
+    let docs = if let Ok(globals) = module.downcast::<PyGlobals>() {
+        globals.get().0.documentation()
+    } else if let Ok(fm) = module.downcast::<PyFrozenModule>() {
+        fm.get().0.documentation()
+    } else {
+        return Err(PyTypeError::new_err("expected a Globals or FrozenModule"));
+    };
+
+    let mut pages = BTreeMap::new();
+    // reserved first, so that no other page takes its place
+    pages.insert(page_path(prefix, "globals"), String::new());
+    let docs = take_own_pages(&mut pages, prefix, None, docs)?;
+    pages.insert(
+        page_path(prefix, "globals"),
+        render_doc_item(
+            "globals",
+            &DocItem::Module(docs),
+            &TypeRenderConfig::Default,
+        ),
+    );
+    Ok(pages)
+}
+
+fn page_path(prefix: &str, name: &str) -> String {
+    let prefix = prefix.trim_end_matches('/');
+    let name = name.replace('.', "/");
+    if prefix.is_empty() {
+        format!("{}.md", name)
+    } else {
+        format!("{}/{}.md", prefix, name)
+    }
+}
+
+fn insert_page(
+    pages: &mut BTreeMap<String, String>,
+    prefix: &str,
+    name: &str,
+    item: &DocItem,
+) -> PyResult<()> {
+    let path = page_path(prefix, name);
+    if pages.contains_key(&path) {
+        return Err(PyValueError::new_err(format!(
+            "the documentation of `{}` would overwrite the page {}",
+            name, path
+        )));
+    }
+    let page = render_doc_item(name, item, &TypeRenderConfig::Default);
+    pages.insert(path, page);
+    Ok(())
+}
+
+/// Renders the namespaces and types in `docs` to pages of their own, and
+/// returns `docs` without the namespaces.
+fn take_own_pages(
+    pages: &mut BTreeMap<String, String>,
+    prefix: &str,
+    name: Option<&str>,
+    docs: DocModule,
+) -> PyResult<DocModule> {
+    let mut members = SmallMap::new();
+    for (k, v) in docs.members {
+        let qualified = match name {
+            Some(name) => format!("{}.{}", name, k),
+            None => k.clone(),
+        };
+        match v {
+            DocItem::Module(x) => render_namespace_page(pages, prefix, &qualified, x)?,
+            v => {
+                if let DocItem::Type(_) = &v {
+                    insert_page(pages, prefix, &qualified, &v)?;
+                }
+                members.insert(k, v);
+            }
+        }
+    }
+    Ok(DocModule {
+        docs: docs.docs,
+        members,
+    })
+}
+
+fn render_namespace_page(
+    pages: &mut BTreeMap<String, String>,
+    prefix: &str,
+    name: &str,
+    docs: DocModule,
+) -> PyResult<()> {
+    let docs = take_own_pages(pages, prefix, Some(name), docs)?;
+    // rendered as a type, so that the members are qualified with the name,
+    // types being listed as properties like in the page of the globals
+    let members = docs
+        .members
+        .iter()
+        .map(|(k, v)| match v.try_as_member_with_collapsed_object() {
+            Ok(x) => (k.clone(), x),
+            Err(_) => unreachable!("namespaces are rendered to pages of their own"),
+        })
+        .collect();
+    let docs = DocType {
+        docs: docs.docs,
+        members,
+        ty: Ty::any(),
+        constructor: None,
+    };
+    insert_page(pages, prefix, name, &DocItem::Type(docs))
+}
@@ -1,10 +1,17 @@
+use std::cell::Cell;
+use std::thread::{self, ThreadId};
+
 use pyo3::exceptions::{PyRuntimeError, PyValueError};
 use pyo3::prelude::*;
 use starlark::environment::{FrozenModule, Globals, GlobalsBuilder, LibraryExtension, Module};
-use starlark::values::{FrozenStringValue, FrozenValue};
+use starlark::values::{FrozenHeapRef, FrozenStringValue, FrozenValue, Value};
 
-use crate::py2sl::{self, sl_frozen_value_from_py};
+use crate::docs::PyDocModule;
+use crate::eval::with_heap_owner;
+use crate::py2sl::{self, sl_frozen_value_from_py, SlPyFunction};
+use crate::roots;
 use crate::sl2py::{self, py_from_sl_frozen_value};
+use crate::values::HeapOwner;
 
 /// The extra library definitions available in this Starlark implementation, but not in the standard.
 #[pyclass(
@@ -23,6 +30,9 @@ pub(crate) enum PyLibraryExtension {
     RecordType,
     /// Definitions to support the `enum` type, the `enum()` constructor.
     EnumType,
+    /// Add a function `namespace()` which acts much like `struct()` but is clear about it's
+    /// intended use and stricter
+    NamespaceType,
     /// A function `map(f, xs)` which applies `f` to each element of `xs` and returns the result.
     Map,
     /// A function `filter(f, xs)` which applies `f` to each element of `xs` and returns those for which `f` returns `True`.
@@ -38,6 +48,10 @@ pub(crate) enum PyLibraryExtension {
     Print,
     /// Add a function `pprint(x)` which pretty-prints to stderr.
     Pprint,
+    /// Add a function `pstr` which is a pretty-printed version of `str`.
+    Pstr,
+    /// Add a function `prepr` which is a pretty-printed version of `repr`.
+    Prepr,
     /// Add a function `breakpoint()` which will drop into a console-module evaluation prompt.
     Breakpoint,
     /// Add a function `json()` which will generate JSON for a module.
@@ -52,6 +66,8 @@ pub(crate) enum PyLibraryExtension {
     /// Add a function `call_stack()` which returns a string representation of
     /// the current call stack.
     CallStack,
+    /// Definitions to support the `set` type, the `set()` constructor.
+    SetType,
     // NOTE: keep this in sync with LibraryExtension
 }
 
@@ -61,17 +77,21 @@ impl From<LibraryExtension> for PyLibraryExtension {
             LibraryExtension::StructType => Self::StructType,
             LibraryExtension::RecordType => Self::RecordType,
             LibraryExtension::EnumType => Self::EnumType,
+            LibraryExtension::NamespaceType => Self::NamespaceType,
             LibraryExtension::Map => Self::Map,
             LibraryExtension::Filter => Self::Filter,
             LibraryExtension::Partial => Self::Partial,
             LibraryExtension::Debug => Self::Debug,
             LibraryExtension::Print => Self::Print,
             LibraryExtension::Pprint => Self::Pprint,
+            LibraryExtension::Pstr => Self::Pstr,
+            LibraryExtension::Prepr => Self::Prepr,
             LibraryExtension::Breakpoint => Self::Breakpoint,
             LibraryExtension::Json => Self::Json,
             LibraryExtension::Typing => Self::Typing,
             LibraryExtension::Internal => Self::Internal,
             LibraryExtension::CallStack => Self::CallStack,
+            LibraryExtension::SetType => Self::SetType,
         }
     }
 }
@@ -82,17 +102,21 @@ impl From<PyLibraryExtension> for LibraryExtension {
             PyLibraryExtension::StructType => Self::StructType,
             PyLibraryExtension::RecordType => Self::RecordType,
             PyLibraryExtension::EnumType => Self::EnumType,
+            PyLibraryExtension::NamespaceType => Self::NamespaceType,
             PyLibraryExtension::Map => Self::Map,
             PyLibraryExtension::Filter => Self::Filter,
             PyLibraryExtension::Partial => Self::Partial,
             PyLibraryExtension::Debug => Self::Debug,
             PyLibraryExtension::Print => Self::Print,
             PyLibraryExtension::Pprint => Self::Pprint,
+            PyLibraryExtension::Pstr => Self::Pstr,
+            PyLibraryExtension::Prepr => Self::Prepr,
             PyLibraryExtension::Breakpoint => Self::Breakpoint,
             PyLibraryExtension::Json => Self::Json,
             PyLibraryExtension::Typing => Self::Typing,
             PyLibraryExtension::Internal => Self::Internal,
             PyLibraryExtension::CallStack => Self::CallStack,
+            PyLibraryExtension::SetType => Self::SetType,
         }
     }
 }
@@ -144,9 +168,15 @@ impl PyGlobals {
     }
 
     fn __iter__(slf: &Bound<'_, Self>) -> PyResult<Py<PyGlobalsItemsIterator>> {
+        // the heap of the Globals is not accessible, but a module made from it
+        // references the heap
+        let heap = FrozenModule::from_globals(&slf.borrow().0)
+            .map_err(anyhow::Error::from)?
+            .frozen_heap()
+            .clone();
         Py::new(
             slf.py(),
-            PyGlobalsItemsIterator::new(slf, Box::new(slf.borrow().0.iter())),
+            PyGlobalsItemsIterator::new(slf, heap, Box::new(slf.borrow().0.iter())),
         )
     }
 
@@ -159,7 +189,10 @@ impl PyGlobals {
         self.0.docstring()
     }
 
-    // TODO: documentation
+    /// Get the documentation for both the object itself, and its members.
+    fn documentation(&self) -> PyDocModule {
+        self.0.documentation().into()
+    }
 }
 
 #[pyclass(module = "xingque", name = "_FrozenStringValueIterator")]
@@ -196,17 +229,20 @@ impl PyFrozenStringValueIterator {
 #[pyclass(module = "xingque", name = "_GlobalsItemsIterator")]
 pub(crate) struct PyGlobalsItemsIterator {
     _parent: Py<PyGlobals>,
+    heap: FrozenHeapRef,
     inner: Box<dyn Iterator<Item = (&'static str, FrozenValue)> + Send + Sync>,
 }
 
 impl PyGlobalsItemsIterator {
     fn new(
         parent: &Bound<'_, PyGlobals>,
+        heap: FrozenHeapRef,
         value: Box<dyn Iterator<Item = (&str, FrozenValue)> + Send + Sync + '_>,
     ) -> Self {
         let parent = parent.clone().unbind();
         Self {
             _parent: parent,
+            heap,
             // Safety: parent is kept alive by the reference above
             inner: unsafe { ::core::mem::transmute(value) },
         }
@@ -224,7 +260,7 @@ impl PyGlobalsItemsIterator {
         match slf.inner.next() {
             None => Ok(None),
             Some((k, v)) => {
-                let v = py_from_sl_frozen_value(py, v)?;
+                let v = py_from_sl_frozen_value(py, v, &slf.heap)?;
                 Ok(Some((k, v)))
             }
         }
@@ -240,6 +276,14 @@ impl From<GlobalsBuilder> for PyGlobalsBuilder {
     }
 }
 
+impl PyGlobalsBuilder {
+    pub(crate) fn inner_mut(&mut self) -> PyResult<&mut GlobalsBuilder> {
+        self.0.as_mut().ok_or(PyRuntimeError::new_err(
+            "this GlobalsBuilder has already been consumed",
+        ))
+    }
+}
+
 #[pymethods]
 impl PyGlobalsBuilder {
     #[new]
@@ -281,7 +325,7 @@ impl PyGlobalsBuilder {
         };
 
         let mut err = None;
-        inner.struct_(name, |gb| {
+        inner.namespace(name, |gb| {
             let args = (PySubGlobalsBuilder::new(gb),);
             err = f.call1(args).err();
         });
@@ -321,11 +365,11 @@ impl PyGlobalsBuilder {
         f: &'py Bound<'py, PyAny>,
     ) -> PyResult<&'py Bound<'py, Self>> {
         // implement the logic ourselves to avoid having to do ownership dance
-        // it's basically just self.struct_(name, f) and return self
+        // it's basically just self.namespace(name, f) and return self
         slf.borrow_mut().r#struct(name, f).map(|_| slf)
     }
 
-    fn build(&mut self) -> PyResult<PyGlobals> {
+    pub(crate) fn build(&mut self) -> PyResult<PyGlobals> {
         let inner = match self.0.take() {
             Some(inner) => inner,
             None => {
@@ -352,7 +396,26 @@ impl PyGlobalsBuilder {
         Ok(())
     }
 
-    // TODO: set_function
+    #[pyo3(signature = (name, f, *, signature = None, doc = None))]
+    fn set_function(
+        &mut self,
+        name: &str,
+        f: &Bound<'_, PyAny>,
+        signature: Option<&Bound<'_, PyAny>>,
+        doc: Option<String>,
+    ) -> PyResult<()> {
+        let inner = match &mut self.0 {
+            Some(inner) => inner,
+            None => {
+                return Err(PyRuntimeError::new_err(
+                    "this GlobalsBuilder has already been consumed",
+                ))
+            }
+        };
+
+        inner.set(name, SlPyFunction::new(name, f, signature, doc)?);
+        Ok(())
+    }
 
     // TODO: are those necessary?
     //
@@ -363,7 +426,7 @@ impl PyGlobalsBuilder {
 
 // necessary for proper ownership maintenance
 #[pyclass(module = "xingque", name = "_SubGlobalsBuilder", unsendable)]
-pub(crate) struct PySubGlobalsBuilder(&'static mut GlobalsBuilder);
+pub(crate) struct PySubGlobalsBuilder(pub(crate) &'static mut GlobalsBuilder);
 
 impl PySubGlobalsBuilder {
     fn new(ptr: &mut GlobalsBuilder) -> Self {
@@ -377,7 +440,7 @@ impl PySubGlobalsBuilder {
 impl PySubGlobalsBuilder {
     fn r#struct(&mut self, name: &str, f: &Bound<'_, PyAny>) -> PyResult<()> {
         let mut err = None;
-        self.0.struct_(name, |gb| {
+        self.0.namespace(name, |gb| {
             let args = (PySubGlobalsBuilder::new(gb),);
             err = f.call1(args).err();
         });
@@ -409,7 +472,7 @@ impl PySubGlobalsBuilder {
         f: &'py Bound<'py, PyAny>,
     ) -> PyResult<&'py Bound<'py, Self>> {
         // implement the logic ourselves to avoid having to do ownership dance
-        // it's basically just self.struct_(name, f) and return self
+        // it's basically just self.namespace(name, f) and return self
         slf.borrow_mut().r#struct(name, f).map(|_| slf)
     }
 
@@ -421,6 +484,19 @@ impl PySubGlobalsBuilder {
         self.0.set(name, sl_frozen_value_from_py(value, heap)?);
         Ok(())
     }
+
+    #[pyo3(signature = (name, f, *, signature = None, doc = None))]
+    fn set_function(
+        &mut self,
+        name: &str,
+        f: &Bound<'_, PyAny>,
+        signature: Option<&Bound<'_, PyAny>>,
+        doc: Option<String>,
+    ) -> PyResult<()> {
+        self.0
+            .set(name, SlPyFunction::new(name, f, signature, doc)?);
+        Ok(())
+    }
 }
 
 #[pyclass(module = "xingque", name = "FrozenModule", frozen)]
@@ -437,7 +513,9 @@ impl From<FrozenModule> for PyFrozenModule {
 impl PyFrozenModule {
     #[staticmethod]
     fn from_globals(globals: &Bound<'_, PyGlobals>) -> PyResult<Self> {
-        Ok(FrozenModule::from_globals(&globals.borrow().0)?.into())
+        Ok(FrozenModule::from_globals(&globals.borrow().0)
+            .map_err(anyhow::Error::from)?
+            .into())
     }
 
     fn dump_debug(&self) -> String {
@@ -446,13 +524,13 @@ impl PyFrozenModule {
 
     fn get_option(&self, py: Python, name: &str) -> PyResult<PyObject> {
         match self.0.get_option(name)? {
-            Some(sl) => sl2py::py_from_sl_value(py, sl.value()),
+            Some(sl) => sl2py::py_from_sl_owned_frozen_value(py, &sl),
             None => Ok(py.None()),
         }
     }
 
     fn get(&self, py: Python, name: &str) -> PyResult<PyObject> {
-        sl2py::py_from_sl_value(py, self.0.get(name)?.value())
+        sl2py::py_from_sl_owned_frozen_value(py, &self.0.get(name)?)
     }
 
     fn names(slf: &Bound<'_, Self>) -> PyResult<Py<PyFrozenStringValueIterator>> {
@@ -466,45 +544,88 @@ impl PyFrozenModule {
         self.0.describe()
     }
 
-    // TODO: documentation
+    /// The documentation for the module, and all of its top level values.
+    fn documentation(&self) -> PyDocModule {
+        self.0.documentation().into()
+    }
+
     // TODO: aggregated_heap_profile_info
 
     #[getter]
     fn get_extra_value(&self, py: Python) -> PyResult<PyObject> {
-        match self.0.extra_value() {
-            Some(sl) => sl2py::py_from_sl_frozen_value(py, sl),
+        match roots::frozen_extra_value(&self.0) {
+            Some(sl) => sl2py::py_from_sl_owned_frozen_value(py, &sl),
             None => Ok(py.None()),
         }
     }
 }
 
 #[pyclass(module = "xingque", name = "Module")]
-pub(crate) struct PyModule(Option<Module>);
+pub(crate) struct PyModule(
+    Option<Module>,
+    // the thread evaluating on this module with the GIL released, if any
+    Cell<Option<ThreadId>>,
+);
 
 impl From<Module> for PyModule {
     fn from(value: Module) -> Self {
-        Self(Some(value))
+        Self(Some(value), Cell::new(None))
     }
 }
 
 impl PyModule {
     pub(crate) fn inner(&self) -> PyResult<&Module> {
+        if self.1.get().is_some_and(|x| x != thread::current().id()) {
+            return Err(PyRuntimeError::new_err(
+                "this Module is being evaluated on another thread",
+            ));
+        }
         self.0
             .as_ref()
             .ok_or(PyRuntimeError::new_err("this Module is already consumed"))
     }
 
-    pub(crate) fn inner_mut(&mut self) -> PyResult<&mut Module> {
-        self.0
-            .as_mut()
-            .ok_or(PyRuntimeError::new_err("this Module is already consumed"))
+    pub(crate) fn is_consumed(&self) -> bool {
+        self.0.is_none()
     }
 
     pub(crate) fn take_inner(&mut self) -> PyResult<Module> {
+        if self.1.get().is_some() {
+            return Err(PyRuntimeError::new_err(
+                "this Module cannot be consumed while being evaluated",
+            ));
+        }
         self.0
             .take()
             .ok_or(PyRuntimeError::new_err("this Module is already consumed"))
     }
+
+    /// Marks the module as being evaluated on by the current thread until the
+    /// returned lease is dropped, so that other threads cannot use it while
+    /// the evaluation runs with the GIL released.
+    pub(crate) fn lease(slf: &Bound<'_, Self>) -> PyResult<ModuleLease> {
+        let me = slf.try_borrow()?;
+        me.inner()?;
+        let prev = me.1.replace(Some(thread::current().id()));
+        Ok(ModuleLease(slf.clone().unbind(), prev))
+    }
+
+    fn sl_value_from_py<'v>(
+        slf: &Bound<'_, Self>,
+        inner: &'v Module,
+        value: &Bound<'_, PyAny>,
+    ) -> PyResult<Value<'v>> {
+        let owner = HeapOwner::from(slf.clone().unbind());
+        with_heap_owner(owner, || py2sl::sl_value_from_py(value, inner.heap()))
+    }
+}
+
+pub(crate) struct ModuleLease(Py<PyModule>, Option<ThreadId>);
+
+impl Drop for ModuleLease {
+    fn drop(&mut self) {
+        Python::with_gil(|py| self.0.borrow(py).1.set(self.1));
+    }
 }
 
 #[pymethods]
@@ -527,32 +648,38 @@ impl PyModule {
     // TODO: names_and_visibilities
     // TODO: __getitem__/__setitem__?
 
-    fn get(&self, py: Python, name: &str) -> PyResult<Option<PyObject>> {
-        sl2py::py_from_sl_value_option(py, self.inner()?.get(name))
+    fn get(slf: &Bound<'_, Self>, name: &str) -> PyResult<Option<PyObject>> {
+        let owner = HeapOwner::from(slf.clone().unbind());
+        let inner = slf.borrow();
+        sl2py::py_from_sl_value_option(slf.py(), inner.inner()?.get(name), Some(&owner))
     }
 
-    fn set(&mut self, name: &str, value: &Bound<'_, PyAny>) -> PyResult<()> {
-        let inner = self.inner_mut()?;
-        inner.set(name, py2sl::sl_value_from_py(value, inner.heap()));
+    fn set(slf: &Bound<'_, Self>, name: &str, value: &Bound<'_, PyAny>) -> PyResult<()> {
+        let me = slf.borrow();
+        let inner = me.inner()?;
+        inner.set(name, Self::sl_value_from_py(slf, inner, value)?);
         Ok(())
     }
 
     fn freeze(&mut self) -> PyResult<PyFrozenModule> {
         let inner = self.take_inner()?;
-        Ok(inner.freeze()?.into())
+        Ok(inner.freeze().map_err(anyhow::Error::from)?.into())
     }
 
     // TODO: import_public_symbols
 
     #[getter]
-    fn get_extra_value(&self, py: Python) -> PyResult<Option<PyObject>> {
-        sl2py::py_from_sl_value_option(py, self.inner()?.extra_value())
+    fn get_extra_value(slf: &Bound<'_, Self>) -> PyResult<Option<PyObject>> {
+        let owner = HeapOwner::from(slf.clone().unbind());
+        let inner = slf.borrow();
+        sl2py::py_from_sl_value_option(slf.py(), roots::extra_value(inner.inner()?), Some(&owner))
     }
 
     #[setter]
-    fn set_extra_value(&self, value: &Bound<'_, PyAny>) -> PyResult<()> {
-        let inner = self.inner()?;
-        inner.set_extra_value(py2sl::sl_value_from_py(value, inner.heap()));
+    fn set_extra_value(slf: &Bound<'_, Self>, value: &Bound<'_, PyAny>) -> PyResult<()> {
+        let me = slf.borrow();
+        let inner = me.inner()?;
+        roots::set_extra_value(inner, Self::sl_value_from_py(slf, inner, value)?);
         Ok(())
     }
 }
@@ -1,8 +1,21 @@
+use std::fmt;
+use std::sync::Arc;
+
+use pyo3::create_exception;
+use pyo3::exceptions::{
+    PyAttributeError, PyException, PyIndexError, PyKeyError, PyTypeError, PyZeroDivisionError,
+};
 use pyo3::prelude::*;
+use pyo3::PyTypeInfo;
 use starlark::codemap::FileSpan;
 use starlark::errors::Frame;
+use starlark::eval::CallStack;
+use starlark::values::ValueError;
+use starlark::ErrorKind;
 
 use crate::codemap::PyFileSpan;
+use crate::eval::PyCallStack;
+use crate::limits::{Abort, AbortKind};
 
 #[pyclass(module = "xingque", name = "Frame", frozen)]
 #[derive(Clone)]
@@ -28,3 +41,446 @@ impl PyFrame {
 
     // TODO: write_two_lines
 }
+
+/// The different kinds of errors that can be produced by starlark.
+#[pyclass(
+    module = "xingque",
+    name = "ErrorKind",
+    rename_all = "SCREAMING_SNAKE_CASE",
+    frozen,
+    eq,
+    hash
+)]
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub(crate) enum PyErrorKind {
+    /// An explicit `fail` invocation.
+    Fail,
+    /// Starlark call stack overflow.
+    StackOverflow,
+    /// An error approximately associated with a value.
+    ///
+    /// Includes unsupported operations, missing attributes, things of that sort.
+    Value,
+    /// Errors relating to the way a function is called (wrong number of args, etc.)
+    Function,
+    /// Out of scope variables and similar.
+    Scope,
+    /// Syntax error.
+    Parser,
+    /// Freeze errors.
+    Freeze,
+    /// Indicates a logic bug in starlark.
+    Internal,
+    /// Error from user provided native function.
+    Native,
+    /// Fallback option.
+    Other,
+    // NOTE: keep this in sync with ErrorKind
+}
+
+impl From<&ErrorKind> for PyErrorKind {
+    fn from(value: &ErrorKind) -> Self {
+        match value {
+            ErrorKind::Fail(_) => Self::Fail,
+            ErrorKind::StackOverflow(_) => Self::StackOverflow,
+            ErrorKind::Value(_) => Self::Value,
+            ErrorKind::Function(_) => Self::Function,
+            ErrorKind::Scope(_) => Self::Scope,
+            ErrorKind::Parser(_) => Self::Parser,
+            ErrorKind::Freeze(_) => Self::Freeze,
+            ErrorKind::Internal(_) => Self::Internal,
+            ErrorKind::Native(_) => Self::Native,
+            ErrorKind::Other(_) => Self::Other,
+            // ErrorKind is non_exhaustive
+            // NOTE: check if variants are added after every starlark dep bump!
+            _ => Self::Other,
+        }
+    }
+}
+
+create_exception!(
+    xingque,
+    StarlarkError,
+    PyException,
+    "Base class of all errors originating from Starlark."
+);
+create_exception!(
+    xingque,
+    ParseError,
+    StarlarkError,
+    "Error raised when Starlark source code fails to parse."
+);
+create_exception!(
+    xingque,
+    EvalError,
+    StarlarkError,
+    "Error raised when evaluation of Starlark code fails."
+);
+create_exception!(
+    xingque,
+    LoadError,
+    EvalError,
+    "Error raised when a module cannot be loaded."
+);
+create_exception!(
+    xingque,
+    StarlarkResourceExhausted,
+    EvalError,
+    "Error raised when evaluation is aborted for exceeding its execution budget."
+);
+create_exception!(
+    xingque,
+    StarlarkCancelled,
+    EvalError,
+    "Error raised when evaluation is aborted because it is cancelled."
+);
+create_exception!(
+    xingque,
+    TypeCheckError,
+    StarlarkError,
+    "Error found by the static typechecker."
+);
+create_exception!(
+    xingque,
+    InternalError,
+    StarlarkError,
+    "Error indicating a logic bug in starlark-rust."
+);
+
+/// Converts a `starlark::Error` produced by the parser into a Python `ParseError`.
+pub(crate) fn py_err_from_sl_parse_error(py: Python<'_>, e: starlark::Error) -> PyErr {
+    py_err_from_sl_error::<ParseError>(py, e, None)
+}
+
+/// Converts a `starlark::Error` produced during evaluation into a Python `EvalError`.
+///
+/// If the error is caused by a Python exception raised from Python code called
+/// by Starlark, the original exception is returned instead, with its
+/// `__cause__` set to the `EvalError`, so that it survives the round-trip.
+pub(crate) fn py_err_from_sl_eval_error(py: Python<'_>, e: starlark::Error) -> PyErr {
+    if let Some(x) = LoadFailure::from_sl_error(&e) {
+        return py_err_from_load_failure(py, x);
+    }
+    if let Some(x) = Abort::from_sl_error(&e) {
+        return py_err_from_abort(py, x);
+    }
+    match original_py_err(py, &e) {
+        Some((original, call_stack)) => {
+            let cause = py_err_from_sl_error::<EvalError>(py, e, call_stack);
+            // don't lose the original exception's own cause, if any
+            if let Some(prev_cause) = original.cause(py) {
+                cause.set_cause(py, Some(prev_cause));
+            }
+            original.set_cause(py, Some(cause));
+            original
+        }
+        None => py_err_from_sl_error::<EvalError>(py, e, None),
+    }
+}
+
+/// Converts a `starlark::Error` produced by a `Value` operation invoked from
+/// Python into the closest built-in Python exception, so that the Python data
+/// model protocols (e.g. `hasattr` or `dict.get`) work as usual.
+///
+/// Errors without a Python counterpart are converted like evaluation errors.
+pub(crate) fn py_err_from_sl_op_error(py: Python<'_>, e: starlark::Error) -> PyErr {
+    let value_error = match e.kind() {
+        ErrorKind::Value(x)
+        | ErrorKind::Function(x)
+        | ErrorKind::Native(x)
+        | ErrorKind::Other(x) => x.downcast_ref::<ValueError>(),
+        _ => None,
+    };
+    match value_error {
+        Some(ValueError::IndexOutOfBound(_)) => PyIndexError::new_err(e.to_string()),
+        Some(ValueError::KeyNotFound(_)) => PyKeyError::new_err(e.to_string()),
+        Some(ValueError::NoAttr(..) | ValueError::NoAttrDidYouMean(..)) => {
+            PyAttributeError::new_err(e.to_string())
+        }
+        Some(
+            ValueError::OperationNotSupported { .. }
+            | ValueError::OperationNotSupportedBinary { .. },
+        ) => PyTypeError::new_err(e.to_string()),
+        Some(ValueError::DivisionByZero) => PyZeroDivisionError::new_err(e.to_string()),
+        _ => py_err_from_sl_eval_error(py, e),
+    }
+}
+
+fn original_py_err(py: Python<'_>, e: &starlark::Error) -> Option<(PyErr, Option<CallStack>)> {
+    let inner = match e.kind() {
+        ErrorKind::Fail(x)
+        | ErrorKind::StackOverflow(x)
+        | ErrorKind::Value(x)
+        | ErrorKind::Function(x)
+        | ErrorKind::Scope(x)
+        | ErrorKind::Parser(x)
+        | ErrorKind::Freeze(x)
+        | ErrorKind::Internal(x)
+        | ErrorKind::Native(x)
+        | ErrorKind::Other(x) => x,
+        _ => return None,
+    };
+
+    if let Some(x) = inner.downcast_ref::<PyErrFromCall>() {
+        Some((x.err.clone_ref(py), Some(x.call_stack.clone())))
+    } else {
+        inner
+            .downcast_ref::<PyErr>()
+            .map(|x| (x.clone_ref(py), None))
+    }
+}
+
+/// A Python exception raised by a Python callable invoked from Starlark,
+/// together with the Starlark call stack at the point of the call.
+#[derive(Debug)]
+pub(crate) struct PyErrFromCall {
+    err: PyErr,
+    call_stack: CallStack,
+}
+
+impl PyErrFromCall {
+    pub(crate) fn new(err: PyErr, call_stack: CallStack) -> Self {
+        Self { err, call_stack }
+    }
+}
+
+impl fmt::Display for PyErrFromCall {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(&self.err, f)
+    }
+}
+
+impl std::error::Error for PyErrFromCall {}
+
+/// A failure of a file loader to provide a module, together with the `load()`
+/// statements being evaluated at the time.
+#[derive(Debug, Clone)]
+pub(crate) struct LoadFailure {
+    module_id: String,
+    message: String,
+    cycle: Option<Vec<String>>,
+    /// Spans of the `load()` statements leading to the failure, outermost
+    /// first.
+    load_spans: Vec<FileSpan>,
+    /// The exception the failure is due to, set as the `__cause__` of the
+    /// `LoadError`.
+    cause: Option<Arc<PyErr>>,
+}
+
+impl LoadFailure {
+    pub(crate) fn new(module_id: &str, message: String) -> Self {
+        Self {
+            module_id: module_id.to_owned(),
+            message,
+            cycle: None,
+            load_spans: Vec::new(),
+            cause: None,
+        }
+    }
+
+    /// A load cycle, with `cycle` listing the modules involved, the first and
+    /// last being the same.
+    pub(crate) fn new_cycle(module_id: &str, cycle: Vec<String>) -> Self {
+        Self {
+            module_id: module_id.to_owned(),
+            message: format!("load cycle detected: {}", cycle.join(" -> ")),
+            cycle: Some(cycle),
+            load_spans: Vec::new(),
+            cause: None,
+        }
+    }
+
+    /// A failure due to the loader raising `cause`.
+    pub(crate) fn from_py_err(module_id: &str, cause: PyErr) -> Self {
+        Self {
+            cause: Some(Arc::new(cause)),
+            ..Self::new(module_id, format!("cannot load `{}`", module_id))
+        }
+    }
+
+    /// Wraps `e`, an error parsing or evaluating the module `module_id`, as a
+    /// load failure, unless it is one already. Aborted evaluations stay
+    /// aborted.
+    pub(crate) fn wrap(module_id: &str, e: starlark::Error, parsing: bool) -> starlark::Error {
+        if Self::from_sl_error(&e).is_some() || Abort::from_sl_error(&e).is_some() {
+            return e;
+        }
+        let message = format!("cannot load `{}`: {}", module_id, e.without_diagnostic());
+        let cause = Python::with_gil(|py| {
+            if parsing {
+                py_err_from_sl_parse_error(py, e)
+            } else {
+                py_err_from_sl_eval_error(py, e)
+            }
+        });
+        Self {
+            cause: Some(Arc::new(cause)),
+            ..Self::new(module_id, message)
+        }
+        .into()
+    }
+
+    /// Records `spans`, outermost first, as the `load()` statements leading to
+    /// `e` if it is a load failure, for failures found without evaluating the
+    /// loading modules.
+    pub(crate) fn with_load_spans(e: starlark::Error, spans: Vec<FileSpan>) -> starlark::Error {
+        match e.kind() {
+            ErrorKind::Other(x) => match x.downcast_ref::<Self>() {
+                Some(x) => {
+                    let mut result = x.clone();
+                    result.load_spans.splice(0..0, spans);
+                    result.into()
+                }
+                None => e,
+            },
+            _ => e,
+        }
+    }
+
+    /// Returns the load failure causing `e`, if any, recording the `load()`
+    /// statement the failure surfaced at.
+    pub(crate) fn from_sl_error(e: &starlark::Error) -> Option<Self> {
+        let mut result = match e.kind() {
+            ErrorKind::Other(x) => x.downcast_ref::<Self>()?.clone(),
+            _ => return None,
+        };
+        if let Some(span) = e.span() {
+            result.load_spans.insert(0, span.clone());
+        }
+        Some(result)
+    }
+}
+
+impl fmt::Display for LoadFailure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl std::error::Error for LoadFailure {}
+
+impl From<LoadFailure> for starlark::Error {
+    fn from(value: LoadFailure) -> Self {
+        starlark::Error::new_other(value)
+    }
+}
+
+fn py_err_from_load_failure(py: Python<'_>, x: LoadFailure) -> PyErr {
+    let mut text = x.message.clone();
+    if !x.load_spans.is_empty() {
+        text.push_str("\nimport stack (outermost first):");
+        for span in &x.load_spans {
+            text.push_str(&format!("\n  {}", span));
+        }
+    }
+    let err = PyErr::new::<LoadError, _>(text);
+
+    let result: PyResult<()> = (|| {
+        let value = err.value_bound(py);
+        value.setattr("kind", PyErrorKind::Other.into_py(py))?;
+        value.setattr(
+            "span",
+            x.load_spans
+                .last()
+                .cloned()
+                .map(PyFileSpan::from)
+                .into_py(py),
+        )?;
+        value.setattr("message", &x.message)?;
+        value.setattr(
+            "call_stack",
+            PyCallStack::from(CallStack::default()).into_py(py),
+        )?;
+        value.setattr("module_id", &x.module_id)?;
+        value.setattr(
+            "import_stack",
+            x.load_spans
+                .iter()
+                .map(|x| x.filename().to_owned())
+                .collect::<Vec<_>>(),
+        )?;
+        value.setattr("cycle", x.cycle)?;
+        if let Some(cause) = &x.cause {
+            err.set_cause(py, Some(cause.clone_ref(py)));
+        }
+        Ok(())
+    })();
+
+    match result {
+        Ok(_) => err,
+        Err(e) => e,
+    }
+}
+
+fn py_err_from_abort(py: Python<'_>, x: &Abort) -> PyErr {
+    let text = match &x.span {
+        Some(span) => format!("{}\n{}at {}", x.message, x.call_stack, span),
+        None => x.message.clone(),
+    };
+    let err = match x.kind {
+        AbortKind::ResourceExhausted => PyErr::new::<StarlarkResourceExhausted, _>(text),
+        AbortKind::Cancelled => PyErr::new::<StarlarkCancelled, _>(text),
+    };
+
+    let result: PyResult<()> = (|| {
+        let value = err.value_bound(py);
+        value.setattr("kind", PyErrorKind::Other.into_py(py))?;
+        value.setattr("span", x.span.clone().map(PyFileSpan::from).into_py(py))?;
+        value.setattr("message", &x.message)?;
+        value.setattr(
+            "call_stack",
+            PyCallStack::from(x.call_stack.clone()).into_py(py),
+        )?;
+        Ok(())
+    })();
+
+    match (result, &x.cause) {
+        (Err(e), _) => e,
+        // like for exceptions raised by Python code called by Starlark
+        (Ok(_), Some(cause)) => {
+            let original = cause.clone_ref(py);
+            original.set_cause(py, Some(err));
+            original
+        }
+        (Ok(_), None) => err,
+    }
+}
+
+/// Converts a `starlark::Error` into a Python exception of type `T`, unless
+/// the error is an internal one, in which case `InternalError` is always used.
+///
+/// The structured information (kind, span and call stack) is attached to the
+/// exception object as attributes, so Python code does not have to parse the
+/// message to find out where the error happened.
+///
+/// The call stack is the one recorded inside the error, unless `call_stack`
+/// is given, e.g. the one captured when Starlark called into the Python code
+/// that raised the error.
+pub(crate) fn py_err_from_sl_error<T: PyTypeInfo>(
+    py: Python<'_>,
+    e: starlark::Error,
+    call_stack: Option<CallStack>,
+) -> PyErr {
+    let kind = PyErrorKind::from(e.kind());
+    let span = e.span().cloned().map(PyFileSpan::from);
+    let call_stack = call_stack.unwrap_or_else(|| e.call_stack().clone());
+    let message = e.without_diagnostic().to_string();
+    let err = match kind {
+        PyErrorKind::Internal => PyErr::new::<InternalError, _>(e.to_string()),
+        _ => PyErr::new::<T, _>(e.to_string()),
+    };
+
+    let result: PyResult<()> = (|| {
+        let value = err.value_bound(py);
+        value.setattr("kind", kind.into_py(py))?;
+        value.setattr("span", span.into_py(py))?;
+        value.setattr("message", message)?;
+        value.setattr("call_stack", PyCallStack::from(call_stack).into_py(py))?;
+        Ok(())
+    })();
+
+    match result {
+        Ok(_) => err,
+        Err(e) => e,
+    }
+}
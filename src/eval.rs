@@ -1,7 +1,9 @@
+use std::cell::{Cell, RefCell};
 use std::collections::{HashMap, HashSet};
+use std::rc::Rc;
+use std::time::Duration;
 
-use anyhow::anyhow;
-use pyo3::exceptions::PyRuntimeError;
+use pyo3::exceptions::{PyRuntimeError, PyValueError};
 use pyo3::intern;
 use pyo3::prelude::*;
 use pyo3::types::{PyDict, PyTuple};
@@ -9,12 +11,17 @@ use starlark::codemap::ResolvedFileSpan;
 use starlark::environment::{FrozenModule, Module};
 use starlark::errors::Frame;
 use starlark::eval::{CallStack, Evaluator, FileLoader, ProfileMode};
+use starlark::values::{FrozenHeapRef, Heap, Value};
 use starlark::PrintHandler;
 
 use crate::codemap::{PyFileSpan, PyResolvedFileSpan};
-use crate::environment::{PyFrozenModule, PyGlobals, PyModule};
-use crate::errors::PyFrame;
+use crate::converters::{with_conversion, ConversionSettings};
+use crate::environment::{ModuleLease, PyFrozenModule, PyGlobals, PyModule};
+use crate::errors::{py_err_from_sl_eval_error, LoadFailure, PyFrame};
+use crate::limits::{end_evaluation, EvaluatorLimits, Limits, LimitsScope, PyCancellationToken};
+use crate::loader::PyFilesystemLoader;
 use crate::syntax::PyAstModule;
+use crate::values::{HeapOwner, PyValue};
 use crate::{py2sl, sl2py};
 
 #[pyclass(module = "xingque", name = "CallStack")]
@@ -43,11 +50,14 @@ impl PyCallStack {
 // it seems the Evaluator contains many thread-unsafe states
 #[pyclass(module = "xingque", name = "Evaluator", unsendable)]
 pub(crate) struct PyEvaluator(
-    Evaluator<'static, 'static>,
+    Evaluator<'static, 'static, 'static>,
     // this reference is necessary for memory safety
     #[allow(dead_code)] Py<PyModule>,
     PyObjectFileLoader,
     PyObjectPrintHandler,
+    EvaluatorLimits,
+    // how values are converted between Python and Starlark
+    Rc<ConversionSettings>,
 );
 
 impl PyEvaluator {
@@ -61,12 +71,36 @@ impl PyEvaluator {
             module_ref,
             PyObjectFileLoader::default(),
             PyObjectPrintHandler::default(),
+            EvaluatorLimits::default(),
+            Rc::default(),
         ))
     }
 
     fn ensure_module_available(&self, py: Python) -> PyResult<()> {
         self.1.bind(py).borrow().inner().map(|_| ())
     }
+
+    fn heap_owner(&self, py: Python) -> HeapOwner {
+        HeapOwner::from(self.1.clone_ref(py))
+    }
+
+    /// Prepares for an evaluation started from Python, returning the lease on
+    /// the module and the scope of the limits to hold while it runs.
+    fn begin_eval(&mut self, py: Python) -> PyResult<(ModuleLease, LimitsScope)> {
+        self.ensure_module_available(py)?;
+        let lease = PyModule::lease(self.1.bind(py))?;
+        Ok((lease, self.4.enter(&mut self.0)))
+    }
+
+    /// Checks the heap limit once more on the result of a successful
+    /// evaluation started with `begin_eval`.
+    fn end_eval<'v>(&self, result: starlark::Result<Value<'v>>) -> starlark::Result<Value<'v>> {
+        end_evaluation(self.0.heap(), result)
+    }
+
+    fn limits(&mut self) -> &Limits {
+        self.4.get_or_insert(&mut self.0)
+    }
 }
 
 #[pymethods]
@@ -90,26 +124,35 @@ impl PyEvaluator {
         py: Python,
         statements: &Bound<'_, PyAstModule>,
     ) -> PyResult<PyObject> {
-        self.ensure_module_available(py)?;
-
-        match self
-            .0
-            .eval_statements(statements.borrow_mut().take_inner()?)
-        {
-            Ok(sl) => sl2py::py_from_sl_value(py, sl),
-            Err(e) => Err(PyRuntimeError::new_err(e.to_string())),
-        }
+        let _scope = self.begin_eval(py)?;
+
+        let statements = statements.borrow_mut().take_inner()?;
+        let owner = self.heap_owner(py);
+        let conversion = self.5.clone();
+        with_conversion(&conversion, || {
+            let result = with_heap_owner(owner.clone_ref(py), || {
+                allow_threads_unsend(py, || self.0.eval_statements(statements))
+            });
+            let result = self.end_eval(result);
+            py_from_sl_eval_result(py, result, Some(&owner))
+        })
     }
 
     fn local_variables(&self, py: Python) -> PyResult<HashMap<String, PyObject>> {
         self.ensure_module_available(py)?;
 
+        let owner = self.heap_owner(py);
         let vars = self.0.local_variables();
-        let mut result = HashMap::with_capacity(vars.len());
-        for (k, v) in vars.into_iter() {
-            result.insert(k.to_string(), sl2py::py_from_sl_value(py, v)?);
-        }
-        Ok(result)
+        with_conversion(&self.5, || {
+            let mut result = HashMap::with_capacity(vars.len());
+            for (k, v) in vars.into_iter() {
+                result.insert(
+                    k.to_string(),
+                    sl2py::py_from_sl_value_owned(py, v, Some(&owner))?,
+                );
+            }
+            Ok(result)
+        })
     }
 
     fn verbose_gc(&mut self, py: Python) -> PyResult<()> {
@@ -149,7 +192,8 @@ impl PyEvaluator {
         Ok(self
             .0
             .coverage()
-            .map(|x| x.into_iter().map(ResolvedFileSpan::into).collect())?)
+            .map(|x| x.into_iter().map(ResolvedFileSpan::into).collect())
+            .map_err(starlark::Error::into_anyhow)?)
     }
 
     fn enable_terminal_breakpoint_console(&mut self, py: Python) -> PyResult<()> {
@@ -213,21 +257,126 @@ impl PyEvaluator {
         Ok(())
     }
 
+    /// Enables or disables the strict conversion of Starlark values to
+    /// Python, where values without a Python counterpart raise `TypeError`
+    /// instead of being wrapped in `Value`s and `FrozenValue`s.
+    ///
+    /// This applies to the results of evaluations with this evaluator, and
+    /// to the values passed to Python code called during them.
+    fn set_strict_conversion(&mut self, py: Python, strict: bool) {
+        let mut conversion = self.5.clone_ref(py);
+        conversion.strict = strict;
+        self.5 = Rc::new(conversion);
+    }
+
+    /// Enables or disables the conversion of dataclass, `typing.NamedTuple`,
+    /// attrs class and `types.SimpleNamespace` instances to Starlark structs,
+    /// their fields being converted in turn, and back.
+    ///
+    /// Starlark structs are converted back by calling `struct_type` with their
+    /// fields as keyword arguments, `types.SimpleNamespace` by default, and
+    /// records of unregistered types become dicts. `struct_type` is kept as
+    /// previously set unless given.
+    ///
+    /// This applies to evaluations with this evaluator, the values passed to
+    /// and returned from Python code called during them, and conversions
+    /// outside evaluations while this evaluator is bound with `with`.
+    #[pyo3(signature = (enabled, *, struct_type = None))]
+    fn set_struct_conversion(&mut self, py: Python, enabled: bool, struct_type: Option<PyObject>) {
+        let mut conversion = self.5.clone_ref(py);
+        conversion.structs = enabled;
+        if struct_type.is_some() {
+            conversion.struct_type = struct_type;
+        }
+        self.5 = Rc::new(conversion);
+    }
+
+    /// Aborts every evaluation after `limit` steps, or lifts the limit if
+    /// `None`. A step is a statement executed, or an item pulled from a Python
+    /// iterator.
+    ///
+    /// The limits apply to all the code the evaluator runs, including
+    /// functions defined before they were set and those of modules loaded in
+    /// any way. Modules evaluated during an evaluation share its limits,
+    /// whether by a `FilesystemLoader`, including in parallel by `load_graph`,
+    /// or by a loader using an evaluator without limits of its own.
+    ///
+    /// starlark-rust only offers a hook before statements, so a single
+    /// expression runs to completion before being caught: a comprehension, a
+    /// call into Rust or Python, or a `for` loop whose body is only `pass`.
+    #[pyo3(signature = (limit))]
+    fn set_step_limit(&mut self, py: Python, limit: Option<u64>) -> PyResult<()> {
+        self.ensure_module_available(py)?;
+        self.limits().set_step_limit(limit);
+        Ok(())
+    }
+
+    /// Aborts every evaluation running for longer than `seconds`, or lifts the
+    /// limit if `None`.
+    ///
+    /// The time is checked before every statement, with the same caveats as
+    /// `set_step_limit`, so calls into Rust or Python cannot be interrupted.
+    #[pyo3(signature = (seconds))]
+    fn set_timeout(&mut self, py: Python, seconds: Option<f64>) -> PyResult<()> {
+        self.ensure_module_available(py)?;
+        let timeout = seconds
+            .map(Duration::try_from_secs_f64)
+            .transpose()
+            .map_err(|e| PyValueError::new_err(e.to_string()))?;
+        self.limits().set_timeout(timeout);
+        Ok(())
+    }
+
+    /// Aborts every evaluation found to have grown the module heap past
+    /// `limit` bytes, or lifts the limit if `None`.
+    ///
+    /// The heap is checked before every statement, with the same caveats as
+    /// `set_step_limit`, and once more when the evaluation completes. This is
+    /// a check after the fact, not a cap on allocations: a single operation,
+    /// e.g. `"x" * n`, allocates in full before being caught, so it does not
+    /// protect the process from running out of memory. The heaps of the
+    /// modules loaded during the evaluation are limited separately.
+    #[pyo3(signature = (limit))]
+    fn set_heap_limit(&mut self, py: Python, limit: Option<usize>) -> PyResult<()> {
+        self.ensure_module_available(py)?;
+        self.limits().set_heap_limit(limit);
+        Ok(())
+    }
+
+    /// Makes evaluations stop at the next statement once `token` is
+    /// cancelled, with the same caveats as `set_step_limit`, or stops
+    /// watching for cancellation if `None`.
+    #[pyo3(signature = (token))]
+    fn set_cancellation_token(
+        &mut self,
+        py: Python,
+        token: Option<&Bound<'_, PyCancellationToken>>,
+    ) -> PyResult<()> {
+        self.ensure_module_available(py)?;
+        self.limits().set_cancellation(token.map(Bound::get));
+        Ok(())
+    }
+
     fn eval_module(
         &mut self,
         py: Python,
         ast: &Bound<'_, PyAstModule>,
         globals: &Bound<'_, PyGlobals>,
     ) -> PyResult<PyObject> {
-        self.ensure_module_available(py)?;
-
-        match self
-            .0
-            .eval_module(ast.borrow_mut().take_inner()?, &globals.borrow().0)
-        {
-            Ok(sl) => sl2py::py_from_sl_value(py, sl),
-            Err(e) => Err(PyRuntimeError::new_err(e.to_string())),
-        }
+        let _scope = self.begin_eval(py)?;
+
+        let ast = ast.borrow_mut().take_inner()?;
+        let globals = globals.borrow();
+        let globals = &globals.0;
+        let owner = self.heap_owner(py);
+        let conversion = self.5.clone();
+        with_conversion(&conversion, || {
+            let result = with_heap_owner(owner.clone_ref(py), || {
+                allow_threads_unsend(py, || self.0.eval_module(ast, globals))
+            });
+            let result = self.end_eval(result);
+            py_from_sl_eval_result(py, result, Some(&owner))
+        })
     }
 
     #[pyo3(signature = (function, *args, **kwargs))]
@@ -238,30 +387,259 @@ impl PyEvaluator {
         args: &Bound<'_, PyTuple>,
         kwargs: Option<&Bound<'_, PyDict>>,
     ) -> PyResult<PyObject> {
-        self.ensure_module_available(py)?;
-
-        let heap = self.0.heap();
-        let to_sl = |x| py2sl::sl_value_from_py(x, heap);
-        let function = to_sl(function);
-        let positional: Vec<_> = args
-            .iter_borrowed()
-            .map(|x| py2sl::sl_value_from_py(&x, heap)) // borrowck doesn't let me use to_sl, sigh
-            .collect();
-        let named: Vec<_> = if let Some(kwargs) = kwargs {
-            let mut tmp = Vec::with_capacity(kwargs.len());
-            for (k, v) in kwargs.clone().into_iter() {
-                tmp.push((k.extract::<String>()?, v));
+        let _scope = self.begin_eval(py)?;
+        let owner = self.heap_owner(py);
+        let conversion = self.5.clone();
+        with_conversion(&conversion, || {
+            let result = with_heap_owner(owner.clone_ref(py), || {
+                eval_function_with(&mut self.0, function, args, kwargs)
+            })?;
+            let result = self.end_eval(result);
+            py_from_sl_eval_result(py, result, Some(&owner))
+        })
+    }
+
+    /// Binds this evaluator to the current thread for the duration of the
+    /// `with` block, so that Starlark callables called directly from Python
+    /// are evaluated with it.
+    fn __enter__(slf: Bound<'_, Self>) -> PyResult<Bound<'_, Self>> {
+        slf.borrow().ensure_module_available(slf.py())?;
+        BOUND_EVALUATORS.with(|x| x.borrow_mut().push(slf.clone().unbind()));
+        Ok(slf)
+    }
+
+    #[pyo3(signature = (*_args))]
+    fn __exit__(slf: &Bound<'_, Self>, _args: &Bound<'_, PyTuple>) -> PyResult<()> {
+        BOUND_EVALUATORS.with(|x| {
+            let mut bound = x.borrow_mut();
+            match bound.last() {
+                Some(top) if top.is(slf) => {
+                    bound.pop();
+                    Ok(())
+                }
+                _ => Err(PyRuntimeError::new_err(
+                    "evaluator is not the innermost one bound to this thread",
+                )),
             }
-            tmp
-        } else {
-            Vec::new()
-        };
-        let named: Vec<_> = named.iter().map(|(k, v)| (k.as_str(), to_sl(v))).collect();
+        })
+    }
+}
+
+fn eval_function_with<'v>(
+    eval: &mut Evaluator<'v, '_, '_>,
+    function: &Bound<'_, PyAny>,
+    args: &Bound<'_, PyTuple>,
+    kwargs: Option<&Bound<'_, PyDict>>,
+) -> PyResult<starlark::Result<Value<'v>>> {
+    let heap = eval.heap();
+    let to_sl = |x| py2sl::sl_value_from_py(x, heap);
+    let function = to_sl(function)?;
+    let positional = args
+        .iter_borrowed()
+        .map(|x| py2sl::sl_value_from_py(&x, heap)) // borrowck doesn't let me use to_sl, sigh
+        .collect::<PyResult<Vec<_>>>()?;
+    let named: Vec<_> = if let Some(kwargs) = kwargs {
+        let mut tmp = Vec::with_capacity(kwargs.len());
+        for (k, v) in kwargs.clone().into_iter() {
+            tmp.push((k.extract::<String>()?, v));
+        }
+        tmp
+    } else {
+        Vec::new()
+    };
+    let named = named
+        .iter()
+        .map(|(k, v)| Ok((k.as_str(), to_sl(v)?)))
+        .collect::<PyResult<Vec<_>>>()?;
+
+    Ok(allow_threads_unsend(args.py(), || {
+        eval.eval_function(function, &positional, &named)
+    }))
+}
+
+/// Like `Python::allow_threads`, but for `f` holding on to thread-unsafe
+/// Starlark state, which is fine as `f` still runs on the current thread.
+/// `f` must re-acquire the GIL whenever it touches Python objects.
+pub(crate) fn allow_threads_unsend<R>(py: Python, f: impl FnOnce() -> R) -> R {
+    struct AssertSend<T>(T);
+
+    // Safety: see above
+    unsafe impl<T> Send for AssertSend<T> {}
+
+    let f = AssertSend(f);
+    py.allow_threads(move || {
+        let f = f;
+        AssertSend((f.0)())
+    })
+    .0
+}
+
+thread_local! {
+    /// The evaluator currently calling into Python, if any.
+    static CURRENT_EVALUATOR: Cell<Option<*mut Evaluator<'static, 'static, 'static>>> =
+        const { Cell::new(None) };
+
+    /// Evaluators bound with `with evaluator:` blocks, innermost last.
+    static BOUND_EVALUATORS: RefCell<Vec<Py<PyEvaluator>>> = const { RefCell::new(Vec::new()) };
+
+    /// Owners of the heaps of the evaluations in progress, innermost last.
+    static HEAP_OWNERS: RefCell<Vec<HeapOwner>> = const { RefCell::new(Vec::new()) };
+}
+
+/// Records `owner` as the owner of the heap being evaluated on while running
+/// `f`, so that values passed to Python during the evaluation keep it alive.
+pub(crate) fn with_heap_owner<R>(owner: HeapOwner, f: impl FnOnce() -> R) -> R {
+    struct Pop;
+
+    impl Drop for Pop {
+        fn drop(&mut self) {
+            HEAP_OWNERS.with(|x| x.borrow_mut().pop());
+        }
+    }
+
+    HEAP_OWNERS.with(|x| x.borrow_mut().push(owner));
+    let _pop = Pop;
+    f()
+}
+
+/// Returns the owner of the heap of the innermost evaluation in progress.
+pub(crate) fn current_heap_owner(py: Python) -> Option<HeapOwner> {
+    HEAP_OWNERS.with(|x| x.borrow().last().map(|x| x.clone_ref(py)))
+}
+
+/// Returns the conversion settings of the innermost evaluator bound to this
+/// thread, unless it is evaluating, in which case they are in effect already.
+pub(crate) fn bound_conversion_settings(py: Python) -> Option<Rc<ConversionSettings>> {
+    let bound = BOUND_EVALUATORS.with(|x| x.borrow().last().map(|x| x.clone_ref(py)))?;
+    let eval = bound.bind(py).try_borrow().ok()?;
+    Some(eval.5.clone())
+}
+
+/// Returns the owner of `heap`, which must be the heap of an evaluation or
+/// operation in progress.
+pub(crate) fn heap_owner_of(py: Python, heap: &Heap) -> PyResult<HeapOwner> {
+    let owners: Vec<_> = HEAP_OWNERS.with(|x| x.borrow().iter().map(|x| x.clone_ref(py)).collect());
+    for owner in owners.into_iter().rev() {
+        if owner.with_heap(py, |x| Ok(::core::ptr::eq(x, heap)))? {
+            return Ok(owner);
+        }
+    }
+    Err(PyRuntimeError::new_err(
+        "the heap owning this Starlark value is unknown",
+    ))
+}
 
-        match self.0.eval_function(function, &positional, &named) {
+/// Makes `heap`, which must be the heap of an evaluation or operation in
+/// progress, keep the frozen heap `frozen` alive.
+pub(crate) fn keep_frozen_heap_alive(
+    py: Python,
+    heap: &Heap,
+    frozen: &FrozenHeapRef,
+) -> PyResult<()> {
+    heap_owner_of(py, heap)?.keep_alive(py, frozen)
+}
+
+/// Records `eval` as the evaluator calling into Python while running `f`, so
+/// that Starlark callables called back from Python reuse it. `eval` is lent
+/// out for the duration of `f`, which must not use it, nor anything borrowed
+/// from it.
+pub(crate) fn with_current_evaluator<R>(
+    eval: &mut Evaluator<'_, '_, '_>,
+    f: impl FnOnce() -> R,
+) -> R {
+    struct Restore(Option<*mut Evaluator<'static, 'static, 'static>>);
+
+    impl Drop for Restore {
+        fn drop(&mut self) {
+            CURRENT_EVALUATOR.with(|x| x.set(self.0));
+        }
+    }
+
+    let ptr = eval as *mut Evaluator<'_, '_, '_> as *mut Evaluator<'static, 'static, 'static>;
+    let _restore = Restore(CURRENT_EVALUATOR.with(|x| x.replace(Some(ptr))));
+    f()
+}
+
+/// The evaluator calling into Python, taken out of `CURRENT_EVALUATOR` for
+/// exclusive use, and put back when dropped.
+struct CurrentEvaluator(*mut Evaluator<'static, 'static, 'static>);
+
+impl CurrentEvaluator {
+    fn take() -> Option<Self> {
+        CURRENT_EVALUATOR.with(Cell::take).map(Self)
+    }
+}
+
+impl Drop for CurrentEvaluator {
+    fn drop(&mut self) {
+        CURRENT_EVALUATOR.with(|x| x.set(Some(self.0)));
+    }
+}
+
+/// Calls a Starlark callable from Python.
+///
+/// The evaluator calling into Python is reused if there is one, i.e. if this
+/// is a callback from Python code called by Starlark. Otherwise the innermost
+/// evaluator bound with a `with` block is used, and failing that, a fresh
+/// evaluator with the default settings, on the module owning the callable if
+/// it is not frozen, or an empty module otherwise.
+pub(crate) fn call_sl_callable(
+    function: &Bound<'_, PyAny>,
+    args: &Bound<'_, PyTuple>,
+    kwargs: Option<&Bound<'_, PyDict>>,
+) -> PyResult<PyObject> {
+    let py = function.py();
+
+    if let Some(current) = CurrentEvaluator::take() {
+        // Safety: the evaluator is suspended in a call into Python further up
+        // the stack, which is where we are now, and does not touch it until
+        // that call returns. It was taken out of `CURRENT_EVALUATOR`, so this
+        // is the only reference derived from the pointer until `current` puts
+        // it back when dropped; nested callbacks get it passed down again by
+        // `with_current_evaluator`, as a reborrow of this one.
+        let eval = unsafe { &mut *current.0 };
+        // errors from the Python side are handled by the outer evaluation
+        return match eval_function_with(eval, function, args, kwargs)? {
             Ok(sl) => sl2py::py_from_sl_value(py, sl),
-            Err(e) => Err(PyRuntimeError::new_err(e.to_string())),
+            Err(e) => Err(py_err_from_sl_eval_error(py, e)),
+        };
+    }
+
+    let bound = BOUND_EVALUATORS.with(|x| x.borrow().last().map(|x| x.clone_ref(py)));
+    let eval = match bound {
+        Some(eval) => eval.into_bound(py),
+        None => {
+            // unfrozen callables can only be called on their own heap
+            let module = match function.downcast::<PyValue>() {
+                Ok(x) => x.borrow().owner().module().clone_ref(py).into_bound(py),
+                Err(_) => Bound::new(py, PyModule::from(Module::new()))?,
+            };
+            Bound::new(py, PyEvaluator::new(module)?)?
         }
+    };
+    let mut eval = eval.try_borrow_mut()?;
+    let _scope = eval.begin_eval(py)?;
+    // the module has to outlive the evaluator, for the results
+    let owner = eval.heap_owner(py);
+    let conversion = eval.5.clone();
+    with_conversion(&conversion, || {
+        let result = with_heap_owner(owner.clone_ref(py), || {
+            eval_function_with(&mut eval.0, function, args, kwargs)
+        })?;
+        let result = eval.end_eval(result);
+        py_from_sl_eval_result(py, result, Some(&owner))
+    })
+}
+
+/// Converts the result of an evaluation to Python.
+fn py_from_sl_eval_result(
+    py: Python,
+    result: starlark::Result<Value>,
+    owner: Option<&HeapOwner>,
+) -> PyResult<PyObject> {
+    match result {
+        Ok(sl) => sl2py::py_from_sl_value_owned(py, sl, owner),
+        Err(e) => Err(py_err_from_sl_eval_error(py, e)),
     }
 }
 
@@ -352,23 +730,30 @@ impl PyObjectFileLoader {
 }
 
 impl FileLoader for PyObjectFileLoader {
-    fn load(&self, path: &str) -> anyhow::Result<FrozenModule> {
+    fn load(&self, path: &str) -> starlark::Result<FrozenModule> {
         if let Some(inner) = self.0.as_ref() {
             Python::with_gil(|py| {
-                // first check if it's a PyDictFileLoader and forward to its impl
+                // first check if it's one of our loaders and forward to its impl
                 if let Ok(x) = inner.downcast_bound::<PyDictFileLoader>(py) {
                     return x.borrow().load(path);
                 }
+                if let Ok(x) = inner.downcast_bound::<PyFilesystemLoader>(py) {
+                    let loader = x.get();
+                    return py.allow_threads(|| loader.load(path));
+                }
 
                 // duck-typing
                 // call the wrapped PyObject's "load" method with the path
                 // and expect the return value to be exactly PyFrozenModule
                 let name = intern!(py, "load");
                 let args = PyTuple::new_bound(py, &[path]);
-                Ok(inner
-                    .call_method_bound(py, name, args, None)?
-                    .extract::<PyFrozenModule>(py)?
-                    .0)
+                let result: PyResult<_> = (|| {
+                    Ok(inner
+                        .call_method_bound(py, name, args, None)?
+                        .extract::<PyFrozenModule>(py)?
+                        .0)
+                })();
+                result.map_err(|e| LoadFailure::from_py_err(path, e).into())
             })
         } else {
             // this should never happen because we control the only place where
@@ -396,16 +781,21 @@ impl PyDictFileLoader {
 }
 
 impl FileLoader for PyDictFileLoader {
-    fn load(&self, path: &str) -> anyhow::Result<FrozenModule> {
-        let result: anyhow::Result<_> =
-            Python::with_gil(|py| match self.0.bind(py).get_item(path)? {
-                Some(v) => Ok(Some(v.extract::<PyFrozenModule>()?)),
-                None => Ok(None),
-            });
-        result?.map(|x| x.0).ok_or(anyhow!(
-            "DictFileLoader does not know the module `{}`",
-            path
-        ))
+    fn load(&self, path: &str) -> starlark::Result<FrozenModule> {
+        let result: PyResult<_> = Python::with_gil(|py| match self.0.bind(py).get_item(path)? {
+            Some(v) => Ok(Some(v.extract::<PyFrozenModule>()?)),
+            None => Ok(None),
+        });
+        result
+            .map_err(starlark::Error::new_other)?
+            .map(|x| x.0)
+            .ok_or_else(|| {
+                LoadFailure::new(
+                    path,
+                    format!("DictFileLoader does not know the module `{}`", path),
+                )
+                .into()
+            })
     }
 }
 
@@ -423,7 +813,7 @@ impl PyObjectPrintHandler {
 }
 
 impl PrintHandler for PyObjectPrintHandler {
-    fn println(&self, text: &str) -> anyhow::Result<()> {
+    fn println(&self, text: &str) -> starlark::Result<()> {
         if let Some(inner) = self.0.as_ref() {
             Python::with_gil(|py| {
                 // duck-typing
@@ -431,7 +821,9 @@ impl PrintHandler for PyObjectPrintHandler {
                 // and ignore the return value
                 let name = intern!(py, "println");
                 let args = PyTuple::new_bound(py, &[text]);
-                inner.call_method_bound(py, name, args, None)?;
+                inner
+                    .call_method_bound(py, name, args, None)
+                    .map_err(starlark::Error::new_other)?;
                 Ok(())
             })
         } else {
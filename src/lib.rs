@@ -1,19 +1,31 @@
 use pyo3::prelude::*;
 
+mod analysis;
 mod codemap;
+mod converters;
+mod docs;
 mod environment;
 mod errors;
 mod eval;
+mod library;
+mod limits;
+mod loader;
 mod py2sl;
 mod repr_utils;
+mod roots;
 mod sl2py;
 mod syntax;
+mod typing;
 mod values;
 
 #[pymodule]
 mod xingque {
     use super::*;
 
+    #[pymodule_export]
+    use analysis::PyEvalSeverity;
+    #[pymodule_export]
+    use analysis::PyLint;
     #[pymodule_export]
     use codemap::PyCodeMap;
     #[pymodule_export]
@@ -31,6 +43,36 @@ mod xingque {
     #[pymodule_export]
     use codemap::PySpan;
     #[pymodule_export]
+    use converters::register_converter;
+    #[pymodule_export]
+    use converters::register_enum_type;
+    #[pymodule_export]
+    use converters::register_record_type;
+    #[pymodule_export]
+    use converters::unregister_converter;
+    #[pymodule_export]
+    use converters::unregister_enum_type;
+    #[pymodule_export]
+    use converters::unregister_record_type;
+    #[pymodule_export]
+    use docs::render_docs_markdown;
+    #[pymodule_export]
+    use docs::PyDocFunction;
+    #[pymodule_export]
+    use docs::PyDocModule;
+    #[pymodule_export]
+    use docs::PyDocParam;
+    #[pymodule_export]
+    use docs::PyDocParamKind;
+    #[pymodule_export]
+    use docs::PyDocProperty;
+    #[pymodule_export]
+    use docs::PyDocReturn;
+    #[pymodule_export]
+    use docs::PyDocString;
+    #[pymodule_export]
+    use docs::PyDocType;
+    #[pymodule_export]
     use environment::PyFrozenModule;
     #[pymodule_export]
     use environment::PyGlobals;
@@ -41,8 +83,26 @@ mod xingque {
     #[pymodule_export]
     use environment::PyModule;
     #[pymodule_export]
+    use errors::EvalError;
+    #[pymodule_export]
+    use errors::InternalError;
+    #[pymodule_export]
+    use errors::LoadError;
+    #[pymodule_export]
+    use errors::ParseError;
+    #[pymodule_export]
+    use errors::PyErrorKind;
+    #[pymodule_export]
     use errors::PyFrame;
     #[pymodule_export]
+    use errors::StarlarkCancelled;
+    #[pymodule_export]
+    use errors::StarlarkError;
+    #[pymodule_export]
+    use errors::StarlarkResourceExhausted;
+    #[pymodule_export]
+    use errors::TypeCheckError;
+    #[pymodule_export]
     use eval::PyCallStack;
     #[pymodule_export]
     use eval::PyDictFileLoader;
@@ -51,12 +111,34 @@ mod xingque {
     #[pymodule_export]
     use eval::PyProfileMode;
     #[pymodule_export]
+    use library::builtin;
+    #[pymodule_export]
+    use library::PyBuiltinOptions;
+    #[pymodule_export]
+    use library::PyStarlarkLibrary;
+    #[pymodule_export]
+    use limits::PyCancellationToken;
+    #[pymodule_export]
+    use loader::PyFilesystemLoader;
+    #[pymodule_export]
     use syntax::PyAstModule;
     #[pymodule_export]
     use syntax::PyDialect;
     #[pymodule_export]
     use syntax::PyDialectTypes;
     #[pymodule_export]
+    use typing::PyApproximation;
+    #[pymodule_export]
+    use typing::PyInterface;
+    #[pymodule_export]
+    use typing::PyTy;
+    #[pymodule_export]
+    use typing::PyTypeMap;
+    #[pymodule_export]
+    use values::PyCallableFrozenValue;
+    #[pymodule_export]
+    use values::PyCallableValue;
+    #[pymodule_export]
     use values::PyFrozenValue;
     #[pymodule_export]
     use values::PyHeap;
@@ -71,7 +153,7 @@ mod xingque {
             "VERSION",
             option_env!("CARGO_PKG_VERSION").unwrap_or("unknown"),
         )?;
-        m.add("STARLARK_RUST_VERSION", "0.12.0")?; // TODO: query this from Cargo
+        m.add("STARLARK_RUST_VERSION", "0.13.0")?; // TODO: query this from Cargo
         Ok(())
     }
 }
@@ -0,0 +1,189 @@
+use pyo3::exceptions::PyTypeError;
+use pyo3::prelude::*;
+use pyo3::types::{PyCFunction, PyDict, PyTuple, PyType};
+use starlark::environment::GlobalsBuilder;
+
+use crate::environment::{PyGlobals, PyGlobalsBuilder, PySubGlobalsBuilder};
+use crate::py2sl::{sl_frozen_value_from_py, SlPyFunction};
+
+const BUILTIN_ATTR: &str = "__xingque_builtin__";
+
+/// Options recorded by the `builtin` decorator.
+#[pyclass(module = "xingque", name = "_BuiltinOptions", frozen)]
+pub(crate) struct PyBuiltinOptions {
+    name: Option<String>,
+    doc: Option<String>,
+    signature: Option<PyObject>,
+}
+
+/// Marks a function as a Starlark builtin, optionally overriding the name,
+/// docstring or signature it is exposed with.
+///
+/// Can be used both as `@builtin` and as `@builtin(name=...)`. The function
+/// itself is returned unchanged.
+#[pyfunction]
+#[pyo3(signature = (f = None, *, name = None, doc = None, signature = None))]
+pub(crate) fn builtin(
+    py: Python<'_>,
+    f: Option<&Bound<'_, PyAny>>,
+    name: Option<String>,
+    doc: Option<String>,
+    signature: Option<PyObject>,
+) -> PyResult<PyObject> {
+    let options = Py::new(
+        py,
+        PyBuiltinOptions {
+            name,
+            doc,
+            signature,
+        },
+    )?;
+    match f {
+        Some(f) => mark_builtin(f, &options),
+        None => {
+            let decorator = PyCFunction::new_closure_bound(
+                py,
+                None,
+                None,
+                move |args: &Bound<'_, PyTuple>, _kwargs: Option<&Bound<'_, PyDict>>| {
+                    mark_builtin(&args.get_item(0)?, &options)
+                },
+            )?;
+            Ok(decorator.into_any().unbind())
+        }
+    }
+}
+
+fn mark_builtin(f: &Bound<'_, PyAny>, options: &Py<PyBuiltinOptions>) -> PyResult<PyObject> {
+    f.setattr(BUILTIN_ATTR, options)?;
+    Ok(f.clone().unbind())
+}
+
+/// Base class for declaratively defined sets of Starlark builtins.
+///
+/// Every public attribute of a subclass, as well as every private method
+/// decorated with `builtin`, becomes a global (or a struct member, when
+/// nested): routines become Starlark functions as if registered with
+/// `GlobalsBuilder.set_function`, nested `StarlarkLibrary` subclasses become
+/// struct namespaces, and everything else is set as a plain value. Methods
+/// are bound to the library instance, so they can access its state.
+#[pyclass(module = "xingque", name = "StarlarkLibrary", subclass)]
+pub(crate) struct PyStarlarkLibrary;
+
+#[pymethods]
+impl PyStarlarkLibrary {
+    #[new]
+    #[pyo3(signature = (*_args, **_kwargs))]
+    fn py_new(_args: &Bound<'_, PyTuple>, _kwargs: Option<&Bound<'_, PyDict>>) -> Self {
+        Self
+    }
+
+    /// Registers the members of this library into the given builder.
+    fn register(slf: &Bound<'_, Self>, builder: &Bound<'_, PyAny>) -> PyResult<()> {
+        if let Ok(gb) = builder.downcast::<PyGlobalsBuilder>() {
+            let mut gb = gb.borrow_mut();
+            register_library(slf.as_any(), gb.inner_mut()?, true)
+        } else if let Ok(gb) = builder.downcast::<PySubGlobalsBuilder>() {
+            register_library(slf.as_any(), gb.borrow_mut().0, false)
+        } else {
+            Err(PyTypeError::new_err("expected a GlobalsBuilder"))
+        }
+    }
+
+    /// Builds `Globals` out of this library, on top of the given builder or
+    /// `GlobalsBuilder.standard()` if not given.
+    #[pyo3(signature = (builder = None))]
+    fn build(
+        slf: &Bound<'_, Self>,
+        builder: Option<&Bound<'_, PyGlobalsBuilder>>,
+    ) -> PyResult<PyGlobals> {
+        match builder {
+            Some(builder) => {
+                let mut gb = builder.borrow_mut();
+                register_library(slf.as_any(), gb.inner_mut()?, true)?;
+                gb.build()
+            }
+            None => {
+                let mut gb = GlobalsBuilder::standard();
+                register_library(slf.as_any(), &mut gb, true)?;
+                Ok(gb.build().into())
+            }
+        }
+    }
+}
+
+fn register_library(
+    lib: &Bound<'_, PyAny>,
+    gb: &mut GlobalsBuilder,
+    top_level: bool,
+) -> PyResult<()> {
+    let py = lib.py();
+    let inspect = py.import_bound("inspect")?;
+    let base = py.get_type_bound::<PyStarlarkLibrary>();
+
+    if top_level {
+        if let Some(doc) = inspect
+            .call_method1("getdoc", (lib.get_type(),))?
+            .extract::<Option<String>>()?
+        {
+            gb.set_docstring(&doc);
+        }
+    }
+
+    // walk the MRO from the most basic class, so that members are registered
+    // in definition order, and overridden members only once
+    let mut names: Vec<String> = Vec::new();
+    for cls in lib.get_type().mro().iter().rev() {
+        let cls = cls.downcast::<PyType>()?;
+        if cls.is_subclass(&base)? && !cls.is(&base) {
+            for name in cls.getattr("__dict__")?.iter()? {
+                let name: String = name?.extract()?;
+                if !name.starts_with("__") && !names.contains(&name) {
+                    names.push(name);
+                }
+            }
+        }
+    }
+
+    for name in names {
+        let value = lib.getattr(name.as_str())?;
+        // private members are only exposed if explicitly marked as builtins
+        if name.starts_with('_') && !value.hasattr(BUILTIN_ATTR)? {
+            continue;
+        }
+        if let Ok(cls) = value.downcast::<PyType>() {
+            if cls.is_subclass(&base)? {
+                let nested = cls.call0()?;
+                register_struct(gb, &name, &nested)?;
+                continue;
+            }
+        }
+        if value.is_instance(&base)? {
+            register_struct(gb, &name, &value)?;
+        } else if inspect.call_method1("isroutine", (&value,))?.is_truthy()? {
+            let f = match value.getattr(BUILTIN_ATTR) {
+                Ok(options) => {
+                    let options = options.downcast::<PyBuiltinOptions>()?.get();
+                    let name = options.name.as_deref().unwrap_or(&name);
+                    let signature = options.signature.as_ref().map(|x| x.bind(py));
+                    SlPyFunction::new(name, &value, signature, options.doc.clone())?
+                }
+                Err(_) => SlPyFunction::new(&name, &value, None, None)?,
+            };
+            let name = f.name().to_owned();
+            gb.set(&name, f);
+        } else {
+            let value = sl_frozen_value_from_py(&value, gb.frozen_heap())?;
+            gb.set(&name, value);
+        }
+    }
+    Ok(())
+}
+
+fn register_struct(gb: &mut GlobalsBuilder, name: &str, lib: &Bound<'_, PyAny>) -> PyResult<()> {
+    let mut result = Ok(());
+    gb.namespace(name, |gb| {
+        result = register_library(lib, gb, false);
+    });
+    result
+}
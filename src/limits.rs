@@ -0,0 +1,401 @@
+use std::cell::RefCell;
+use std::fmt;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use pyo3::prelude::*;
+use starlark::codemap::{FileSpan, FileSpanRef};
+use starlark::eval::{BeforeStmtFuncDyn, CallStack, Evaluator};
+use starlark::values::{Heap, Value};
+use starlark::ErrorKind;
+
+use crate::errors::PyErrFromCall;
+
+/// How often signals are checked for, in steps.
+const SIGNAL_CHECK_INTERVAL: u64 = 1024;
+
+thread_local! {
+    /// The evaluations running on this thread, innermost last.
+    static EVALUATIONS: RefCell<Vec<Evaluation>> = const { RefCell::new(Vec::new()) };
+}
+
+struct Evaluation {
+    /// The limits of the evaluation, which the modules it loads are subject
+    /// to as well.
+    limits: Option<Arc<Limits>>,
+    /// The exception that ended a loop over a Python iterator early, failing
+    /// the evaluation at its next statement or when it completes.
+    failure: Option<PyErr>,
+}
+
+/// The execution budget of an evaluator and its cancellation token, checked
+/// before every statement, and for the heap, at the end of the evaluation too.
+///
+/// Exceeding the budget fails the statement about to be executed with an
+/// `Abort` error, which unwinds the evaluation like any other error. Every
+/// later statement of the evaluation fails the same way, so Starlark code
+/// cannot carry on past an abort.
+///
+/// The budget is shared with the evaluators of the modules loaded during the
+/// evaluation, possibly on other threads, except for the heap limit, which
+/// applies to the heap of each module separately.
+///
+/// starlark-rust has no hook into allocations, so the heap limit is only
+/// checked after the fact: a single operation such as `"x" * n` allocates in
+/// full before being caught, and can still run the process out of memory.
+#[derive(Debug, Default)]
+pub(crate) struct Limits {
+    settings: Mutex<Settings>,
+    steps: AtomicU64,
+    deadline: Mutex<Option<Instant>>,
+}
+
+#[derive(Debug, Default, Clone)]
+struct Settings {
+    step_limit: Option<u64>,
+    timeout: Option<Duration>,
+    heap_limit: Option<usize>,
+    cancellation: Option<Arc<Cancellation>>,
+}
+
+impl Limits {
+    pub(crate) fn set_step_limit(&self, limit: Option<u64>) {
+        self.settings.lock().unwrap().step_limit = limit;
+    }
+
+    pub(crate) fn set_timeout(&self, timeout: Option<Duration>) {
+        self.settings.lock().unwrap().timeout = timeout;
+    }
+
+    pub(crate) fn set_heap_limit(&self, limit: Option<usize>) {
+        self.settings.lock().unwrap().heap_limit = limit;
+    }
+
+    pub(crate) fn set_cancellation(&self, token: Option<&PyCancellationToken>) {
+        self.settings.lock().unwrap().cancellation = token.map(|x| x.0.clone());
+    }
+
+    /// Starts counting the budget afresh, for a new top-level evaluation.
+    pub(crate) fn start(&self) {
+        self.steps.store(0, Ordering::Relaxed);
+        let timeout = self.settings.lock().unwrap().timeout;
+        *self.deadline.lock().unwrap() = timeout.map(|x| Instant::now() + x);
+    }
+
+    fn check(&self, span: FileSpanRef, eval: &Evaluator) -> starlark::Result<()> {
+        match self.count_step(eval.heap(), true) {
+            Some((kind, message, cause)) => Err(starlark::Error::new_other(Abort {
+                kind,
+                message,
+                span: Some(span.to_file_span()),
+                call_stack: eval.call_stack(),
+                cause,
+            })),
+            None => Ok(()),
+        }
+    }
+
+    /// Counts a step, returning why the evaluation is to be aborted if it is,
+    /// along with the exception raised by a signal handler if that is why.
+    fn count_step(
+        &self,
+        heap: &Heap,
+        check_signals: bool,
+    ) -> Option<(AbortKind, String, Option<PyErr>)> {
+        let steps = self.steps.fetch_add(1, Ordering::Relaxed) + 1;
+        // not holding the lock while calling into Python below
+        let settings = self.settings.lock().unwrap().clone();
+        let deadline = *self.deadline.lock().unwrap();
+
+        if let Some(limit) = settings.step_limit.filter(|&x| steps > x) {
+            Some((
+                AbortKind::ResourceExhausted,
+                format!("step limit of {} exceeded", limit),
+                None,
+            ))
+        } else if deadline.is_some_and(|x| Instant::now() >= x) {
+            Some((
+                AbortKind::ResourceExhausted,
+                format!(
+                    "timeout of {:?} exceeded",
+                    settings.timeout.unwrap_or_default()
+                ),
+                None,
+            ))
+        } else if let Some(message) = heap_exceeded(settings.heap_limit, heap) {
+            Some((AbortKind::ResourceExhausted, message, None))
+        } else if let Some(cancellation) = &settings.cancellation {
+            let mut cause = None;
+            if check_signals
+                && cancellation.check_signals
+                && steps.is_multiple_of(SIGNAL_CHECK_INTERVAL)
+            {
+                if let Err(e) = Python::with_gil(|py| py.check_signals()) {
+                    cancellation.cancel();
+                    cause = Some(e);
+                }
+            }
+            cancellation.is_cancelled().then(|| {
+                (
+                    AbortKind::Cancelled,
+                    "evaluation was cancelled".to_owned(),
+                    cause,
+                )
+            })
+        } else {
+            None
+        }
+    }
+
+    /// Checks the heap limit once more at the end of an evaluation, which
+    /// catches allocations made by its last statement.
+    pub(crate) fn check_heap(&self, heap: &Heap) -> starlark::Result<()> {
+        let limit = self.settings.lock().unwrap().heap_limit;
+        match heap_exceeded(limit, heap) {
+            Some(message) => Err(starlark::Error::new_other(Abort {
+                kind: AbortKind::ResourceExhausted,
+                message,
+                span: None,
+                call_stack: CallStack::default(),
+                cause: None,
+            })),
+            None => Ok(()),
+        }
+    }
+}
+
+fn heap_exceeded(limit: Option<usize>, heap: &Heap) -> Option<String> {
+    let limit = limit?;
+    (heap.allocated_bytes() > limit).then(|| format!("heap limit of {} bytes exceeded", limit))
+}
+
+/// Makes `eval` check the limits of the evaluation running on this thread, if
+/// any, before every statement it executes, and fail it with the exception
+/// recorded by `fail_evaluation`, if any.
+pub(crate) fn install_limits<'a, 'e: 'a>(eval: &mut Evaluator<'_, 'a, 'e>) {
+    struct Hook;
+
+    impl<'a, 'e: 'a> BeforeStmtFuncDyn<'a, 'e> for Hook {
+        fn call<'v>(
+            &mut self,
+            span: FileSpanRef,
+            eval: &mut Evaluator<'v, 'a, 'e>,
+        ) -> starlark::Result<()> {
+            if let Some(e) = take_failure() {
+                let e = PyErrFromCall::new(e, eval.call_stack());
+                return Err(starlark::Error::new_kind(ErrorKind::Value(e.into())));
+            }
+            match current_limits() {
+                Some(limits) => limits.check(span, eval),
+                None => Ok(()),
+            }
+        }
+    }
+
+    let hook: Box<dyn BeforeStmtFuncDyn<'a, 'e>> = Box::new(Hook);
+    eval.before_stmt_for_dap(hook.into());
+}
+
+/// The limits set for an evaluator created from Python, if any.
+///
+/// The hook checking limits is installed on first use, as checking them slows
+/// down evaluation.
+#[derive(Debug, Default)]
+pub(crate) struct EvaluatorLimits {
+    limits: Option<Arc<Limits>>,
+    installed: bool,
+}
+
+impl EvaluatorLimits {
+    pub(crate) fn get_or_insert<'a, 'e: 'a>(
+        &mut self,
+        eval: &mut Evaluator<'_, 'a, 'e>,
+    ) -> &Limits {
+        self.install(eval);
+        self.limits.get_or_insert_with(Arc::default)
+    }
+
+    /// Makes the limits of the evaluator the limits of the evaluation it is
+    /// about to run, until the returned guard is dropped.
+    ///
+    /// An evaluator without limits of its own is subject to those of the
+    /// evaluation running on this thread, if any, so that modules evaluated by
+    /// loaders written in Python count towards the budget of the evaluation
+    /// loading them.
+    pub(crate) fn enter<'a, 'e: 'a>(&mut self, eval: &mut Evaluator<'_, 'a, 'e>) -> LimitsScope {
+        let limits = match &self.limits {
+            Some(x) => {
+                x.start();
+                Some(x.clone())
+            }
+            None => current_limits(),
+        };
+        if limits.is_some() {
+            self.install(eval);
+        }
+        enter_limits(limits)
+    }
+
+    fn install<'a, 'e: 'a>(&mut self, eval: &mut Evaluator<'_, 'a, 'e>) {
+        if !self.installed {
+            install_limits(eval);
+            self.installed = true;
+        }
+    }
+}
+
+/// Makes `limits` the limits of the evaluation running on this thread until
+/// the returned guard is dropped.
+pub(crate) fn enter_limits(limits: Option<Arc<Limits>>) -> LimitsScope {
+    EVALUATIONS.with(|x| {
+        x.borrow_mut().push(Evaluation {
+            limits,
+            failure: None,
+        })
+    });
+    LimitsScope(())
+}
+
+/// Returns the limits of the innermost evaluation running on this thread, if
+/// it has any.
+pub(crate) fn current_limits() -> Option<Arc<Limits>> {
+    EVALUATIONS.with(|x| x.borrow().last().and_then(|x| x.limits.clone()))
+}
+
+/// Counts an item pulled from a Python iterator as a step of the evaluation
+/// running on this thread, returning whether to stop the iteration, as the
+/// evaluation is to be aborted at its next statement.
+///
+/// Signals are left for the statements to check, or for the Python iterator
+/// to handle.
+pub(crate) fn count_iteration(heap: &Heap) -> bool {
+    current_limits().is_some_and(|x| x.count_step(heap, false).is_some())
+}
+
+/// Fails the evaluation running on this thread with `e`, raised by a Python
+/// iterator, at its next statement if it checks limits, or else when it
+/// completes.
+pub(crate) fn fail_evaluation(e: PyErr) {
+    EVALUATIONS.with(|x| {
+        if let Some(evaluation) = x.borrow_mut().last_mut() {
+            // keep the first failure, as the later ones are likely consequences
+            evaluation.failure.get_or_insert(e);
+        }
+    })
+}
+
+fn take_failure() -> Option<PyErr> {
+    EVALUATIONS.with(|x| x.borrow_mut().last_mut().and_then(|x| x.failure.take()))
+}
+
+/// Completes the evaluation running on this thread with `result`, unless it
+/// is to fail with the exception recorded by `fail_evaluation`, or its heap
+/// exceeds the limit.
+pub(crate) fn end_evaluation<'v>(
+    heap: &Heap,
+    result: starlark::Result<Value<'v>>,
+) -> starlark::Result<Value<'v>> {
+    if let Some(e) = take_failure() {
+        return Err(starlark::Error::new_other(e));
+    }
+    match (current_limits(), result) {
+        (Some(limits), Ok(v)) => limits.check_heap(heap).map(|()| v),
+        (_, result) => result,
+    }
+}
+
+pub(crate) struct LimitsScope(());
+
+impl Drop for LimitsScope {
+    fn drop(&mut self) {
+        EVALUATIONS.with(|x| x.borrow_mut().pop());
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct Cancellation {
+    cancelled: AtomicBool,
+    check_signals: bool,
+}
+
+impl Cancellation {
+    fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+    }
+
+    fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Relaxed)
+    }
+}
+
+/// A token for cancelling evaluations from any thread.
+///
+/// Evaluations by evaluators given the token stop at the next statement once
+/// it is cancelled. With `check_signals`, the token is also cancelled when a
+/// signal handler raises, e.g. on Ctrl-C.
+#[pyclass(module = "xingque", name = "CancellationToken", frozen)]
+pub(crate) struct PyCancellationToken(Arc<Cancellation>);
+
+#[pymethods]
+impl PyCancellationToken {
+    #[new]
+    #[pyo3(signature = (*, check_signals = false))]
+    fn py_new(check_signals: bool) -> Self {
+        Self(Arc::new(Cancellation {
+            cancelled: AtomicBool::new(false),
+            check_signals,
+        }))
+    }
+
+    fn cancel(&self) {
+        self.0.cancel();
+    }
+
+    #[getter]
+    fn cancelled(&self) -> bool {
+        self.0.is_cancelled()
+    }
+
+    #[getter]
+    fn check_signals(&self) -> bool {
+        self.0.check_signals
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum AbortKind {
+    ResourceExhausted,
+    Cancelled,
+}
+
+/// An evaluation aborted from the outside, because it exceeded its execution
+/// budget or it was cancelled.
+#[derive(Debug)]
+pub(crate) struct Abort {
+    pub(crate) kind: AbortKind,
+    pub(crate) message: String,
+    /// The statement about to be executed at the time, if any.
+    pub(crate) span: Option<FileSpan>,
+    pub(crate) call_stack: CallStack,
+    /// The exception raised by a signal handler, if that is the cause.
+    pub(crate) cause: Option<PyErr>,
+}
+
+impl Abort {
+    /// Returns the abort causing `e`, if any.
+    pub(crate) fn from_sl_error(e: &starlark::Error) -> Option<&Self> {
+        match e.kind() {
+            ErrorKind::Other(x) => x.downcast_ref::<Self>(),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Abort {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl std::error::Error for Abort {}
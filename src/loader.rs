@@ -0,0 +1,584 @@
+use std::collections::HashMap;
+use std::mem;
+use std::num::NonZeroUsize;
+use std::path::{Component, Path, PathBuf};
+use std::sync::{Condvar, Mutex};
+use std::thread::{self, ThreadId};
+
+use pyo3::exceptions::PyValueError;
+use pyo3::prelude::*;
+use starlark::codemap::FileSpan;
+use starlark::environment::{FrozenModule, Globals, Module};
+use starlark::eval::{Evaluator, FileLoader};
+use starlark::syntax::{AstModule, Dialect};
+
+use crate::environment::{PyFrozenModule, PyGlobals, PyModule};
+use crate::errors::{py_err_from_sl_eval_error, LoadFailure};
+use crate::eval::with_heap_owner;
+use crate::limits::{current_limits, end_evaluation, enter_limits, install_limits};
+use crate::syntax::PyDialect;
+use crate::values::HeapOwner;
+
+/// A file loader that loads modules from the filesystem, evaluating and
+/// freezing them (and in turn their dependencies) on demand.
+///
+/// `//pkg/dir:file.star` labels are looked up in each of the roots in order,
+/// and `:file.star` and plain relative paths are resolved relative to the
+/// directory of the loading module, or the first root for modules not loaded
+/// by this loader. Loaded modules are cached by their canonical path, and
+/// each is evaluated once, even when loaded from several threads at once.
+///
+/// Modules outside of the roots cannot be loaded: labels with absolute paths
+/// or `..` components are rejected, as are paths leading out of the roots
+/// through symlinks.
+#[pyclass(module = "xingque", name = "FilesystemLoader", frozen)]
+pub(crate) struct PyFilesystemLoader {
+    roots: Vec<PathBuf>,
+    globals: Globals,
+    dialect: Dialect,
+    cache: Mutex<Cache>,
+    /// Notified whenever a module is done being evaluated.
+    evaluated: Condvar,
+}
+
+/// The modules loaded so far, by canonical path, and the modules being
+/// evaluated.
+#[derive(Default)]
+struct Cache {
+    modules: HashMap<PathBuf, FrozenModule>,
+    /// The modules being evaluated, with the threads evaluating them.
+    evaluating: HashMap<PathBuf, ThreadId>,
+    /// The modules the threads waiting for another thread to evaluate one are
+    /// waiting for.
+    waiting: HashMap<ThreadId, PathBuf>,
+}
+
+impl Cache {
+    /// Returns the modules the thread evaluating `path` waits for in turn,
+    /// up to one evaluated by `thread`, if waiting for `path` on `thread`
+    /// would deadlock.
+    fn deadlock(&self, path: &Path, thread: ThreadId) -> Option<Vec<PathBuf>> {
+        let mut cycle = vec![path.to_owned()];
+        let mut owner = self.evaluating[path];
+        while owner != thread {
+            let next = self.waiting.get(&owner)?;
+            owner = *self.evaluating.get(next)?;
+            cycle.push(next.clone());
+        }
+        Some(cycle)
+    }
+}
+
+#[pymethods]
+impl PyFilesystemLoader {
+    #[new]
+    #[pyo3(signature = (roots, globals, dialect = &PyDialect::STANDARD))]
+    fn py_new(roots: Vec<PathBuf>, globals: &Bound<'_, PyGlobals>, dialect: &PyDialect) -> Self {
+        Self {
+            roots,
+            globals: globals.borrow().0.clone(),
+            dialect: dialect.0.clone(),
+            cache: Mutex::default(),
+            evaluated: Condvar::new(),
+        }
+    }
+
+    #[getter]
+    fn roots(&self) -> Vec<PathBuf> {
+        self.roots.clone()
+    }
+
+    fn load(&self, py: Python, path: &str) -> PyResult<PyFrozenModule> {
+        match py.allow_threads(|| FileLoader::load(self, path)) {
+            Ok(x) => Ok(x.into()),
+            Err(e) => Err(py_err_from_sl_eval_error(py, e)),
+        }
+    }
+
+    /// Loads the modules at `paths` along with all their dependencies, and
+    /// returns all of them by their canonical paths.
+    ///
+    /// Modules that do not depend on each other are evaluated in parallel, on
+    /// up to `max_workers` threads (the number of CPUs by default), without
+    /// holding the GIL except when calling into Python.
+    #[pyo3(signature = (paths, *, max_workers = None))]
+    fn load_graph(
+        &self,
+        py: Python,
+        paths: Vec<String>,
+        max_workers: Option<usize>,
+    ) -> PyResult<HashMap<PathBuf, PyFrozenModule>> {
+        let max_workers = match max_workers {
+            Some(0) => return Err(PyValueError::new_err("max_workers must be positive")),
+            Some(x) => x,
+            None => thread::available_parallelism().map_or(1, NonZeroUsize::get),
+        };
+        let result = py.allow_threads(|| ModuleGraph::new(self, &paths)?.eval(self, max_workers));
+        match result {
+            Ok(x) => Ok(x.into_iter().map(|(k, v)| (k, v.into())).collect()),
+            Err(e) => Err(py_err_from_sl_eval_error(py, e)),
+        }
+    }
+
+    /// Forgets all modules loaded so far, so they are loaded afresh.
+    fn clear_cache(&self) {
+        self.cache.lock().unwrap().modules.clear();
+    }
+}
+
+impl PyFilesystemLoader {
+    /// Returns the module at `path` from the cache, or else evaluates it with
+    /// `eval` and caches it, waiting for the module to be evaluated if another
+    /// thread is evaluating it already.
+    fn load_once(
+        &self,
+        label: &str,
+        path: &Path,
+        eval: impl FnOnce() -> starlark::Result<FrozenModule>,
+    ) -> starlark::Result<FrozenModule> {
+        let thread = thread::current().id();
+        let mut cache = self.cache.lock().unwrap();
+        loop {
+            if let Some(x) = cache.modules.get(path) {
+                return Ok(x.clone());
+            }
+            if !cache.evaluating.contains_key(path) {
+                break;
+            }
+            if let Some(mut cycle) = cache.deadlock(path, thread) {
+                cycle.push(path.to_owned());
+                let cycle = cycle.iter().map(|x| x.display().to_string()).collect();
+                return Err(LoadFailure::new_cycle(label, cycle).into());
+            }
+            cache.waiting.insert(thread, path.to_owned());
+            cache = self.evaluated.wait(cache).unwrap();
+            cache.waiting.remove(&thread);
+        }
+        cache.evaluating.insert(path.to_owned(), thread);
+        drop(cache);
+
+        struct Evaluating<'a>(&'a PyFilesystemLoader, &'a Path);
+
+        impl Drop for Evaluating<'_> {
+            fn drop(&mut self) {
+                self.0.cache.lock().unwrap().evaluating.remove(self.1);
+                self.0.evaluated.notify_all();
+            }
+        }
+
+        let _evaluating = Evaluating(self, path);
+        let module = eval()?;
+        self.cache
+            .lock()
+            .unwrap()
+            .modules
+            .insert(path.to_owned(), module.clone());
+        Ok(module)
+    }
+}
+
+impl FileLoader for PyFilesystemLoader {
+    fn load(&self, path: &str) -> starlark::Result<FrozenModule> {
+        LoadContext {
+            loader: self,
+            chain: Vec::new(),
+        }
+        .load(path)
+    }
+}
+
+/// The modules reachable from a set of modules, indexed in the order they are
+/// found.
+struct ModuleGraph {
+    paths: Vec<PathBuf>,
+    /// The parsed modules, with `None` for the modules already loaded.
+    asts: Vec<Option<AstModule>>,
+    /// The modules already loaded.
+    modules: Vec<Option<FrozenModule>>,
+    /// The modules loaded by each module, with the `load()` statements
+    /// loading them.
+    deps: Vec<Vec<(usize, FileSpan)>>,
+    /// The module each module was first found to be loaded by, with the
+    /// `load()` statement loading it, or `None` for the modules asked for.
+    parents: Vec<Option<(usize, FileSpan)>>,
+}
+
+/// The progress of evaluating a `ModuleGraph`, shared by the worker threads.
+struct Schedule {
+    asts: Vec<Option<AstModule>>,
+    modules: Vec<Option<FrozenModule>>,
+    /// The number of modules each module is waiting on to be evaluated.
+    waiting: Vec<usize>,
+    /// The modules ready to be evaluated.
+    ready: Vec<usize>,
+    running: usize,
+    error: Option<starlark::Error>,
+}
+
+impl ModuleGraph {
+    fn new(loader: &PyFilesystemLoader, labels: &[String]) -> starlark::Result<Self> {
+        let mut graph = Self {
+            paths: Vec::new(),
+            asts: Vec::new(),
+            modules: Vec::new(),
+            deps: Vec::new(),
+            parents: Vec::new(),
+        };
+        let mut index = HashMap::new();
+        let top = LoadContext {
+            loader,
+            chain: Vec::new(),
+        };
+        for label in labels {
+            graph.add(&mut index, top.resolve(label)?, None);
+        }
+
+        let mut next = 0;
+        while next < graph.paths.len() {
+            let path = graph.paths[next].clone();
+            let ast = AstModule::parse_file(&path, &loader.dialect).map_err(|e| {
+                let e = LoadFailure::wrap(&path.to_string_lossy(), e, true);
+                LoadFailure::with_load_spans(e, graph.load_spans(next))
+            })?;
+            let ctx = LoadContext {
+                loader,
+                chain: vec![path.clone()],
+            };
+            let mut deps: Vec<(usize, FileSpan)> = Vec::new();
+            for load in ast.loads() {
+                let dep = ctx.resolve(load.module_id).map_err(|e| {
+                    let mut spans = graph.load_spans(next);
+                    spans.push(load.span.clone());
+                    LoadFailure::with_load_spans(e, spans)
+                })?;
+                let i = graph.add(&mut index, dep, Some((next, load.span.clone())));
+                if !deps.iter().any(|&(j, _)| j == i) {
+                    deps.push((i, load.span));
+                }
+            }
+            graph.deps[next] = deps;
+            graph.modules[next] = loader.cache.lock().unwrap().modules.get(&path).cloned();
+            if graph.modules[next].is_none() {
+                graph.asts[next] = Some(ast);
+            }
+            next += 1;
+        }
+        Ok(graph)
+    }
+
+    fn add(
+        &mut self,
+        index: &mut HashMap<PathBuf, usize>,
+        path: PathBuf,
+        parent: Option<(usize, FileSpan)>,
+    ) -> usize {
+        *index.entry(path).or_insert_with_key(|path| {
+            self.paths.push(path.clone());
+            self.asts.push(None);
+            self.modules.push(None);
+            self.deps.push(Vec::new());
+            self.parents.push(parent);
+            self.paths.len() - 1
+        })
+    }
+
+    /// Returns the spans of the `load()` statements the module `i` was first
+    /// found through, outermost first.
+    fn load_spans(&self, mut i: usize) -> Vec<FileSpan> {
+        let mut spans = Vec::new();
+        while let Some((parent, span)) = &self.parents[i] {
+            spans.push(span.clone());
+            i = *parent;
+        }
+        spans.reverse();
+        spans
+    }
+
+    /// Returns the error for a load cycle among the modules left `stuck`
+    /// after evaluating everything else.
+    fn cycle_error(&self, stuck: &[bool]) -> starlark::Error {
+        // every stuck module waits on another stuck module, so following
+        // them eventually comes back to one of them
+        let mut path = vec![stuck.iter().position(|&x| x).unwrap()];
+        let mut spans = Vec::new();
+        loop {
+            let (j, span) = self.deps[*path.last().unwrap()]
+                .iter()
+                .find(|&&(j, _)| stuck[j])
+                .unwrap();
+            spans.push(span.clone());
+            if let Some(k) = path.iter().position(|x| x == j) {
+                let cycle = path[k..]
+                    .iter()
+                    .chain([j])
+                    .map(|&x| self.paths[x].display().to_string())
+                    .collect();
+                let mut load_spans = self.load_spans(path[k]);
+                load_spans.extend(spans.drain(k..));
+                let e = LoadFailure::new_cycle(&self.paths[*j].to_string_lossy(), cycle);
+                return LoadFailure::with_load_spans(e.into(), load_spans);
+            }
+            path.push(*j);
+        }
+    }
+
+    /// Evaluates the modules not loaded yet, with the limits of the
+    /// evaluation running on this thread if any.
+    fn eval(
+        mut self,
+        loader: &PyFilesystemLoader,
+        max_workers: usize,
+    ) -> starlark::Result<Vec<(PathBuf, FrozenModule)>> {
+        let limits = current_limits();
+        let mut dependents = vec![Vec::new(); self.paths.len()];
+        for (i, deps) in self.deps.iter().enumerate() {
+            for &(j, _) in deps {
+                dependents[j].push(i);
+            }
+        }
+        let waiting: Vec<_> = self
+            .deps
+            .iter()
+            .map(|deps| deps.iter().filter(|(j, _)| self.asts[*j].is_some()).count())
+            .collect();
+        let ready = (0..self.paths.len())
+            .filter(|&i| self.asts[i].is_some() && waiting[i] == 0)
+            .collect();
+        let workers = max_workers.min(self.asts.iter().flatten().count());
+        let schedule = Mutex::new(Schedule {
+            asts: mem::take(&mut self.asts),
+            modules: mem::take(&mut self.modules),
+            waiting,
+            ready,
+            running: 0,
+            error: None,
+        });
+        let changed = Condvar::new();
+
+        let work = || {
+            let _scope = enter_limits(limits.clone());
+            let mut state = schedule.lock().unwrap();
+            loop {
+                if state.error.is_some() {
+                    return;
+                }
+                let Some(i) = state.ready.pop() else {
+                    if state.running == 0 {
+                        // either everything is evaluated, or the rest is
+                        // stuck on a cycle
+                        return;
+                    }
+                    state = changed.wait(state).unwrap();
+                    continue;
+                };
+
+                let ast = state.asts[i].take().unwrap();
+                state.running += 1;
+                drop(state);
+                let path = &self.paths[i];
+                let label = path.to_string_lossy();
+                let result = loader
+                    .load_once(&label, path, || {
+                        LoadContext {
+                            loader,
+                            chain: vec![path.clone()],
+                        }
+                        .eval(ast)
+                    })
+                    .map_err(|e| {
+                        let e = LoadFailure::wrap(&label, e, false);
+                        LoadFailure::with_load_spans(e, self.load_spans(i))
+                    });
+                state = schedule.lock().unwrap();
+                state.running -= 1;
+
+                match result {
+                    Ok(module) => {
+                        state.modules[i] = Some(module);
+                        for &j in &dependents[i] {
+                            state.waiting[j] -= 1;
+                            if state.waiting[j] == 0 && state.asts[j].is_some() {
+                                state.ready.push(j);
+                            }
+                        }
+                    }
+                    Err(e) => state.error = Some(e),
+                }
+                changed.notify_all();
+            }
+        };
+        thread::scope(|s| {
+            for _ in 0..workers {
+                s.spawn(work);
+            }
+        });
+
+        let state = schedule.into_inner().unwrap();
+        if let Some(e) = state.error {
+            return Err(e);
+        }
+        let stuck: Vec<_> = state.asts.iter().map(Option::is_some).collect();
+        if stuck.contains(&true) {
+            return Err(self.cycle_error(&stuck));
+        }
+        Ok(self
+            .paths
+            .into_iter()
+            .zip(state.modules)
+            .map(|(path, module)| (path, module.unwrap()))
+            .collect())
+    }
+}
+
+/// Rejects the paths of labels that could escape the roots, i.e. absolute
+/// paths and those going up with `..`.
+fn check_relative(label: &str, path: &Path) -> starlark::Result<()> {
+    if path
+        .components()
+        .all(|x| matches!(x, Component::Normal(_) | Component::CurDir))
+    {
+        Ok(())
+    } else {
+        Err(LoadFailure::new(
+            label,
+            format!(
+                "cannot load `{}`: only relative paths without `..` are allowed",
+                label
+            ),
+        )
+        .into())
+    }
+}
+
+/// The file loader in effect while evaluating a module loaded by a
+/// `PyFilesystemLoader`.
+struct LoadContext<'a> {
+    loader: &'a PyFilesystemLoader,
+    /// The modules being loaded, outermost first.
+    chain: Vec<PathBuf>,
+}
+
+impl LoadContext<'_> {
+    fn resolve(&self, label: &str) -> starlark::Result<PathBuf> {
+        let path = if let Some(rest) = label.strip_prefix("//") {
+            let (package, name) = rest.split_once(':').unwrap_or(("", rest));
+            let path = Path::new(package).join(name);
+            check_relative(label, &path)?;
+            self.loader
+                .roots
+                .iter()
+                .map(|root| root.join(&path))
+                .find(|x| x.is_file())
+                .ok_or_else(|| {
+                    LoadFailure::new(
+                        label,
+                        format!("cannot find `{}` in any of the roots", label),
+                    )
+                })?
+        } else {
+            let base = match self.chain.last() {
+                Some(x) => x.parent().unwrap_or(Path::new("")),
+                None => self.loader.roots.first().ok_or_else(|| {
+                    LoadFailure::new(
+                        label,
+                        format!("cannot resolve `{}` without any roots", label),
+                    )
+                })?,
+            };
+            let path = Path::new(label.strip_prefix(':').unwrap_or(label));
+            check_relative(label, path)?;
+            base.join(path)
+        };
+        let path = path
+            .canonicalize()
+            .map_err(|e| LoadFailure::new(label, format!("cannot load `{}`: {}", label, e)))?;
+        // symlinks may still lead elsewhere
+        let inside = self
+            .loader
+            .roots
+            .iter()
+            .filter_map(|root| root.canonicalize().ok())
+            .any(|root| path.starts_with(root));
+        if !inside {
+            return Err(LoadFailure::new(
+                label,
+                format!("cannot load `{}`: it is outside of the roots", label),
+            )
+            .into());
+        }
+        Ok(path)
+    }
+
+    fn load(&self, label: &str) -> starlark::Result<FrozenModule> {
+        let path = self.resolve(label)?;
+
+        if let Some(i) = self.chain.iter().position(|x| *x == path) {
+            let cycle = self.chain[i..]
+                .iter()
+                .chain([&path])
+                .map(|x| x.display().to_string())
+                .collect();
+            return Err(LoadFailure::new_cycle(label, cycle).into());
+        }
+
+        self.loader.load_once(label, &path, || {
+            let ast = AstModule::parse_file(&path, &self.loader.dialect)
+                .map_err(|e| LoadFailure::wrap(label, e, true))?;
+            let mut chain = self.chain.clone();
+            chain.push(path.clone());
+            LoadContext {
+                loader: self.loader,
+                chain,
+            }
+            .eval(ast)
+            .map_err(|e| LoadFailure::wrap(label, e, false))
+        })
+    }
+
+    fn eval(&self, ast: AstModule) -> starlark::Result<FrozenModule> {
+        // evaluate in a module known to Python, as values may be passed to
+        // Python during the evaluation; the GIL is only taken when that
+        // happens, so modules can be evaluated in parallel
+        let (module, inner, owner, lease) = Python::with_gil(|py| -> PyResult<_> {
+            let module = Py::new(py, PyModule::from(Module::new()))?;
+            let inner = module.borrow(py).inner()? as *const Module;
+            let owner = HeapOwner::from(module.clone_ref(py));
+            let lease = PyModule::lease(module.bind(py))?;
+            Ok((module, inner, owner, lease))
+        })
+        .map_err(starlark::Error::new_other)?;
+        // Safety: the module cannot be consumed while leased, so it stays in
+        // place until it is taken below
+        let inner = unsafe { &*inner };
+
+        // loaded modules are subject to the limits of the loading evaluation
+        let limits = current_limits();
+        let mut eval = Evaluator::new(inner);
+        eval.set_loader(self);
+        if limits.is_some() {
+            install_limits(&mut eval);
+        }
+        let scope = enter_limits(limits);
+        let result = with_heap_owner(owner, || {
+            let result = eval.eval_module(ast, &self.loader.globals);
+            end_evaluation(inner.heap(), result)
+        });
+        drop(scope);
+        drop(eval);
+        drop(lease);
+        result.map_err(|e| match LoadFailure::from_sl_error(&e) {
+            // keep the failure recognizable to the outer modules
+            Some(x) => x.into(),
+            None => e,
+        })?;
+        let module = Python::with_gil(|py| module.borrow_mut(py).take_inner())
+            .map_err(starlark::Error::new_other)?;
+        Ok(module.freeze()?)
+    }
+}
+
+impl FileLoader for LoadContext<'_> {
+    fn load(&self, path: &str) -> starlark::Result<FrozenModule> {
+        LoadContext::load(self, path)
+    }
+}
@@ -1,14 +1,30 @@
-use pyo3::exceptions::PyValueError;
+use std::ptr;
+use std::sync::OnceLock;
+
+use pyo3::exceptions::{PyTypeError, PyValueError};
 use pyo3::prelude::*;
-use pyo3::types::{PyDict, PyList, PyTuple};
+use pyo3::types::{PyByteArray, PyBytes, PyDict, PyFrozenSet, PyList, PySet, PyTuple};
+use starlark::environment::{Globals, LibraryExtension, Module};
+use starlark::eval::Evaluator;
 use starlark::values::dict::AllocDict;
 use starlark::values::list::AllocList;
 use starlark::values::tuple::AllocTuple;
-use starlark::values::{FrozenHeap, FrozenValue, Heap, Value};
+use starlark::values::{FrozenHeap, FrozenHeapRef, FrozenValue, Heap, Value};
 
+mod scratch;
+mod slpyfunction;
+mod slpyiterator;
 mod slpyobject;
+mod structs;
+use scratch::{with_new_scratch, with_scratch_of};
+pub(crate) use slpyfunction::SlPyFunction;
 pub(crate) use slpyobject::SlPyObject;
+use structs::{sl_frozen_struct_from_py, sl_struct_from_py};
 
+use crate::converters::to_starlark_via_converter;
+use crate::errors::py_err_from_sl_eval_error;
+use crate::eval::{self, heap_owner_of};
+use crate::sl2py::PySlNativeFunction;
 use crate::values::{PyFrozenValue, PyValue};
 
 pub(crate) fn sl_frozen_value_from_py(
@@ -17,6 +33,11 @@ pub(crate) fn sl_frozen_value_from_py(
 ) -> PyResult<FrozenValue> {
     if value.is_none() {
         Ok(FrozenValue::new_none())
+    } else if let Some(x) = to_starlark_via_converter(value, |x| sl_frozen_value_from_py(x, heap))?
+    {
+        Ok(x)
+    } else if let Some(x) = sl_frozen_struct_from_py(value, heap)? {
+        Ok(x)
     } else if let Ok(x) = value.extract::<bool>() {
         Ok(FrozenValue::new_bool(x))
     } else if let Ok(x) = value.extract::<i64>() {
@@ -59,8 +80,28 @@ pub(crate) fn sl_frozen_value_from_py(
             tmp
         };
         Ok(heap.alloc(AllocDict(entries)))
+    } else if is_set(value) {
+        let set = with_new_scratch(|scratch| {
+            let elements = {
+                let mut tmp = Vec::new();
+                for elem in set_elements(value)?.into_iter() {
+                    tmp.push(sl_frozen_value_from_py(&elem, scratch.frozen_heap())?.to_value());
+                }
+                tmp
+            };
+            scratch.set_result(make_set(value.py(), scratch.heap(), elements)?);
+            Ok(())
+        })?;
+        // Safety: the heap is made to keep the value alive
+        Ok(unsafe { set.owned_frozen_value(heap) })
+    } else if is_bytes(value) {
+        Err(bytes_error(value))
     } else if let Ok(x) = value.downcast::<PyFrozenValue>() {
-        Ok(x.borrow().0)
+        // Safety: the heap is made to keep the value alive
+        Ok(unsafe { x.borrow().0.owned_frozen_value(heap) })
+    } else if let Ok(x) = value.downcast::<PySlNativeFunction>() {
+        // Safety: same as above
+        Ok(unsafe { x.get().0.owned_frozen_value(heap) })
     } else if let Ok(_) = value.downcast::<PyValue>() {
         // disallow this
         Err(PyValueError::new_err(
@@ -71,39 +112,135 @@ pub(crate) fn sl_frozen_value_from_py(
     }
 }
 
-pub(crate) fn sl_value_from_py<'v>(value: &Bound<'_, PyAny>, heap: &'v Heap) -> Value<'v> {
+pub(crate) fn sl_value_from_py<'v>(
+    value: &Bound<'_, PyAny>,
+    heap: &'v Heap,
+) -> PyResult<Value<'v>> {
     if value.is_none() {
-        Value::new_none()
+        Ok(Value::new_none())
+    } else if let Some(x) = to_starlark_via_converter(value, |x| sl_value_from_py(x, heap))? {
+        Ok(x)
+    } else if let Some(x) = sl_struct_from_py(value, heap)? {
+        Ok(x)
     } else if let Ok(x) = value.extract::<bool>() {
-        Value::new_bool(x)
+        Ok(Value::new_bool(x))
     } else if let Ok(x) = value.extract::<i64>() {
-        heap.alloc(x)
+        Ok(heap.alloc(x))
     } else if let Ok(x) = value.extract::<u64>() {
-        heap.alloc(x)
+        Ok(heap.alloc(x))
     } else if let Ok(x) = value.extract::<num_bigint::BigInt>() {
-        heap.alloc(x)
+        Ok(heap.alloc(x))
     } else if let Ok(x) = value.extract::<f64>() {
-        heap.alloc(x)
+        Ok(heap.alloc(x))
     } else if let Ok(x) = value.extract::<String>() {
-        heap.alloc(x)
+        Ok(heap.alloc(x))
     } else if let Ok(x) = value.downcast::<PyTuple>() {
-        let entries = x.iter_borrowed().map(|elem| sl_value_from_py(&elem, heap));
-        heap.alloc(AllocTuple(entries))
+        let entries = x
+            .iter_borrowed()
+            .map(|elem| sl_value_from_py(&elem, heap))
+            .collect::<PyResult<Vec<_>>>()?;
+        Ok(heap.alloc(AllocTuple(entries)))
     } else if let Ok(x) = value.downcast::<PyList>() {
-        let entries = x.into_iter().map(|elem| sl_value_from_py(&elem, heap));
-        heap.alloc(AllocList(entries))
+        let entries = x
+            .into_iter()
+            .map(|elem| sl_value_from_py(&elem, heap))
+            .collect::<PyResult<Vec<_>>>()?;
+        Ok(heap.alloc(AllocList(entries)))
     } else if let Ok(x) = value.downcast::<PyDict>() {
         let entries = x
             .into_iter()
-            .map(|(k, v)| (sl_value_from_py(&k, heap), sl_value_from_py(&v, heap)));
-        heap.alloc(AllocDict(entries))
+            .map(|(k, v)| Ok((sl_value_from_py(&k, heap)?, sl_value_from_py(&v, heap)?)))
+            .collect::<PyResult<Vec<_>>>()?;
+        Ok(heap.alloc(AllocDict(entries)))
+    } else if is_set(value) {
+        let elements = set_elements(value)?
+            .into_iter()
+            .map(|elem| sl_value_from_py(&elem, heap))
+            .collect::<PyResult<Vec<_>>>()?;
+        make_set(value.py(), heap, elements)
+    } else if is_bytes(value) {
+        Err(bytes_error(value))
     } else if let Ok(x) = value.downcast::<PyFrozenValue>() {
-        x.borrow().0.to_value()
+        let x = x.borrow();
+        keep_frozen_heap_alive(value.py(), heap, x.0.owner())?;
+        Ok(x.frozen_value().to_value())
+    } else if let Ok(x) = value.downcast::<PySlNativeFunction>() {
+        let x = x.get();
+        keep_frozen_heap_alive(value.py(), heap, x.0.owner())?;
+        Ok(x.frozen_value().to_value())
     } else if let Ok(x) = value.downcast::<PyValue>() {
-        // XXX: This is going to cause problems when value is shared cross-heap,
-        // so more design is needed to correctly track each value's belonging heap.
-        unsafe { ::core::mem::transmute(x.borrow().0) }
+        x.borrow().to_heap(value.py(), heap)
     } else {
-        heap.alloc(SlPyObject::from(value.clone().unbind()))
+        Ok(heap.alloc(SlPyObject::from(value.clone().unbind())))
+    }
+}
+
+/// Makes `heap` keep `frozen` alive, `heap` being the heap of a module known
+/// to Python or of the scratch module of the conversion in progress.
+fn keep_frozen_heap_alive(py: Python<'_>, heap: &Heap, frozen: &FrozenHeapRef) -> PyResult<()> {
+    with_scratch_of(heap, |scratch| {
+        // the frozen module keeps the heaps referenced by its frozen heap
+        scratch.frozen_heap().add_reference(frozen);
+        Ok(())
+    })
+    .unwrap_or_else(|| eval::keep_frozen_heap_alive(py, heap, frozen))
+}
+
+fn is_set(value: &Bound<'_, PyAny>) -> bool {
+    value.is_instance_of::<PySet>() || value.is_instance_of::<PyFrozenSet>()
+}
+
+/// Returns the elements of the set `value`, in the order they are to be
+/// added to the Starlark set, whose order is the insertion order.
+///
+/// The elements are sorted if they can be, so that their order does not vary
+/// across runs as the order of Python sets of strings does.
+fn set_elements<'py>(value: &Bound<'py, PyAny>) -> PyResult<Bound<'py, PyList>> {
+    let py = value.py();
+    let elements = PyList::new_bound(py, value.iter()?.collect::<PyResult<Vec<_>>>()?);
+    match elements.sort() {
+        // elements of different types may not be comparable, in which case
+        // their order is as arbitrary as in Python
+        Err(e) if !e.is_instance_of::<PyTypeError>(py) => Err(e),
+        _ => Ok(elements),
     }
 }
+
+/// Makes a Starlark set of `elements` on `heap`, with the `set` builtin, sets
+/// having no other public constructor. `heap` is the heap of the scratch
+/// module of the conversion in progress, or of a module known to Python.
+fn make_set<'v>(py: Python<'_>, heap: &'v Heap, elements: Vec<Value<'v>>) -> PyResult<Value<'v>> {
+    static GLOBALS: OnceLock<Globals> = OnceLock::new();
+    let globals = GLOBALS.get_or_init(|| Globals::extended_by(&[LibraryExtension::SetType]));
+    let set = globals
+        .iter()
+        .find_map(|(name, value)| (name == "set").then_some(value))
+        .expect("the set type extension defines `set`");
+    let elements = heap.alloc(AllocList(elements));
+    let call = |module: &Module| {
+        assert!(ptr::eq(module.heap(), heap));
+        // Safety: `heap` is part of `module`, which therefore lives at least
+        // as long as the values on `heap` do
+        let module: &'v Module = unsafe { &*(module as *const Module) };
+        Evaluator::new(module)
+            .eval_function(set.to_value(), &[elements], &[])
+            .map_err(|e| py_err_from_sl_eval_error(py, e))
+    };
+    with_scratch_of(heap, |scratch| call(scratch.module()))
+        .unwrap_or_else(|| heap_owner_of(py, heap)?.with_module(py, call))
+}
+
+fn is_bytes(value: &Bound<'_, PyAny>) -> bool {
+    value.is_instance_of::<PyBytes>() || value.is_instance_of::<PyByteArray>()
+}
+
+fn bytes_error(value: &Bound<'_, PyAny>) -> PyErr {
+    let type_name = value
+        .get_type()
+        .qualname()
+        .map_or_else(|_| "bytes".to_owned(), |x| x.to_string());
+    PyTypeError::new_err(format!(
+        "{} cannot be converted to Starlark, which has no bytes type; decode it to str first",
+        type_name
+    ))
+}
@@ -0,0 +1,125 @@
+use std::cell::{Cell, RefCell};
+use std::ptr;
+
+use pyo3::exceptions::PyRuntimeError;
+use pyo3::prelude::*;
+use starlark::environment::Module;
+use starlark::eval::Evaluator;
+use starlark::values::{FrozenHeap, Heap, OwnedFrozenValue, Value};
+
+use crate::errors::py_err_from_sl_eval_error;
+
+thread_local! {
+    /// The scratch module of the conversion in progress on this thread, if
+    /// any.
+    static CURRENT_SCRATCH: Cell<*const Scratch<'static>> = const { Cell::new(ptr::null()) };
+}
+
+/// The name the result of `with_new_scratch` is stored under.
+const RESULT: &str = "result";
+
+/// A module for calling Starlark functions while converting values from
+/// Python, as some Starlark values, e.g. sets and records, can only be made by
+/// evaluators.
+///
+/// The nested conversions share the module of the outermost one, so that
+/// converting a value takes a single module and evaluator however many such
+/// values it contains.
+pub(super) struct Scratch<'v> {
+    module: &'v Module,
+    eval: RefCell<Evaluator<'v, 'v, 'v>>,
+}
+
+impl<'v> Scratch<'v> {
+    pub(super) fn module(&self) -> &'v Module {
+        self.module
+    }
+
+    pub(super) fn heap(&self) -> &'v Heap {
+        self.module.heap()
+    }
+
+    pub(super) fn frozen_heap(&self) -> &'v FrozenHeap {
+        self.module.frozen_heap()
+    }
+
+    /// Whether `heap` is the heap of this module, on which values can be made
+    /// directly.
+    pub(super) fn owns(&self, heap: &Heap) -> bool {
+        ptr::eq(self.heap(), heap)
+    }
+
+    pub(super) fn call(
+        &self,
+        py: Python<'_>,
+        function: Value<'v>,
+        positional: &[Value<'v>],
+        named: &[(&str, Value<'v>)],
+    ) -> PyResult<Value<'v>> {
+        let mut eval = self.eval.try_borrow_mut().map_err(|_| {
+            PyRuntimeError::new_err("cannot convert values while a conversion calls Starlark")
+        })?;
+        eval.eval_function(function, positional, named)
+            .map_err(|e| py_err_from_sl_eval_error(py, e))
+    }
+
+    /// Records `value` as the value to be returned by `with_new_scratch`.
+    pub(super) fn set_result(&self, value: Value<'v>) {
+        self.module.set(RESULT, value);
+    }
+}
+
+/// Runs `f` with the scratch module of the conversion in progress if `heap`
+/// is its heap, returning `None` otherwise.
+pub(super) fn with_scratch_of<R>(
+    heap: &Heap,
+    f: impl for<'v> FnOnce(&Scratch<'v>) -> PyResult<R>,
+) -> Option<PyResult<R>> {
+    let current = CURRENT_SCRATCH.with(Cell::get);
+    // Safety: the scratch module is alive until the conversion that made it
+    // returns, which is further up the stack
+    let current = unsafe { current.as_ref() }?;
+    current.owns(heap).then(|| f(current))
+}
+
+/// Runs `f` with a fresh scratch module, shared by the conversions nested in
+/// `f`, and returns the value `f` leaves in it with `set_result`, frozen.
+pub(super) fn with_new_scratch(
+    f: impl for<'v> FnOnce(&Scratch<'v>) -> PyResult<()>,
+) -> PyResult<OwnedFrozenValue> {
+    let module = Module::new();
+    enter_scratch(&module, f)?;
+    let module = module.freeze().map_err(anyhow::Error::from)?;
+    Ok(module.get(RESULT)?)
+}
+
+fn enter_scratch<'v, R>(
+    module: &'v Module,
+    f: impl FnOnce(&Scratch<'v>) -> PyResult<R>,
+) -> PyResult<R> {
+    struct Restore(*const Scratch<'static>);
+
+    impl Drop for Restore {
+        fn drop(&mut self) {
+            CURRENT_SCRATCH.with(|x| x.set(self.0));
+        }
+    }
+
+    let scratch = Scratch {
+        module,
+        eval: RefCell::new(Evaluator::new(module)),
+    };
+    let ptr = (&scratch as *const Scratch<'v>).cast::<Scratch<'static>>();
+    let _restore = Restore(CURRENT_SCRATCH.with(|x| x.replace(ptr)));
+    f(&scratch)
+}
+
+/// Reinterprets `value` as living on a heap of another lifetime.
+///
+/// # Safety
+///
+/// `value` has to live on a heap with the target lifetime, or be used only
+/// while its own heap is alive.
+pub(super) unsafe fn rebind<'a, 'b>(value: Value<'a>) -> Value<'b> {
+    std::mem::transmute(value)
+}
@@ -0,0 +1,298 @@
+use allocative::Allocative;
+use pyo3::exceptions::PyValueError;
+use pyo3::prelude::*;
+use pyo3::types::{PyDict, PyTuple};
+use starlark::any::ProvidesStaticType;
+use starlark::docs::{DocFunction, DocItem, DocMember, DocParam, DocParams, DocStringKind};
+use starlark::eval::{Arguments, Evaluator, ParametersSpec, ParametersSpecParam};
+use starlark::typing::{ParamIsRequired, ParamSpec, Ty};
+use starlark::util::ArcStr;
+use starlark::values::dict::DictRef;
+use starlark::values::function::FUNCTION_TYPE;
+use starlark::values::tuple::TupleRef;
+use starlark::values::{
+    starlark_value, AllocFrozenValue, FrozenHeap, FrozenValue, NoSerialize, StarlarkValue, Value,
+};
+
+use super::slpyobject::sl_value_err_from_py_call;
+use crate::eval::with_current_evaluator;
+use crate::py2sl::sl_value_from_py;
+use crate::sl2py::py_from_sl_value;
+use crate::typing::ty_from_py_annotation;
+
+/// Mirrors `inspect.Parameter.kind`.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum ParamKind {
+    PositionalOnly,
+    PositionalOrKeyword,
+    VarPositional,
+    KeywordOnly,
+    VarKeyword,
+}
+
+impl TryFrom<u8> for ParamKind {
+    type Error = PyErr;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(Self::PositionalOnly),
+            1 => Ok(Self::PositionalOrKeyword),
+            2 => Ok(Self::VarPositional),
+            3 => Ok(Self::KeywordOnly),
+            4 => Ok(Self::VarKeyword),
+            _ => Err(PyValueError::new_err(format!(
+                "unknown parameter kind {}",
+                value
+            ))),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Param {
+    name: String,
+    kind: ParamKind,
+}
+
+/// A Python callable exposed as a Starlark function, with a proper signature
+/// so that Starlark does the argument binding.
+#[derive(ProvidesStaticType, NoSerialize, Allocative)]
+pub(crate) struct SlPyFunction {
+    #[allocative(skip)]
+    func: PyObject,
+    name: String,
+    #[allocative(skip)]
+    params: Vec<Param>,
+    spec: ParametersSpec<FrozenValue>,
+    docs: DocFunction,
+    ty: Ty,
+}
+
+impl SlPyFunction {
+    /// Builds a function from `func`, with the given `inspect.Signature` or
+    /// `inspect.signature(func)` if not given, and the given docstring or
+    /// `inspect.getdoc(func)` if not given.
+    pub(crate) fn new(
+        name: &str,
+        func: &Bound<'_, PyAny>,
+        signature: Option<&Bound<'_, PyAny>>,
+        doc: Option<String>,
+    ) -> PyResult<Self> {
+        let py = func.py();
+        let inspect = py.import_bound("inspect")?;
+        let signature = match signature {
+            Some(x) => x.clone(),
+            None => inspect.call_method1("signature", (func,))?,
+        };
+        let doc = match doc {
+            Some(x) => Some(x),
+            None => inspect.call_method1("getdoc", (func,))?.extract()?,
+        };
+        let empty = inspect.getattr("Parameter")?.getattr("empty")?;
+
+        let mut params = Vec::new();
+        let mut spec_params: [Vec<(String, ParametersSpecParam<FrozenValue>)>; 3] =
+            Default::default();
+        let mut doc_params = DocParams::default();
+        for p in signature
+            .getattr("parameters")?
+            .call_method0("values")?
+            .iter()?
+        {
+            let p = p?;
+            let name: String = p.getattr("name")?.extract()?;
+            let kind = ParamKind::try_from(p.getattr("kind")?.extract::<u8>()?)?;
+            let default = p.getattr("default")?;
+            let default = if default.is(&empty) {
+                None
+            } else {
+                Some(default.repr()?.to_string())
+            };
+            let doc_param = DocParam {
+                name: name.clone(),
+                docs: None,
+                typ: ty_from_py_annotation(&p.getattr("annotation")?)?,
+                default_value: default.clone(),
+            };
+
+            // Python applies the defaults by itself, so missing arguments are
+            // simply not passed.
+            let spec_param = match default {
+                Some(_) => ParametersSpecParam::Optional,
+                None => ParametersSpecParam::Required,
+            };
+            match kind {
+                ParamKind::PositionalOnly => {
+                    spec_params[0].push((name.clone(), spec_param));
+                    doc_params.pos_only.push(doc_param);
+                }
+                ParamKind::PositionalOrKeyword => {
+                    spec_params[1].push((name.clone(), spec_param));
+                    doc_params.pos_or_named.push(doc_param);
+                }
+                ParamKind::VarPositional => doc_params.args = Some(doc_param),
+                ParamKind::KeywordOnly => {
+                    spec_params[2].push((name.clone(), spec_param));
+                    doc_params.named_only.push(doc_param);
+                }
+                ParamKind::VarKeyword => doc_params.kwargs = Some(doc_param),
+            }
+            params.push(Param { name, kind });
+        }
+        let [pos_only, pos_or_named, named_only] = &spec_params;
+        let spec = ParametersSpec::new_parts(
+            name,
+            pos_only.iter().map(|(n, p)| (n.as_str(), *p)),
+            pos_or_named.iter().map(|(n, p)| (n.as_str(), *p)),
+            doc_params.args.is_some(),
+            named_only.iter().map(|(n, p)| (n.as_str(), *p)),
+            doc_params.kwargs.is_some(),
+        );
+
+        let return_type = ty_from_py_annotation(&signature.getattr("return_annotation")?)?;
+        let ty = ty_from_doc_params(&doc_params, return_type.clone())?;
+        let docs = DocFunction::from_docstring(
+            DocStringKind::Starlark,
+            doc_params,
+            return_type,
+            doc.as_deref(),
+        );
+
+        Ok(Self {
+            func: func.clone().unbind(),
+            name: name.to_owned(),
+            params,
+            spec,
+            docs,
+            ty,
+        })
+    }
+
+    pub(crate) fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub(crate) fn func(&self) -> &PyObject {
+        &self.func
+    }
+}
+
+/// The type of a function with the given parameters, for the typechecker.
+fn ty_from_doc_params(params: &DocParams, return_type: Ty) -> PyResult<Ty> {
+    let required = |p: &DocParam| match p.default_value {
+        Some(_) => ParamIsRequired::No,
+        None => ParamIsRequired::Yes,
+    };
+    let named = |p: &DocParam| (ArcStr::from(p.name.as_str()), required(p), p.typ.clone());
+    let spec = ParamSpec::new_parts(
+        params.pos_only.iter().map(|p| (required(p), p.typ.clone())),
+        params.pos_or_named.iter().map(named),
+        params.args.as_ref().map(|p| p.typ.clone()),
+        params.named_only.iter().map(named),
+        params.kwargs.as_ref().map(|p| p.typ.clone()),
+    )
+    .map_err(|e| PyValueError::new_err(e.to_string()))?;
+    Ok(Ty::function(spec, return_type))
+}
+
+impl AllocFrozenValue for SlPyFunction {
+    fn alloc_frozen_value(self, heap: &FrozenHeap) -> FrozenValue {
+        heap.alloc_simple(self)
+    }
+}
+
+impl ::core::fmt::Debug for SlPyFunction {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SlPyFunction")
+            .field("func", &self.func)
+            .field("name", &self.name)
+            .field("params", &self.params)
+            .finish()
+    }
+}
+
+impl ::std::fmt::Display for SlPyFunction {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.name)
+    }
+}
+
+#[starlark_value(type = FUNCTION_TYPE)]
+impl<'v> StarlarkValue<'v> for SlPyFunction {
+    type Canonical = Self;
+
+    fn name_for_call_stack(&self, _me: Value<'v>) -> String {
+        self.name.clone()
+    }
+
+    fn typechecker_ty(&self) -> Option<Ty> {
+        Some(self.ty.clone())
+    }
+
+    fn documentation(&self) -> DocItem {
+        DocItem::Member(DocMember::Function(self.docs.clone()))
+    }
+
+    fn invoke(
+        &self,
+        _me: Value<'v>,
+        args: &Arguments<'v, '_>,
+        eval: &mut Evaluator<'v, '_, '_>,
+    ) -> starlark::Result<Value<'v>> {
+        let heap = eval.heap();
+        let mut slots = vec![None; self.spec.len()];
+        self.spec.collect(args, &mut slots, heap)?;
+
+        let result: PyResult<Value<'v>> = with_current_evaluator(eval, || {
+            Python::with_gil(|py| {
+                let mut py_args = Vec::new();
+                let py_kwargs = PyDict::new_bound(py);
+                // once an optional positional argument is missing, the following
+                // ones have to be passed by name
+                let mut positional = true;
+                for (param, slot) in self.params.iter().zip(slots.iter()) {
+                    let v = match *slot {
+                        Some(v) => v,
+                        None => {
+                            positional = false;
+                            continue;
+                        }
+                    };
+                    match param.kind {
+                        ParamKind::PositionalOnly => py_args.push(py_from_sl_value(py, v)?),
+                        ParamKind::PositionalOrKeyword if positional => {
+                            py_args.push(py_from_sl_value(py, v)?)
+                        }
+                        ParamKind::PositionalOrKeyword | ParamKind::KeywordOnly => {
+                            py_kwargs.set_item(&param.name, py_from_sl_value(py, v)?)?
+                        }
+                        ParamKind::VarPositional => {
+                            if let Some(x) = TupleRef::from_value(v) {
+                                for elem in x.iter() {
+                                    py_args.push(py_from_sl_value(py, elem)?);
+                                }
+                            }
+                        }
+                        ParamKind::VarKeyword => {
+                            if let Some(x) = DictRef::from_value(v) {
+                                for (k, v) in x.iter() {
+                                    py_kwargs.set_item(
+                                        py_from_sl_value(py, k)?,
+                                        py_from_sl_value(py, v)?,
+                                    )?;
+                                }
+                            }
+                        }
+                    }
+                }
+
+                self.func
+                    .bind(py)
+                    .call(PyTuple::new_bound(py, py_args), Some(&py_kwargs))
+                    .and_then(|v| sl_value_from_py(&v, heap))
+            })
+        });
+
+        result.map_err(|e| sl_value_err_from_py_call(e, eval))
+    }
+}
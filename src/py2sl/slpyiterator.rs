@@ -0,0 +1,57 @@
+use allocative::Allocative;
+use pyo3::prelude::*;
+use pyo3::types::PyIterator;
+use starlark::any::ProvidesStaticType;
+use starlark::values::{starlark_value, Heap, NoSerialize, StarlarkValue, Value};
+
+use crate::limits::{count_iteration, fail_evaluation};
+use crate::py2sl::sl_value_from_py;
+
+/// The iterator value handed to Starlark when iterating over a `pyobject`,
+/// pulling items from the Python iterator lazily.
+///
+/// starlark-rust provides no way to fail in the middle of an iteration, so an
+/// exception raised by the Python iterator, or while converting an item, ends
+/// the loop instead, and fails the evaluation afterwards. Each item counts as
+/// a step of the evaluation, so that its limits bound infinite iterators.
+#[derive(ProvidesStaticType, NoSerialize, Allocative)]
+pub(crate) struct SlPyIterator(#[allocative(skip)] Py<PyIterator>);
+
+impl SlPyIterator {
+    pub(crate) fn new(iter: Bound<'_, PyIterator>) -> Self {
+        Self(iter.unbind())
+    }
+}
+
+impl ::core::fmt::Debug for SlPyIterator {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        ::core::fmt::Debug::fmt(&self.0, f)
+    }
+}
+
+impl ::std::fmt::Display for SlPyIterator {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        ::std::fmt::Display::fmt(self.0.as_any(), f)
+    }
+}
+
+#[starlark_value(type = "pyiterator")]
+impl<'v> StarlarkValue<'v> for SlPyIterator {
+    unsafe fn iter_next(&self, _index: usize, heap: &'v Heap) -> Option<Value<'v>> {
+        if count_iteration(heap) {
+            return None;
+        }
+        Python::with_gil(|py| {
+            let item = self.0.bind(py).clone().next()?;
+            match item.and_then(|x| sl_value_from_py(&x, heap)) {
+                Ok(x) => Some(x),
+                Err(e) => {
+                    fail_evaluation(e);
+                    None
+                }
+            }
+        })
+    }
+
+    unsafe fn iter_stop(&self) {}
+}
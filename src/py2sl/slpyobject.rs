@@ -2,18 +2,23 @@ use core::cmp::Ordering;
 use std::hash::Hasher;
 
 use allocative::Allocative;
-use pyo3::exceptions::PyRuntimeError;
+use pyo3::exceptions::{PyIndexError, PyKeyError, PyRuntimeError};
 use pyo3::prelude::*;
 use pyo3::types::PyDict;
+use pyo3::types::PySlice;
 use pyo3::types::PyTuple;
 use starlark::any::ProvidesStaticType;
 use starlark::collections::StarlarkHasher;
-use starlark::eval::{Arguments, Evaluator};
+use starlark::eval::{Arguments, Evaluator, ParametersSpec};
+use starlark::values::tuple::TupleRef;
 use starlark::values::{
-    starlark_value, AllocFrozenValue, AllocValue, Freeze, Freezer, FrozenHeap, FrozenValue, Heap,
-    NoSerialize, StarlarkValue, Trace, Value,
+    starlark_value, AllocFrozenValue, AllocValue, Freeze, FreezeResult, Freezer, FrozenHeap,
+    FrozenValue, Heap, NoSerialize, StarlarkValue, Trace, Value, ValueError,
 };
 
+use super::slpyiterator::SlPyIterator;
+use crate::errors::PyErrFromCall;
+use crate::eval::with_current_evaluator;
 use crate::py2sl::sl_value_from_py;
 use crate::sl2py::py_from_sl_value;
 
@@ -53,13 +58,51 @@ impl ::std::fmt::Display for SlPyObject {
 impl Freeze for SlPyObject {
     type Frozen = SlPyObject;
 
-    fn freeze(self, _freezer: &Freezer) -> anyhow::Result<Self::Frozen> {
+    fn freeze(self, _freezer: &Freezer) -> FreezeResult<Self::Frozen> {
         Ok(self)
     }
 }
 
 fn sl_value_err_from_py(e: PyErr) -> starlark::Error {
-    starlark::Error::new(starlark::ErrorKind::Value(e.into()))
+    starlark::Error::new_kind(starlark::ErrorKind::Value(e.into()))
+}
+
+/// Like `sl_value_err_from_py`, but maps `IndexError` and `KeyError` to the
+/// corresponding Starlark errors.
+fn sl_value_err_from_py_subscript(py: Python<'_>, e: PyErr, index: Value) -> starlark::Error {
+    if e.is_instance_of::<PyIndexError>(py) {
+        if let Some(i) = index.unpack_i32() {
+            return ValueError::IndexOutOfBound(i).into();
+        }
+    } else if e.is_instance_of::<PyKeyError>(py) {
+        return ValueError::KeyNotFound(index.to_repr()).into();
+    }
+    sl_value_err_from_py(e)
+}
+
+pub(super) fn sl_value_err_from_py_call(e: PyErr, eval: &Evaluator) -> starlark::Error {
+    let e = PyErrFromCall::new(e, eval.call_stack());
+    starlark::Error::new_kind(starlark::ErrorKind::Value(e.into()))
+}
+
+/// Returns the positional arguments of a call, with `*args` unpacked.
+fn positional_args<'v>(
+    args: &Arguments<'v, '_>,
+    heap: &'v Heap,
+) -> starlark::Result<Vec<Value<'v>>> {
+    let spec = ParametersSpec::<FrozenValue>::new_parts(
+        "pyobject",
+        std::iter::empty(),
+        std::iter::empty(),
+        true,
+        std::iter::empty(),
+        true,
+    );
+    let mut slots = [None, None];
+    spec.collect(args, &mut slots, heap)?;
+    Ok(slots[0]
+        .and_then(TupleRef::from_value)
+        .map_or_else(Vec::new, |x| x.content().to_vec()))
 }
 
 #[starlark_value(type = "pyobject")]
@@ -111,69 +154,129 @@ impl<'v> StarlarkValue<'v> for SlPyObject {
         &self,
         _me: Value<'v>,
         args: &Arguments<'v, '_>,
-        eval: &mut Evaluator<'v, '_>,
+        eval: &mut Evaluator<'v, '_, '_>,
     ) -> starlark::Result<Value<'v>> {
         let heap = eval.heap();
-        let result: PyResult<Value<'v>> = Python::with_gil(|py| {
-            let inner = self.0.bind(py);
-
-            let py_args = {
-                let mut result = Vec::new();
-                match args.positions(heap) {
-                    Ok(sl_args) => {
-                        for sl in sl_args {
-                            result.push(py_from_sl_value(py, sl)?);
+        let result: PyResult<Value<'v>> = with_current_evaluator(eval, || {
+            Python::with_gil(|py| {
+                let inner = self.0.bind(py);
+
+                let py_args = {
+                    let mut result = Vec::new();
+                    match positional_args(args, heap) {
+                        Ok(sl_args) => {
+                            for sl in sl_args {
+                                result.push(py_from_sl_value(py, sl)?);
+                            }
+                        }
+                        Err(e) => {
+                            return Err(PyRuntimeError::new_err(format!(
+                                "failed to unpack Starlark positional args: {}",
+                                e.to_string()
+                            )));
                         }
                     }
-                    Err(e) => {
-                        return Err(PyRuntimeError::new_err(format!(
-                            "failed to unpack Starlark positional args: {}",
-                            e.to_string()
-                        )));
-                    }
-                }
-                PyTuple::new_bound(py, result)
-            };
-
-            let py_kwargs = match args.names_map() {
-                Ok(sl_kwargs) => {
-                    if sl_kwargs.len() == 0 {
-                        None
-                    } else {
-                        let result = PyDict::new_bound(py);
-                        for (k, v) in sl_kwargs {
-                            let k = k.as_str();
-                            match py_from_sl_value(py, v) {
-                                Ok(v) => {
-                                    if let Err(e) = result.set_item(k, v) {
+                    PyTuple::new_bound(py, result)
+                };
+
+                let py_kwargs = match args.names_map() {
+                    Ok(sl_kwargs) => {
+                        if sl_kwargs.len() == 0 {
+                            None
+                        } else {
+                            let result = PyDict::new_bound(py);
+                            for (k, v) in sl_kwargs {
+                                let k = k.as_str();
+                                match py_from_sl_value(py, v) {
+                                    Ok(v) => {
+                                        if let Err(e) = result.set_item(k, v) {
+                                            return Err(e);
+                                        }
+                                    }
+                                    Err(e) => {
                                         return Err(e);
                                     }
                                 }
-                                Err(e) => {
-                                    return Err(e);
-                                }
                             }
+                            Some(result)
                         }
-                        Some(result)
                     }
-                }
 
-                Err(e) => {
-                    return Err(PyRuntimeError::new_err(format!(
-                        "failed to unpack Starlark keyword args: {}",
-                        e.to_string()
-                    )));
-                }
-            };
+                    Err(e) => {
+                        return Err(PyRuntimeError::new_err(format!(
+                            "failed to unpack Starlark keyword args: {}",
+                            e.to_string()
+                        )));
+                    }
+                };
 
+                inner
+                    .call(py_args, py_kwargs.as_ref())
+                    .and_then(|v| sl_value_from_py(&v, heap))
+            })
+        });
+
+        result.map_err(|e| sl_value_err_from_py_call(e, eval))
+    }
+
+    fn at(&self, index: Value<'v>, heap: &'v Heap) -> starlark::Result<Value<'v>> {
+        Python::with_gil(|py| {
+            let inner = self.0.bind(py);
+            let key = py_from_sl_value(py, index).map_err(sl_value_err_from_py)?;
+            match inner.get_item(key) {
+                Ok(result) => sl_value_from_py(&result, heap).map_err(sl_value_err_from_py),
+                Err(e) => Err(sl_value_err_from_py_subscript(py, e, index)),
+            }
+        })
+    }
+
+    fn set_at(&self, index: Value<'v>, new_value: Value<'v>) -> starlark::Result<()> {
+        Python::with_gil(|py| {
+            let inner = self.0.bind(py);
+            let key = py_from_sl_value(py, index).map_err(sl_value_err_from_py)?;
+            let new_value = py_from_sl_value(py, new_value).map_err(sl_value_err_from_py)?;
             inner
-                .call(py_args, py_kwargs.as_ref())
-                .map(|v| sl_value_from_py(&v, heap))
+                .set_item(key, new_value)
+                .map_err(|e| sl_value_err_from_py_subscript(py, e, index))
+        })
+    }
+
+    fn slice(
+        &self,
+        start: Option<Value<'v>>,
+        stop: Option<Value<'v>>,
+        stride: Option<Value<'v>>,
+        heap: &'v Heap,
+    ) -> starlark::Result<Value<'v>> {
+        let result = Python::with_gil(|py| {
+            let inner = self.0.bind(py);
+            let to_py = |x: Option<Value<'v>>| match x {
+                Some(x) => py_from_sl_value(py, x),
+                None => Ok(py.None()),
+            };
+            let slice = py.get_type_bound::<PySlice>().call1((
+                to_py(start)?,
+                to_py(stop)?,
+                to_py(stride)?,
+            ))?;
+            inner
+                .get_item(slice)
+                .and_then(|result| sl_value_from_py(&result, heap))
         });
 
         result.map_err(sl_value_err_from_py)
     }
 
+    unsafe fn iterate(&self, _me: Value<'v>, heap: &'v Heap) -> starlark::Result<Value<'v>> {
+        Python::with_gil(|py| {
+            let inner = self.0.bind(py);
+            match inner.iter() {
+                Ok(iter) => Ok(heap.alloc_simple(SlPyIterator::new(iter))),
+                Err(e) => Err(sl_value_err_from_py(e)),
+            }
+        })
+    }
+
     fn length(&self) -> starlark::Result<i32> {
         Python::with_gil(|py| {
             let inner = self.0.bind(py);
@@ -198,7 +301,7 @@ impl<'v> StarlarkValue<'v> for SlPyObject {
         Python::with_gil(|py| {
             let inner = self.0.bind(py);
             match inner.pos() {
-                Ok(result) => Ok(sl_value_from_py(&result, heap)),
+                Ok(result) => sl_value_from_py(&result, heap).map_err(sl_value_err_from_py),
                 Err(e) => Err(sl_value_err_from_py(e)),
             }
         })
@@ -208,7 +311,7 @@ impl<'v> StarlarkValue<'v> for SlPyObject {
         Python::with_gil(|py| {
             let inner = self.0.bind(py);
             match inner.neg() {
-                Ok(result) => Ok(sl_value_from_py(&result, heap)),
+                Ok(result) => sl_value_from_py(&result, heap).map_err(sl_value_err_from_py),
                 Err(e) => Err(sl_value_err_from_py(e)),
             }
         })
@@ -218,11 +321,10 @@ impl<'v> StarlarkValue<'v> for SlPyObject {
         Python::with_gil(|py| {
             let inner = self.0.bind(py);
             // no way to propagate error with this interface
-            if let Some(v) = inner.getattr(attribute).ok() {
-                Some(sl_value_from_py(&v, heap))
-            } else {
-                None
-            }
+            inner
+                .getattr(attribute)
+                .and_then(|v| sl_value_from_py(&v, heap))
+                .ok()
         })
     }
 
@@ -264,7 +366,7 @@ impl<'v> StarlarkValue<'v> for SlPyObject {
                 Err(e) => return Some(Err(sl_value_err_from_py(e))),
             };
             match inner.add(rhs.bind(py)) {
-                Ok(result) => Some(Ok(sl_value_from_py(&result, heap))),
+                Ok(result) => Some(sl_value_from_py(&result, heap).map_err(sl_value_err_from_py)),
                 Err(e) => Some(Err(sl_value_err_from_py(e))),
             }
         })
@@ -278,7 +380,7 @@ impl<'v> StarlarkValue<'v> for SlPyObject {
                 Err(e) => return Err(sl_value_err_from_py(e)),
             };
             match inner.sub(rhs.bind(py)) {
-                Ok(result) => Ok(sl_value_from_py(&result, heap)),
+                Ok(result) => sl_value_from_py(&result, heap).map_err(sl_value_err_from_py),
                 Err(e) => Err(sl_value_err_from_py(e)),
             }
         })
@@ -292,7 +394,7 @@ impl<'v> StarlarkValue<'v> for SlPyObject {
                 Err(e) => return Some(Err(sl_value_err_from_py(e))),
             };
             match inner.mul(rhs.bind(py)) {
-                Ok(result) => Some(Ok(sl_value_from_py(&result, heap))),
+                Ok(result) => Some(sl_value_from_py(&result, heap).map_err(sl_value_err_from_py)),
                 Err(e) => Some(Err(sl_value_err_from_py(e))),
             }
         })
@@ -306,7 +408,7 @@ impl<'v> StarlarkValue<'v> for SlPyObject {
                 Err(e) => return Err(sl_value_err_from_py(e)),
             };
             match inner.div(rhs.bind(py)) {
-                Ok(result) => Ok(sl_value_from_py(&result, heap)),
+                Ok(result) => sl_value_from_py(&result, heap).map_err(sl_value_err_from_py),
                 Err(e) => Err(sl_value_err_from_py(e)),
             }
         })
@@ -320,7 +422,7 @@ impl<'v> StarlarkValue<'v> for SlPyObject {
                 Err(e) => return Err(sl_value_err_from_py(e)),
             };
             match inner.rem(rhs.bind(py)) {
-                Ok(result) => Ok(sl_value_from_py(&result, heap)),
+                Ok(result) => sl_value_from_py(&result, heap).map_err(sl_value_err_from_py),
                 Err(e) => Err(sl_value_err_from_py(e)),
             }
         })
@@ -334,7 +436,7 @@ impl<'v> StarlarkValue<'v> for SlPyObject {
                 Err(e) => return Err(sl_value_err_from_py(e)),
             };
             match inner.floor_div(rhs.bind(py)) {
-                Ok(result) => Ok(sl_value_from_py(&result, heap)),
+                Ok(result) => sl_value_from_py(&result, heap).map_err(sl_value_err_from_py),
                 Err(e) => Err(sl_value_err_from_py(e)),
             }
         })
@@ -348,7 +450,7 @@ impl<'v> StarlarkValue<'v> for SlPyObject {
                 Err(e) => return Err(sl_value_err_from_py(e)),
             };
             match inner.bitand(rhs.bind(py)) {
-                Ok(result) => Ok(sl_value_from_py(&result, heap)),
+                Ok(result) => sl_value_from_py(&result, heap).map_err(sl_value_err_from_py),
                 Err(e) => Err(sl_value_err_from_py(e)),
             }
         })
@@ -362,7 +464,7 @@ impl<'v> StarlarkValue<'v> for SlPyObject {
                 Err(e) => return Err(sl_value_err_from_py(e)),
             };
             match inner.bitor(rhs.bind(py)) {
-                Ok(result) => Ok(sl_value_from_py(&result, heap)),
+                Ok(result) => sl_value_from_py(&result, heap).map_err(sl_value_err_from_py),
                 Err(e) => Err(sl_value_err_from_py(e)),
             }
         })
@@ -376,7 +478,7 @@ impl<'v> StarlarkValue<'v> for SlPyObject {
                 Err(e) => return Err(sl_value_err_from_py(e)),
             };
             match inner.bitxor(rhs.bind(py)) {
-                Ok(result) => Ok(sl_value_from_py(&result, heap)),
+                Ok(result) => sl_value_from_py(&result, heap).map_err(sl_value_err_from_py),
                 Err(e) => Err(sl_value_err_from_py(e)),
             }
         })
@@ -386,7 +488,7 @@ impl<'v> StarlarkValue<'v> for SlPyObject {
         Python::with_gil(|py| {
             let inner = self.0.bind(py);
             match inner.bitnot() {
-                Ok(result) => Ok(sl_value_from_py(&result, heap)),
+                Ok(result) => sl_value_from_py(&result, heap).map_err(sl_value_err_from_py),
                 Err(e) => Err(sl_value_err_from_py(e)),
             }
         })
@@ -400,7 +502,7 @@ impl<'v> StarlarkValue<'v> for SlPyObject {
                 Err(e) => return Err(sl_value_err_from_py(e)),
             };
             match inner.lshift(rhs.bind(py)) {
-                Ok(result) => Ok(sl_value_from_py(&result, heap)),
+                Ok(result) => sl_value_from_py(&result, heap).map_err(sl_value_err_from_py),
                 Err(e) => Err(sl_value_err_from_py(e)),
             }
         })
@@ -414,7 +516,7 @@ impl<'v> StarlarkValue<'v> for SlPyObject {
                 Err(e) => return Err(sl_value_err_from_py(e)),
             };
             match inner.rshift(rhs.bind(py)) {
-                Ok(result) => Ok(sl_value_from_py(&result, heap)),
+                Ok(result) => sl_value_from_py(&result, heap).map_err(sl_value_err_from_py),
                 Err(e) => Err(sl_value_err_from_py(e)),
             }
         })
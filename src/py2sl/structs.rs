@@ -0,0 +1,161 @@
+use pyo3::exceptions::PyTypeError;
+use pyo3::intern;
+use pyo3::prelude::*;
+use pyo3::types::{PyDict, PyTuple};
+use starlark::values::structs::AllocStruct;
+use starlark::values::{FrozenHeap, FrozenValue, Heap, OwnedFrozenValue, Value};
+
+use super::scratch::{rebind, with_new_scratch, with_scratch_of, Scratch};
+use super::{keep_frozen_heap_alive, sl_frozen_value_from_py, sl_value_from_py};
+use crate::converters::{is_struct_conversion_enabled, sl_type_for, SlTypeKind};
+
+type Fields<'py> = Vec<(String, Bound<'py, PyAny>)>;
+
+/// Returns the fields of `value`, if it is a dataclass, `typing.NamedTuple`,
+/// attrs class or `types.SimpleNamespace` instance.
+fn struct_fields<'py>(value: &Bound<'py, PyAny>) -> PyResult<Option<Fields<'py>>> {
+    let py = value.py();
+    let cls = value.get_type();
+    let names: Vec<String> = if cls.hasattr(intern!(py, "__dataclass_fields__"))? {
+        // unlike `__dataclass_fields__`, this leaves out pseudo-fields
+        let fields = py
+            .import_bound("dataclasses")?
+            .call_method1("fields", (value,))?;
+        fields
+            .iter()?
+            .map(|x| x?.getattr(intern!(py, "name"))?.extract())
+            .collect::<PyResult<_>>()?
+    } else if cls.hasattr(intern!(py, "__attrs_attrs__"))? {
+        cls.getattr(intern!(py, "__attrs_attrs__"))?
+            .iter()?
+            .map(|x| x?.getattr(intern!(py, "name"))?.extract())
+            .collect::<PyResult<_>>()?
+    } else if value.is_instance_of::<PyTuple>() && cls.hasattr(intern!(py, "_fields"))? {
+        cls.getattr(intern!(py, "_fields"))?.extract()?
+    } else if value.is_instance(&py.import_bound("types")?.getattr("SimpleNamespace")?)? {
+        value
+            .getattr(intern!(py, "__dict__"))?
+            .downcast::<PyDict>()?
+            .keys()
+            .extract()?
+    } else {
+        return Ok(None);
+    };
+
+    names
+        .into_iter()
+        .map(|name| {
+            let value = value.getattr(name.as_str())?;
+            Ok((name, value))
+        })
+        .collect::<PyResult<_>>()
+        .map(Some)
+}
+
+/// Makes the Starlark record or enum value of type `sl_type` for `value`, on
+/// the heap of `scratch`, as record and enum types can only be called by
+/// evaluators.
+fn typed_value_from_py<'v>(
+    value: &Bound<'_, PyAny>,
+    kind: SlTypeKind,
+    sl_type: &OwnedFrozenValue,
+    scratch: &Scratch<'v>,
+) -> PyResult<Value<'v>> {
+    let py = value.py();
+    let heap = scratch.heap();
+    let (positional, fields) = match kind {
+        SlTypeKind::Record => {
+            let fields = struct_fields(value)?.ok_or_else(|| {
+                PyTypeError::new_err(format!(
+                    "{} has a record type registered but is not a dataclass, NamedTuple or attrs class",
+                    value.get_type()
+                ))
+            })?;
+            (None, fields)
+        }
+        SlTypeKind::Enum => {
+            let member_value = value.getattr(intern!(py, "value"))?;
+            (Some(member_value), Vec::new())
+        }
+    };
+    // nested records and enum values are made on the same heap
+    let positional = positional.map(|x| sl_value_from_py(&x, heap)).transpose()?;
+    let named = fields
+        .iter()
+        .map(|(k, v)| Ok((k.as_str(), sl_value_from_py(v, heap)?)))
+        .collect::<PyResult<Vec<_>>>()?;
+    let sl_type = sl_type.owned_value(scratch.frozen_heap());
+    scratch.call(py, sl_type, positional.as_slice(), &named)
+}
+
+/// Makes the frozen Starlark record or enum value of type `sl_type` for
+/// `value`, on a scratch module of its own.
+fn frozen_typed_value_from_py(
+    value: &Bound<'_, PyAny>,
+    kind: SlTypeKind,
+    sl_type: &OwnedFrozenValue,
+) -> PyResult<OwnedFrozenValue> {
+    with_new_scratch(|scratch| {
+        scratch.set_result(typed_value_from_py(value, kind, sl_type, scratch)?);
+        Ok(())
+    })
+}
+
+/// Converts `value` to a frozen Starlark record, enum value or struct, if it
+/// is to be.
+pub(super) fn sl_frozen_struct_from_py(
+    value: &Bound<'_, PyAny>,
+    heap: &FrozenHeap,
+) -> PyResult<Option<FrozenValue>> {
+    if let Some((kind, sl_type)) = sl_type_for(value) {
+        let x = frozen_typed_value_from_py(value, kind, &sl_type)?;
+        // Safety: the heap is made to keep the value alive
+        return Ok(Some(unsafe { x.owned_frozen_value(heap) }));
+    }
+    if !is_struct_conversion_enabled(value.py()) {
+        return Ok(None);
+    }
+    let fields = match struct_fields(value)? {
+        Some(x) => x,
+        None => return Ok(None),
+    };
+    let fields = fields
+        .into_iter()
+        .map(|(k, v)| Ok((k, sl_frozen_value_from_py(&v, heap)?)))
+        .collect::<PyResult<Vec<_>>>()?;
+    Ok(Some(heap.alloc(AllocStruct(fields))))
+}
+
+/// Converts `value` to a Starlark record, enum value or struct, if it is to
+/// be.
+pub(super) fn sl_struct_from_py<'v>(
+    value: &Bound<'_, PyAny>,
+    heap: &'v Heap,
+) -> PyResult<Option<Value<'v>>> {
+    if let Some((kind, sl_type)) = sl_type_for(value) {
+        let nested = with_scratch_of(heap, |scratch| {
+            let x = typed_value_from_py(value, kind, &sl_type, scratch)?;
+            // Safety: the value is on the scratch heap, which is `heap`
+            Ok(unsafe { rebind(x) })
+        });
+        if let Some(x) = nested {
+            return x.map(Some);
+        }
+        let x = frozen_typed_value_from_py(value, kind, &sl_type)?;
+        keep_frozen_heap_alive(value.py(), heap, x.owner())?;
+        // Safety: the heap is made to keep the value alive
+        return Ok(Some(unsafe { x.unchecked_frozen_value() }.to_value()));
+    }
+    if !is_struct_conversion_enabled(value.py()) {
+        return Ok(None);
+    }
+    let fields = match struct_fields(value)? {
+        Some(x) => x,
+        None => return Ok(None),
+    };
+    let fields = fields
+        .into_iter()
+        .map(|(k, v)| Ok((k, sl_value_from_py(&v, heap)?)))
+        .collect::<PyResult<Vec<_>>>()?;
+    Ok(Some(heap.alloc(AllocStruct(fields))))
+}
@@ -0,0 +1,132 @@
+use std::cell::RefCell;
+use std::fmt;
+
+use allocative::Allocative;
+use starlark::any::ProvidesStaticType;
+use starlark::environment::{FrozenModule, Module};
+use starlark::values::{
+    starlark_value, Freeze, FreezeResult, Freezer, FrozenValue, NoSerialize, OwnedFrozenValue,
+    StarlarkValue, Trace, Value, ValueLike,
+};
+
+/// The values of a module referenced from Python, stored as the extra value
+/// of the module so that the garbage collector sees them, and updates them
+/// when it moves them. The extra value set by the user is stored alongside.
+#[derive(Trace, ProvidesStaticType, NoSerialize, Allocative, Default)]
+struct Roots<'v> {
+    #[allocative(skip)]
+    extra: RefCell<Option<Value<'v>>>,
+    #[allocative(skip)]
+    values: RefCell<Vec<Option<Value<'v>>>>,
+    #[allocative(skip)]
+    #[trace(static)]
+    free: RefCell<Vec<usize>>,
+}
+
+impl fmt::Debug for Roots<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Roots")
+            .field("extra", &self.extra)
+            .finish_non_exhaustive()
+    }
+}
+
+impl fmt::Display for Roots<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("roots")
+    }
+}
+
+#[starlark_value(type = "roots")]
+impl<'v> StarlarkValue<'v> for Roots<'v> {}
+
+impl<'v> Freeze for Roots<'v> {
+    type Frozen = FrozenRoots;
+
+    /// The values referenced from Python cannot be used once the module is
+    /// frozen, so only the user's extra value is kept.
+    fn freeze(self, freezer: &Freezer) -> FreezeResult<Self::Frozen> {
+        Ok(FrozenRoots {
+            extra: self
+                .extra
+                .into_inner()
+                .map(|x| x.freeze(freezer))
+                .transpose()?,
+        })
+    }
+}
+
+#[derive(ProvidesStaticType, NoSerialize, Allocative, Debug)]
+struct FrozenRoots {
+    #[allocative(skip)]
+    extra: Option<FrozenValue>,
+}
+
+impl fmt::Display for FrozenRoots {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("roots")
+    }
+}
+
+#[starlark_value(type = "roots")]
+impl<'v> StarlarkValue<'v> for FrozenRoots {
+    type Canonical = Self;
+}
+
+fn roots(module: &Module) -> &Roots<'_> {
+    if let Some(x) = module.extra_value().and_then(|x| x.downcast_ref()) {
+        return x;
+    }
+    let roots = module.heap().alloc_complex(Roots::default());
+    module.set_extra_value(roots);
+    roots.downcast_ref().unwrap()
+}
+
+/// Makes `value` survive garbage collections of `module`, until `unroot` is
+/// called with the returned slot.
+pub(crate) fn root<'v>(module: &'v Module, value: Value<'v>) -> usize {
+    let roots = roots(module);
+    let mut values = roots.values.borrow_mut();
+    match roots.free.borrow_mut().pop() {
+        Some(slot) => {
+            values[slot] = Some(value);
+            slot
+        }
+        None => {
+            values.push(Some(value));
+            values.len() - 1
+        }
+    }
+}
+
+/// Returns the value rooted at `slot`, wherever the last garbage collection
+/// moved it.
+pub(crate) fn rooted(module: &Module, slot: usize) -> Value<'_> {
+    roots(module).values.borrow()[slot].expect("the value is rooted")
+}
+
+pub(crate) fn unroot(module: &Module, slot: usize) {
+    let roots = roots(module);
+    roots.values.borrow_mut()[slot] = None;
+    roots.free.borrow_mut().push(slot);
+}
+
+/// Like `Module::extra_value`, for modules with rooted values.
+pub(crate) fn extra_value(module: &Module) -> Option<Value<'_>> {
+    *roots(module).extra.borrow()
+}
+
+/// Like `Module::set_extra_value`, for modules with rooted values.
+pub(crate) fn set_extra_value<'v>(module: &'v Module, value: Value<'v>) {
+    *roots(module).extra.borrow_mut() = Some(value);
+}
+
+/// Like `FrozenModule::owned_extra_value`, for modules frozen with rooted
+/// values.
+pub(crate) fn frozen_extra_value(module: &FrozenModule) -> Option<OwnedFrozenValue> {
+    let value = module.owned_extra_value()?;
+    match value.value().downcast_ref::<FrozenRoots>() {
+        Some(roots) => roots.extra.map(|extra| value.map(|_| extra)),
+        None => Some(value),
+    }
+}
@@ -1,27 +1,55 @@
+use std::mem;
+
 use num_bigint::BigInt;
+use pyo3::exceptions::{PyRuntimeError, PyTypeError};
 use pyo3::prelude::*;
-use pyo3::types::{PyDict, PyList, PyTuple};
+use pyo3::types::{PyDict, PyList, PySet, PyTuple};
+use starlark::docs::{DocItem, DocMember};
 use starlark::values::dict::{DictRef, FrozenDictRef};
 use starlark::values::float::StarlarkFloat;
-use starlark::values::function::NativeFunction;
+use starlark::values::function::FUNCTION_TYPE;
 use starlark::values::list::ListRef;
+use starlark::values::set::SetRef;
 use starlark::values::tuple::{FrozenTupleRef, TupleRef};
-use starlark::values::{FrozenValue, UnpackValue, Value, ValueLike};
+use starlark::values::{
+    FrozenHeapRef, FrozenValue, Heap, OwnedFrozenValue, UnpackValue, Value, ValueLike,
+};
 
-use crate::py2sl::SlPyObject;
-use crate::values::{PyFrozenValue, PyValue};
+use crate::converters::{
+    from_starlark_converters_for, from_starlark_via_converters, is_strict_conversion_enabled,
+};
+use crate::errors::py_err_from_sl_eval_error;
+use crate::eval::current_heap_owner;
+use crate::py2sl::{SlPyFunction, SlPyObject};
+use crate::values::{HeapOwner, PyFrozenValue, PyValue};
 
 mod native_function;
-use native_function::PySlNativeFunction;
+mod structs;
+pub(crate) use native_function::PySlNativeFunction;
+use structs::py_from_sl_structured;
+
+pub(crate) fn py_from_sl_owned_frozen_value(
+    py: Python<'_>,
+    sl: &OwnedFrozenValue,
+) -> PyResult<PyObject> {
+    // Safety: the owner is kept alive by the opaque values created
+    py_from_sl_frozen_value(py, unsafe { sl.unchecked_frozen_value() }, sl.owner())
+}
 
-pub(crate) fn py_from_sl_frozen_value(py: Python<'_>, sl: FrozenValue) -> PyResult<PyObject> {
+/// Converts a value living on the frozen heap `owner` to Python, opaque values
+/// keeping the heap alive.
+pub(crate) fn py_from_sl_frozen_value(
+    py: Python<'_>,
+    sl: FrozenValue,
+    owner: &FrozenHeapRef,
+) -> PyResult<PyObject> {
     if sl.is_none() {
         Ok(py.None())
     } else if let Some(x) = sl.unpack_bool() {
         Ok(x.to_object(py))
     } else if let Some(x) = sl.unpack_i32() {
         Ok(x.to_object(py))
-    } else if let Some(x) = BigInt::unpack_value(sl.to_value()) {
+    } else if let Some(x) = BigInt::unpack_value(sl.to_value()).ok().flatten() {
         Ok(x.to_object(py))
     } else if let Some(x) = sl.downcast_frozen_ref::<StarlarkFloat>() {
         Ok(x.0.to_object(py))
@@ -30,40 +58,146 @@ pub(crate) fn py_from_sl_frozen_value(py: Python<'_>, sl: FrozenValue) -> PyResu
     } else if let Some(x) = FrozenTupleRef::from_frozen_value(sl) {
         let mut elements = Vec::new();
         for elem in x.content().into_iter() {
-            elements.push(py_from_sl_frozen_value(py, *elem)?);
+            elements.push(py_from_sl_frozen_value(py, *elem, owner)?);
         }
         Ok(PyTuple::new_bound(py, elements).as_any().clone().unbind())
     } else if let Some(x) = ListRef::from_frozen_value(sl) {
         let mut elements = Vec::new();
         for elem in x.content().into_iter() {
-            elements.push(py_from_sl_value(py, *elem)?);
+            // frozen lists only contain frozen values
+            let elem = elem.unpack_frozen().unwrap();
+            elements.push(py_from_sl_frozen_value(py, elem, owner)?);
         }
         Ok(PyList::new_bound(py, elements).as_any().clone().unbind())
     } else if let Some(x) = FrozenDictRef::from_frozen_value(sl) {
         let result = PyDict::new_bound(py);
         for (k, v) in x.iter() {
-            let k = py_from_sl_frozen_value(py, k)?;
-            let v = py_from_sl_frozen_value(py, v)?;
+            let k = py_from_sl_frozen_value(py, k, owner)?;
+            let v = py_from_sl_frozen_value(py, v, owner)?;
             result.set_item(k, v)?;
         }
         Ok(result.as_any().clone().unbind())
-    } else if let Some(x) = sl.downcast_frozen_ref::<NativeFunction>() {
-        PySlNativeFunction::new_py_any(py, x.as_ref())
+    } else if is_set(sl.to_value()) {
+        with_own_heap(py, sl.to_value(), None, |sl, heap| {
+            // frozen sets only contain frozen values
+            py_set_from_sl(py, sl, heap, |v| {
+                py_from_sl_frozen_value(py, v.unpack_frozen().unwrap(), owner)
+            })
+        })
     } else if let Some(x) = sl.downcast_frozen_ref::<SlPyObject>() {
         Ok(x.0.clone_ref(py))
+    } else if let Some(x) = sl.downcast_frozen_ref::<SlPyFunction>() {
+        Ok(x.func().clone_ref(py))
+    } else if is_native_function(sl.to_value()) {
+        // Safety: the value lives on the heap
+        let sl = unsafe { OwnedFrozenValue::new(owner.clone(), sl) };
+        PySlNativeFunction::new_py_any(py, sl)
     } else {
-        Ok(Py::new(py, PyFrozenValue::from(sl))?.into_any())
+        with_own_heap(py, sl.to_value(), None, |v, heap| {
+            py_from_sl_other(
+                py,
+                v,
+                heap,
+                // frozen values only contain frozen values
+                |v| py_from_sl_frozen_value(py, v.unpack_frozen().unwrap(), owner),
+                || {
+                    // Safety: the value lives on the heap
+                    let sl = unsafe { OwnedFrozenValue::new(owner.clone(), sl) };
+                    PyFrozenValue::new_py_any(py, sl)
+                },
+            )
+        })
+    }
+}
+
+/// Whether `sl` is a function implemented in Rust, or a method bound to a
+/// value.
+///
+/// Record and enum types are functions too, but they are documented as
+/// properties.
+fn is_native_function(sl: Value<'_>) -> bool {
+    sl.get_type() == FUNCTION_TYPE
+        // `def`s and lambdas
+        && sl.parameters_spec().is_none()
+        && !matches!(
+            sl.documentation(),
+            DocItem::Member(DocMember::Property(_))
+        )
+}
+
+fn is_set(sl: Value<'_>) -> bool {
+    SetRef::unpack_value_opt(sl).is_some()
+}
+
+thread_local! {
+    /// The heap frozen values are operated on with, for the operations that
+    /// need one but allocate nothing, like reading the value of enum values or
+    /// iterating over sets.
+    static FROZEN_VALUES_HEAP: Heap = Heap::new();
+}
+
+/// Runs `f` with `sl` and the heap it lives on, for operating on it: that of
+/// `owner`, or else of the evaluation in progress, unless `sl` is frozen.
+fn with_own_heap<R>(
+    py: Python<'_>,
+    sl: Value<'_>,
+    owner: Option<&HeapOwner>,
+    f: impl for<'v> FnOnce(Value<'v>, &'v Heap) -> PyResult<R>,
+) -> PyResult<R> {
+    if let Some(sl) = sl.unpack_frozen() {
+        return FROZEN_VALUES_HEAP.with(|heap| f(sl.to_value(), heap));
     }
+    let owner = match owner {
+        Some(owner) => owner.clone_ref(py),
+        None => current_heap_owner(py).ok_or_else(|| {
+            PyRuntimeError::new_err("the heap owning this Starlark value is unknown")
+        })?,
+    };
+    owner.with_heap(py, |heap| {
+        // Safety: `sl` is not frozen, so it lives on the heap of its owner,
+        // which is borrowed for as long as `f` runs, so that neither goes away
+        // in the meantime.
+        let sl = unsafe { mem::transmute::<Value<'_>, Value<'_>>(sl) };
+        f(sl, heap)
+    })
+}
+
+/// Converts the Starlark set `sl`, living on `heap`, to a Python set,
+/// converting its elements with `convert`.
+fn py_set_from_sl<'v>(
+    py: Python<'_>,
+    sl: Value<'v>,
+    heap: &'v Heap,
+    convert: impl Fn(Value<'v>) -> PyResult<PyObject>,
+) -> PyResult<PyObject> {
+    let result = PySet::empty_bound(py)?;
+    let elements = sl
+        .iterate(heap)
+        .map_err(|e| py_err_from_sl_eval_error(py, e))?;
+    for elem in elements {
+        result.add(convert(elem)?)?;
+    }
+    Ok(result.as_any().clone().unbind())
 }
 
 pub(crate) fn py_from_sl_value(py: Python<'_>, sl: Value<'_>) -> PyResult<PyObject> {
+    py_from_sl_value_owned(py, sl, None)
+}
+
+/// Like `py_from_sl_value`, but with the owner of the heap the value lives on
+/// given explicitly, instead of being the module currently being evaluated.
+pub(crate) fn py_from_sl_value_owned(
+    py: Python<'_>,
+    sl: Value<'_>,
+    owner: Option<&HeapOwner>,
+) -> PyResult<PyObject> {
     if sl.is_none() {
         Ok(py.None())
     } else if let Some(x) = sl.unpack_bool() {
         Ok(x.to_object(py))
     } else if let Some(x) = sl.unpack_i32() {
         Ok(x.to_object(py))
-    } else if let Some(x) = BigInt::unpack_value(sl) {
+    } else if let Some(x) = BigInt::unpack_value(sl).ok().flatten() {
         Ok(x.to_object(py))
     } else if let Some(x) = sl.downcast_ref::<StarlarkFloat>() {
         Ok(x.0.to_object(py))
@@ -72,35 +206,92 @@ pub(crate) fn py_from_sl_value(py: Python<'_>, sl: Value<'_>) -> PyResult<PyObje
     } else if let Some(x) = TupleRef::from_value(sl) {
         let mut elements = Vec::new();
         for elem in x.content().into_iter() {
-            elements.push(py_from_sl_value(py, *elem)?);
+            elements.push(py_from_sl_value_owned(py, *elem, owner)?);
         }
         Ok(PyTuple::new_bound(py, elements).as_any().clone().unbind())
     } else if let Some(x) = ListRef::from_value(sl) {
         let mut elements = Vec::new();
         for elem in x.content().into_iter() {
-            elements.push(py_from_sl_value(py, *elem)?);
+            elements.push(py_from_sl_value_owned(py, *elem, owner)?);
         }
         Ok(PyList::new_bound(py, elements).as_any().clone().unbind())
     } else if let Some(x) = DictRef::from_value(sl) {
         let result = PyDict::new_bound(py);
         for (k, v) in x.iter() {
-            let k = py_from_sl_value(py, k)?;
-            let v = py_from_sl_value(py, v)?;
+            let k = py_from_sl_value_owned(py, k, owner)?;
+            let v = py_from_sl_value_owned(py, v, owner)?;
             result.set_item(k, v)?;
         }
         Ok(result.as_any().clone().unbind())
-    } else if let Some(x) = sl.downcast_ref::<NativeFunction>() {
-        PySlNativeFunction::new_py_any(py, x)
+    } else if is_set(sl) {
+        with_own_heap(py, sl, owner, |sl, heap| {
+            py_set_from_sl(py, sl, heap, |v| py_from_sl_value_owned(py, v, owner))
+        })
     } else if let Some(x) = sl.downcast_ref::<SlPyObject>() {
         Ok(x.0.clone_ref(py))
+    } else if let Some(x) = sl.downcast_ref::<SlPyFunction>() {
+        Ok(x.func().clone_ref(py))
     } else {
-        Ok(Py::new(py, PyValue::from(sl))?.into_any())
+        with_own_heap(py, sl, owner, |sl, heap| {
+            py_from_sl_other(
+                py,
+                sl,
+                heap,
+                |v| py_from_sl_value_owned(py, v, owner),
+                || {
+                    let owner = match owner {
+                        Some(owner) => owner.clone_ref(py),
+                        None => current_heap_owner(py).ok_or_else(|| {
+                            PyRuntimeError::new_err(
+                                "the heap owning this Starlark value is unknown",
+                            )
+                        })?,
+                    };
+                    PyValue::new_py_any(py, sl, owner)
+                },
+            )
+        })
     }
 }
 
 pub(crate) fn py_from_sl_value_option(
     py: Python<'_>,
     sl: Option<Value<'_>>,
+    owner: Option<&HeapOwner>,
 ) -> PyResult<Option<PyObject>> {
-    sl.map(|v| py_from_sl_value(py, v)).transpose()
+    sl.map(|v| py_from_sl_value_owned(py, v, owner)).transpose()
+}
+
+/// Converts a Starlark value living on `heap` without a built-in conversion,
+/// converting its contents with `convert`: with the registered converters
+/// first, then as a struct, record or enum value, and last as the opaque value
+/// made by `wrap`.
+fn py_from_sl_other<'v>(
+    py: Python<'_>,
+    sl: Value<'v>,
+    heap: &'v Heap,
+    convert: impl Fn(Value<'v>) -> PyResult<PyObject>,
+    wrap: impl Fn() -> PyResult<PyObject>,
+) -> PyResult<PyObject> {
+    let converters = from_starlark_converters_for(py, sl.get_type());
+    let wrapped = if !converters.is_empty() {
+        let wrapped = wrap()?;
+        if let Some(x) = from_starlark_via_converters(wrapped.bind(py), converters)? {
+            return Ok(x);
+        }
+        Some(wrapped)
+    } else {
+        None
+    };
+
+    if let Some(x) = py_from_sl_structured(py, sl, heap, convert)? {
+        Ok(x)
+    } else if is_strict_conversion_enabled(py) {
+        Err(PyTypeError::new_err(format!(
+            "cannot convert Starlark value of type '{}' to Python",
+            sl.get_type()
+        )))
+    } else {
+        wrapped.map_or_else(wrap, Ok)
+    }
 }
@@ -1,25 +1,35 @@
 use pyo3::prelude::*;
-use starlark::values::function::NativeFunction;
+use pyo3::types::{PyDict, PyTuple};
+use starlark::values::{FrozenValue, OwnedFrozenValue};
+
+use crate::eval::call_sl_callable;
 
 #[pyclass(module = "xingque", name = "_SlNativeFunction", frozen)]
-pub(crate) struct PySlNativeFunction(&'static NativeFunction);
+pub(crate) struct PySlNativeFunction(pub(crate) OwnedFrozenValue);
 
 impl PySlNativeFunction {
-    pub(crate) fn new(value: &NativeFunction) -> Self {
-        // Safety: Rust functions will live as long as the extension is loaded
-        Self(unsafe { ::core::mem::transmute(value) })
+    pub(crate) fn new_py_any(py: Python, value: OwnedFrozenValue) -> PyResult<PyObject> {
+        Py::new(py, Self(value)).map(Py::into_any)
     }
 
-    pub(crate) fn new_py_any(py: Python, value: &NativeFunction) -> PyResult<PyObject> {
-        Py::new(py, Self::new(value)).map(Py::into_any)
+    pub(crate) fn frozen_value(&self) -> FrozenValue {
+        // Safety: see PyFrozenValue::frozen_value
+        unsafe { self.0.unchecked_frozen_value() }
     }
 }
 
 #[pymethods]
 impl PySlNativeFunction {
     fn __repr__(&self) -> String {
-        format!("<Starlark native fn {}>", self.0.to_string())
+        format!("<Starlark native fn {}>", self.0.value())
     }
 
-    // this isn't directly callable because an Evaluator is needed
+    #[pyo3(signature = (*args, **kwargs))]
+    fn __call__(
+        slf: &Bound<'_, Self>,
+        args: &Bound<'_, PyTuple>,
+        kwargs: Option<&Bound<'_, PyDict>>,
+    ) -> PyResult<PyObject> {
+        call_sl_callable(slf.as_any(), args, kwargs)
+    }
 }
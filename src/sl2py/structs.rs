@@ -0,0 +1,60 @@
+use pyo3::exceptions::PyRuntimeError;
+use pyo3::prelude::*;
+use pyo3::types::PyDict;
+use starlark::values::enumeration::EnumValue;
+use starlark::values::record::Record;
+use starlark::values::structs::StructRef;
+use starlark::values::{Heap, Value};
+
+use crate::converters::{is_struct_conversion_enabled, py_type_for, struct_type};
+use crate::errors::py_err_from_sl_eval_error;
+
+/// Converts `sl` to Python if it is a struct or record while struct
+/// conversion is enabled, or a record or enum value of a registered type,
+/// converting its contents with `convert`.
+///
+/// Structs become instances of the configured struct type, and records
+/// instances of the Python type registered for their type, or dicts. `heap` is
+/// the heap `sl` lives on.
+pub(super) fn py_from_sl_structured<'v>(
+    py: Python<'_>,
+    sl: Value<'v>,
+    heap: &'v Heap,
+    convert: impl Fn(Value<'v>) -> PyResult<PyObject>,
+) -> PyResult<Option<PyObject>> {
+    if let Some(x) = StructRef::from_value(sl) {
+        if !is_struct_conversion_enabled(py) {
+            return Ok(None);
+        }
+        let kwargs = PyDict::new_bound(py);
+        for (k, v) in x.iter() {
+            kwargs.set_item(k.as_str(), convert(v)?)?;
+        }
+        Ok(Some(struct_type(py)?.call_bound(py, (), Some(&kwargs))?))
+    } else if let Some(x) = Record::from_value(sl) {
+        let py_type = py_type_for(py, sl);
+        if py_type.is_none() && !is_struct_conversion_enabled(py) {
+            return Ok(None);
+        }
+        let fields = PyDict::new_bound(py);
+        for (k, v) in x.iter() {
+            fields.set_item(k, convert(v)?)?;
+        }
+        match py_type {
+            Some(py_type) => Ok(Some(py_type.call_bound(py, (), Some(&fields))?)),
+            None => Ok(Some(fields.into_any().unbind())),
+        }
+    } else if EnumValue::from_value(sl).is_some() {
+        let py_type = match py_type_for(py, sl) {
+            Some(x) => x,
+            None => return Ok(None),
+        };
+        let value = sl
+            .get_attr("value", heap)
+            .map_err(|e| py_err_from_sl_eval_error(py, e))?
+            .ok_or_else(|| PyRuntimeError::new_err("enum values have a value"))?;
+        Ok(Some(py_type.call1(py, (convert(value)?,))?))
+    } else {
+        Ok(None)
+    }
+}
@@ -1,11 +1,19 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 
-use pyo3::exceptions::{PyRuntimeError, PyValueError};
+use pyo3::exceptions::{PyBaseException, PyRuntimeError};
 use pyo3::prelude::*;
+use starlark::analysis::AstModuleLint;
+use starlark::environment::Globals;
 use starlark::syntax::{AstLoad, AstModule, Dialect, DialectTypes};
+use starlark::typing::AstModuleTypecheck;
 
+use crate::analysis::PyLint;
 use crate::codemap::{PyFileSpan, PySpan};
+use crate::docs::{doc_module_from_ast, PyDocModule};
+use crate::environment::PyGlobals;
+use crate::errors::{py_err_from_sl_error, py_err_from_sl_parse_error, TypeCheckError};
 use crate::repr_utils::{PyReprBool, PyReprDialectTypes};
+use crate::typing::{PyApproximation, PyInterface, PyTypeMap};
 
 #[pyclass(
     module = "xingque",
@@ -43,7 +51,7 @@ impl From<DialectTypes> for PyDialectTypes {
 }
 
 #[pyclass(module = "xingque", name = "Dialect")]
-pub(crate) struct PyDialect(Dialect);
+pub(crate) struct PyDialect(pub(crate) Dialect);
 
 macro_rules! trivial_bool_prop {
     // still no concat_idents! so we have to duplicate a little
@@ -121,7 +129,7 @@ impl PyDialect {
     const EXTENDED: Self = Self(Dialect::Extended);
 
     #[classattr]
-    const STANDARD: Self = Self(Dialect::Standard);
+    pub(crate) const STANDARD: Self = Self(Dialect::Standard);
 
     #[getter]
     fn get_enable_types(&self) -> PyResult<PyDialectTypes> {
@@ -205,23 +213,34 @@ impl PyAstModule {
     }
 }
 
+/// The errors, type map, interface and approximations `AstModule.typecheck`
+/// returns.
+type TypecheckResult = (
+    Vec<Py<PyBaseException>>,
+    PyTypeMap,
+    PyInterface,
+    Vec<PyApproximation>,
+);
+
 #[pymethods]
 impl PyAstModule {
     #[staticmethod]
     #[pyo3(signature = (path, dialect = &PyDialect::STANDARD))]
-    fn parse_file(path: ::std::path::PathBuf, dialect: &PyDialect) -> PyResult<Self> {
-        match AstModule::parse_file(&path, &dialect.0) {
+    fn parse_file(py: Python, path: ::std::path::PathBuf, dialect: &PyDialect) -> PyResult<Self> {
+        let dialect = &dialect.0;
+        match py.allow_threads(|| AstModule::parse_file(&path, dialect)) {
             Ok(x) => Ok(x.into()),
-            Err(e) => Err(PyValueError::new_err(e.to_string())),
+            Err(e) => Err(py_err_from_sl_parse_error(py, e)),
         }
     }
 
     #[staticmethod]
     #[pyo3(signature = (filename, content, dialect = &PyDialect::STANDARD))]
-    fn parse(filename: &str, content: String, dialect: &PyDialect) -> PyResult<Self> {
-        match AstModule::parse(filename, content, &dialect.0) {
+    fn parse(py: Python, filename: &str, content: String, dialect: &PyDialect) -> PyResult<Self> {
+        let dialect = &dialect.0;
+        match py.allow_threads(|| AstModule::parse(filename, content, dialect)) {
             Ok(x) => Ok(x.into()),
-            Err(e) => Err(PyValueError::new_err(e.to_string())),
+            Err(e) => Err(py_err_from_sl_parse_error(py, e)),
         }
     }
 
@@ -247,6 +266,66 @@ impl PyAstModule {
     fn replace_binary_operators(&mut self, replace: HashMap<String, String>) -> PyResult<()> {
         Ok(self.inner_mut()?.replace_binary_operators(&replace))
     }
+
+    /// Runs the linter, skipping the checks named in `disabled`.
+    ///
+    /// With `globals`, the names of the global symbols available to the
+    /// module, uses of any other undefined names are reported too.
+    #[pyo3(signature = (disabled = None, *, globals = None))]
+    fn lint(
+        &self,
+        disabled: Option<HashSet<String>>,
+        globals: Option<HashSet<String>>,
+    ) -> PyResult<Vec<PyLint>> {
+        let lints = self.inner()?.lint(globals.as_ref());
+        Ok(lints
+            .into_iter()
+            .filter(|x| !disabled.as_ref().is_some_and(|d| d.contains(&x.short_name)))
+            .map(Into::into)
+            .collect())
+    }
+
+    /// Extract the documentation of this module without evaluating it.
+    ///
+    /// Type annotations are resolved against `globals`, which defaults to
+    /// `Globals.standard()`, without evaluating them.
+    #[pyo3(signature = (globals = None))]
+    fn documentation(&self, globals: Option<&Bound<'_, PyGlobals>>) -> PyResult<PyDocModule> {
+        let ast = self.inner()?;
+        Ok(match globals {
+            Some(globals) => doc_module_from_ast(ast, &globals.borrow().0),
+            None => doc_module_from_ast(ast, &Globals::standard()),
+        }
+        .into())
+    }
+
+    #[pyo3(signature = (globals, loads = None))]
+    fn typecheck(
+        &self,
+        py: Python,
+        globals: &Bound<'_, PyGlobals>,
+        loads: Option<HashMap<String, PyInterface>>,
+    ) -> PyResult<TypecheckResult> {
+        let loads = loads
+            .unwrap_or_default()
+            .into_iter()
+            .map(|(k, v)| (k, v.0))
+            .collect();
+        let ast = self.inner()?;
+        let (errors, type_map, interface, approximations) =
+            ast.clone().typecheck(&globals.borrow().0, &loads);
+
+        let errors = errors
+            .into_iter()
+            .map(|e| py_err_from_sl_error::<TypeCheckError>(py, e, None).into_value(py))
+            .collect();
+        Ok((
+            errors,
+            type_map.into(),
+            PyInterface::new(interface, ast),
+            approximations.into_iter().map(Into::into).collect(),
+        ))
+    }
 }
 
 #[pyclass(module = "xingque", name = "AstLoad", frozen)]
@@ -0,0 +1,208 @@
+use std::collections::HashSet;
+
+use pyo3::prelude::*;
+use pyo3::types::{PyBool, PyDict, PyFloat, PyInt, PyIterator, PyList, PyNone, PyString, PyTuple};
+use starlark::syntax::AstModule;
+use starlark::typing::{Approximation, Interface, Ty, TypeMap};
+use starlark_syntax::syntax::ast::{AstStmt, Stmt};
+use starlark_syntax::syntax::module::AstModuleFields;
+
+/// A Starlark type.
+#[pyclass(module = "xingque", name = "Ty", eq, hash, frozen)]
+#[repr(transparent)]
+#[derive(PartialEq, Eq, Hash, Clone)]
+pub(crate) struct PyTy(Ty);
+
+impl From<Ty> for PyTy {
+    fn from(value: Ty) -> Self {
+        Self(value)
+    }
+}
+
+#[pymethods]
+impl PyTy {
+    fn __repr__(slf: &Bound<'_, Self>) -> PyResult<String> {
+        let class_name = slf.get_type().qualname()?;
+        Ok(format!("<{} {}>", class_name, slf.borrow().0))
+    }
+
+    fn __str__(&self) -> String {
+        self.0.to_string()
+    }
+
+    /// If this type is a simple named type, its name.
+    fn as_name(&self) -> Option<&str> {
+        self.0.as_name()
+    }
+}
+
+/// Interface representing the types of all bindings in a module.
+#[pyclass(module = "xingque", name = "Interface", frozen)]
+#[derive(Clone, Default)]
+pub(crate) struct PyInterface(pub(crate) Interface, Vec<String>);
+
+impl PyInterface {
+    /// Wraps the interface `ast` was typechecked into.
+    ///
+    /// The names bound by an interface are not exposed by starlark-rust, so
+    /// they are found among the names bound at the top level of `ast`, in the
+    /// order they are first bound.
+    pub(crate) fn new(interface: Interface, ast: &AstModule) -> Self {
+        fn visit(stmt: &AstStmt, names: &mut Vec<String>) {
+            match &stmt.node {
+                Stmt::Def(def) => names.push(def.name.ident.clone()),
+                Stmt::Load(load) => names.extend(load.args.iter().map(|x| x.local.ident.clone())),
+                Stmt::Assign(assign) => assign.lhs.visit_lvalue(|x| names.push(x.ident.clone())),
+                Stmt::AssignModify(lhs, _, _) => lhs.visit_lvalue(|x| names.push(x.ident.clone())),
+                Stmt::For(x) => {
+                    x.var.visit_lvalue(|x| names.push(x.ident.clone()));
+                    visit(&x.body, names);
+                }
+                _ => stmt.visit_stmt(|x| visit(x, names)),
+            }
+        }
+
+        let mut names = Vec::new();
+        visit(ast.statement(), &mut names);
+        let mut seen = HashSet::new();
+        names.retain(|x| interface.get(x).is_some() && seen.insert(x.clone()));
+        Self(interface, names)
+    }
+}
+
+#[pymethods]
+impl PyInterface {
+    /// Create an empty interface, with no bindings.
+    #[new]
+    fn py_new() -> Self {
+        Self::default()
+    }
+
+    /// Get the type for a given binding.
+    fn get(&self, name: &str) -> Option<PyTy> {
+        self.0.get(name).cloned().map(PyTy::from)
+    }
+
+    /// Get the names of the bindings, in the order they are bound.
+    fn keys(&self) -> Vec<String> {
+        self.1.clone()
+    }
+
+    fn __contains__(&self, name: &str) -> bool {
+        self.0.get(name).is_some()
+    }
+
+    fn __iter__<'py>(&self, py: Python<'py>) -> PyResult<Bound<'py, PyIterator>> {
+        PyIterator::from_bound_object(&PyList::new_bound(py, &self.1))
+    }
+}
+
+/// Structure containing all the inferred types.
+#[pyclass(module = "xingque", name = "TypeMap", frozen)]
+pub(crate) struct PyTypeMap(TypeMap);
+
+impl From<TypeMap> for PyTypeMap {
+    fn from(value: TypeMap) -> Self {
+        Self(value)
+    }
+}
+
+#[pymethods]
+impl PyTypeMap {
+    fn __str__(&self) -> String {
+        self.0.to_string()
+    }
+}
+
+/// A typing operation wasn't able to produce a precise result,
+/// so made some kind of approximation.
+#[pyclass(module = "xingque", name = "Approximation", eq, hash, frozen)]
+#[repr(transparent)]
+#[derive(PartialEq, Eq, Hash, Clone)]
+pub(crate) struct PyApproximation(Approximation);
+
+impl From<Approximation> for PyApproximation {
+    fn from(value: Approximation) -> Self {
+        Self(value)
+    }
+}
+
+#[pymethods]
+impl PyApproximation {
+    fn __repr__(slf: &Bound<'_, Self>) -> PyResult<String> {
+        let class_name = slf.get_type().qualname()?;
+        let me = slf.borrow();
+        Ok(format!(
+            "{}(category={:?}, message={:?})",
+            class_name, me.0.category, me.0.message,
+        ))
+    }
+
+    /// The category of the approximation, e.g. `"Unknown type"`.
+    #[getter]
+    fn category(&self) -> &str {
+        self.0.category
+    }
+
+    /// The precise details of this approximation, e.g. which type was unknown.
+    #[getter]
+    fn message(&self) -> &str {
+        &self.0.message
+    }
+}
+
+/// Converts a Python type annotation into the corresponding Starlark type.
+///
+/// Only the builtin types that have Starlark counterparts, their generic
+/// aliases (e.g. `list[int]`) and unions (including `Optional`) are
+/// understood; everything else, including missing annotations, becomes
+/// `typing.Any`.
+pub(crate) fn ty_from_py_annotation(x: &Bound<'_, PyAny>) -> PyResult<Ty> {
+    let py = x.py();
+    if x.is_none() || x.is(&py.get_type_bound::<PyNone>()) {
+        return Ok(Ty::none());
+    }
+
+    let typing = py.import_bound("typing")?;
+    let origin = typing.call_method1("get_origin", (x,))?;
+    if origin.is_none() {
+        // not a generic alias or union
+        return Ok(if x.is(&py.get_type_bound::<PyBool>()) {
+            Ty::bool()
+        } else if x.is(&py.get_type_bound::<PyInt>()) {
+            Ty::int()
+        } else if x.is(&py.get_type_bound::<PyFloat>()) {
+            Ty::float()
+        } else if x.is(&py.get_type_bound::<PyString>()) {
+            Ty::string()
+        } else if x.is(&py.get_type_bound::<PyList>()) {
+            Ty::list(Ty::any())
+        } else if x.is(&py.get_type_bound::<PyDict>()) {
+            Ty::dict(Ty::any(), Ty::any())
+        } else {
+            Ty::any()
+        });
+    }
+
+    let raw_args = typing.call_method1("get_args", (x,))?;
+    let args = raw_args
+        .iter()?
+        .map(|arg| ty_from_py_annotation(&arg?))
+        .collect::<PyResult<Vec<_>>>()?;
+    let is_union = origin.is(&typing.getattr("Union")?)
+        || py
+            .import_bound("types")?
+            .getattr("UnionType")
+            .is_ok_and(|t| origin.is(&t));
+    Ok(if is_union {
+        Ty::unions(args)
+    } else if origin.is(&py.get_type_bound::<PyList>()) && args.len() == 1 {
+        Ty::list(args[0].clone())
+    } else if origin.is(&py.get_type_bound::<PyDict>()) && args.len() == 2 {
+        Ty::dict(args[0].clone(), args[1].clone())
+    } else if origin.is(&py.get_type_bound::<PyTuple>()) && !raw_args.contains(py.Ellipsis())? {
+        Ty::tuple(args)
+    } else {
+        Ty::any()
+    })
+}
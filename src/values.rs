@@ -1,24 +1,79 @@
+use std::cmp::Ordering;
 use std::collections::HashMap;
 
+use pyo3::exceptions::{PyAttributeError, PyRuntimeError, PyTypeError, PyValueError};
+use pyo3::pyclass::CompareOp;
+use pyo3::types::{PyDict, PyIterator, PyList, PySlice};
 use pyo3::{prelude::*, types::PyTuple};
-use starlark::values::{FrozenValue, Heap, Value};
+use starlark::environment::Module;
+use starlark::values::function::FUNCTION_TYPE;
+use starlark::values::{FrozenHeapRef, FrozenValue, Heap, OwnedFrozenValue, Value};
 
-#[pyclass(module = "xingque", name = "FrozenValue", frozen)]
-pub(crate) struct PyFrozenValue(pub(crate) FrozenValue);
+use crate::environment::PyModule;
+use crate::errors::py_err_from_sl_op_error;
+use crate::eval::{call_sl_callable, with_heap_owner};
+use crate::py2sl::sl_value_from_py;
+use crate::roots;
+use crate::sl2py::py_from_sl_value_owned;
 
-impl From<FrozenValue> for PyFrozenValue {
-    fn from(value: FrozenValue) -> Self {
+#[pyclass(module = "xingque", name = "FrozenValue", subclass, frozen)]
+pub(crate) struct PyFrozenValue(pub(crate) OwnedFrozenValue);
+
+impl From<OwnedFrozenValue> for PyFrozenValue {
+    fn from(value: OwnedFrozenValue) -> Self {
         Self(value)
     }
 }
 
+impl PyFrozenValue {
+    /// Wraps `value` as a `FrozenValue`, or a `CallableFrozenValue` if it can
+    /// be called.
+    pub(crate) fn new_py_any(py: Python<'_>, value: OwnedFrozenValue) -> PyResult<PyObject> {
+        let callable = is_callable(value.value());
+        let init = PyClassInitializer::from(Self(value));
+        if callable {
+            Ok(Py::new(py, init.add_subclass(PyCallableFrozenValue))?.into_any())
+        } else {
+            Ok(Py::new(py, init)?.into_any())
+        }
+    }
+
+    pub(crate) fn frozen_value(&self) -> FrozenValue {
+        // Safety: the heap is kept alive by self, and every use of the value
+        // by us keeps the heap alive by other means
+        unsafe { self.0.unchecked_frozen_value() }
+    }
+}
+
 #[pymethods]
 impl PyFrozenValue {
     fn __repr__(&self) -> String {
-        format!("<Starlark frozen value {}>", self.0)
+        format!("<Starlark frozen value {}>", self.0.value())
     }
 }
 
+/// A `FrozenValue` that can be called from Python.
+#[pyclass(module = "xingque", name = "CallableFrozenValue", extends = PyFrozenValue, frozen)]
+pub(crate) struct PyCallableFrozenValue;
+
+#[pymethods]
+impl PyCallableFrozenValue {
+    #[pyo3(signature = (*args, **kwargs))]
+    fn __call__(
+        slf: &Bound<'_, Self>,
+        args: &Bound<'_, PyTuple>,
+        kwargs: Option<&Bound<'_, PyDict>>,
+    ) -> PyResult<PyObject> {
+        call_sl_callable(slf.as_any(), args, kwargs)
+    }
+}
+
+/// Whether `value` is a function, including `def`s, lambdas, `partial`
+/// results, bound methods and record and enum types.
+fn is_callable(value: Value<'_>) -> bool {
+    value.get_type() == FUNCTION_TYPE
+}
+
 /// Information about the data stored on a heap.
 #[pyclass(module = "xingque", name = "HeapSummary")]
 pub(crate) struct PyHeapSummary(HashMap<String, (usize, usize)>);
@@ -92,19 +147,486 @@ impl PyHeap {
     }
 }
 
-#[pyclass(module = "xingque", name = "Value", frozen)]
-pub(crate) struct PyValue(pub(crate) Value<'static>);
+/// The owner of the heap an unfrozen value lives on, i.e. the `Module` the
+/// value comes from, or a private one for results of operations on frozen
+/// values.
+pub(crate) struct HeapOwner(Py<PyModule>);
+
+impl From<Py<PyModule>> for HeapOwner {
+    fn from(value: Py<PyModule>) -> Self {
+        Self(value)
+    }
+}
+
+impl HeapOwner {
+    fn new_scratch(py: Python<'_>) -> PyResult<Self> {
+        Ok(Self(Py::new(py, PyModule::from(Module::new()))?))
+    }
+
+    pub(crate) fn module(&self) -> &Py<PyModule> {
+        &self.0
+    }
+
+    pub(crate) fn clone_ref(&self, py: Python<'_>) -> Self {
+        Self(self.0.clone_ref(py))
+    }
+
+    pub(crate) fn with_module<R>(
+        &self,
+        py: Python<'_>,
+        f: impl FnOnce(&Module) -> PyResult<R>,
+    ) -> PyResult<R> {
+        let module = self.0.try_borrow(py)?;
+        if module.is_consumed() {
+            return Err(PyRuntimeError::new_err(
+                "the Module owning this Value is already frozen",
+            ));
+        }
+        f(module.inner()?)
+    }
+
+    /// Runs `f` with the owned heap, failing if it is gone because the owning
+    /// `Module` has been frozen. The `Module` cannot be frozen while `f` runs.
+    pub(crate) fn with_heap<R>(
+        &self,
+        py: Python<'_>,
+        f: impl for<'v> FnOnce(&'v Heap) -> PyResult<R>,
+    ) -> PyResult<R> {
+        self.with_module(py, |module| {
+            with_heap_owner(self.clone_ref(py), || f(module.heap()))
+        })
+    }
+
+    /// Makes the owned heap keep the frozen heap `heap` alive, so that values
+    /// on it can be referenced by values on the owned heap.
+    pub(crate) fn keep_alive(&self, py: Python<'_>, heap: &FrozenHeapRef) -> PyResult<()> {
+        self.with_module(py, |module| {
+            module.frozen_heap().add_reference(heap);
+            Ok(())
+        })
+    }
+}
+
+#[pyclass(module = "xingque", name = "Value", subclass, frozen)]
+pub(crate) struct PyValue {
+    value: WrappedValue,
+    // this reference is necessary for memory safety
+    owner: HeapOwner,
+}
+
+enum WrappedValue {
+    Frozen(FrozenValue),
+    /// Rooted in the owning module, as the garbage collector moves values
+    /// around.
+    Rooted(usize),
+}
+
+impl PyValue {
+    pub(crate) fn new(py: Python<'_>, value: Value<'_>, owner: HeapOwner) -> PyResult<Self> {
+        let value = match value.unpack_frozen() {
+            Some(x) => WrappedValue::Frozen(x),
+            // the value lives on the heap of the module
+            None => owner.with_module(py, |module| {
+                Ok(WrappedValue::Rooted(roots::root(module, rebind(value))))
+            })?,
+        };
+        Ok(Self { value, owner })
+    }
+
+    /// Wraps `value` as a `Value`, or a `CallableValue` if it can be called.
+    pub(crate) fn new_py_any(
+        py: Python<'_>,
+        value: Value<'_>,
+        owner: HeapOwner,
+    ) -> PyResult<PyObject> {
+        let callable = is_callable(value);
+        let init = PyClassInitializer::from(Self::new(py, value, owner)?);
+        if callable {
+            Ok(Py::new(py, init.add_subclass(PyCallableValue))?.into_any())
+        } else {
+            Ok(Py::new(py, init)?.into_any())
+        }
+    }
+
+    pub(crate) fn owner(&self) -> &HeapOwner {
+        &self.owner
+    }
 
-impl<'v> From<Value<'v>> for PyValue {
-    fn from(value: Value<'v>) -> Self {
-        // TODO: safety
-        Self(unsafe { ::core::mem::transmute(value) })
+    /// Returns the wrapped value for use on `heap`, which must be the heap
+    /// the value lives on, unless the value is frozen.
+    pub(crate) fn to_heap<'v>(&self, py: Python<'_>, heap: &'v Heap) -> PyResult<Value<'v>> {
+        let slot = match self.value {
+            WrappedValue::Frozen(x) => return Ok(x.to_value()),
+            WrappedValue::Rooted(slot) => slot,
+        };
+        self.owner.with_module(py, |module| {
+            if ::core::ptr::eq(module.heap(), heap) {
+                Ok(rebind(roots::rooted(module, slot)))
+            } else {
+                Err(PyValueError::new_err(
+                    "this Value belongs to a different heap and cannot be used here",
+                ))
+            }
+        })
+    }
+}
+
+impl Drop for PyValue {
+    fn drop(&mut self) {
+        if let WrappedValue::Rooted(slot) = self.value {
+            Python::with_gil(|py| {
+                // nothing to do if the module is gone
+                let _ = self.owner.with_module(py, |module| {
+                    roots::unroot(module, slot);
+                    Ok(())
+                });
+            })
+        }
     }
 }
 
 #[pymethods]
 impl PyValue {
-    fn __repr__(&self) -> String {
-        format!("<Starlark value {}>", self.0)
+    fn __repr__(&self, py: Python<'_>) -> String {
+        match self.with_value(py, None, |v, _, _| Ok(v.to_repr())) {
+            Ok(repr) => format!("<Starlark value {}>", repr),
+            Err(_) => "<Starlark value from a frozen Module>".to_owned(),
+        }
     }
 }
+
+/// A `Value` that can be called from Python.
+#[pyclass(module = "xingque", name = "CallableValue", extends = PyValue, frozen)]
+pub(crate) struct PyCallableValue;
+
+#[pymethods]
+impl PyCallableValue {
+    #[pyo3(signature = (*args, **kwargs))]
+    fn __call__(
+        slf: &Bound<'_, Self>,
+        args: &Bound<'_, PyTuple>,
+        kwargs: Option<&Bound<'_, PyDict>>,
+    ) -> PyResult<PyObject> {
+        call_sl_callable(slf.as_any(), args, kwargs)
+    }
+}
+
+/// Extends the lifetime of a value on the heap of a module to that of `heap`,
+/// which is the same heap borrowed differently.
+fn rebind<'a, 'v>(value: Value<'a>) -> Value<'v> {
+    unsafe { ::core::mem::transmute(value) }
+}
+
+/// A Starlark value that can be operated on from Python.
+trait SlOperand {
+    /// Runs `f` with the value, a heap for allocating the results and the
+    /// owner of that heap. `other` is the other operand, if any, whose heap
+    /// is preferred over a scratch one if this value does not have a heap.
+    fn with_value<R>(
+        &self,
+        py: Python<'_>,
+        other: Option<&Bound<'_, PyAny>>,
+        f: impl for<'v> FnOnce(Value<'v>, &'v Heap, &HeapOwner) -> PyResult<R>,
+    ) -> PyResult<R>;
+}
+
+impl SlOperand for PyValue {
+    fn with_value<R>(
+        &self,
+        py: Python<'_>,
+        _other: Option<&Bound<'_, PyAny>>,
+        f: impl for<'v> FnOnce(Value<'v>, &'v Heap, &HeapOwner) -> PyResult<R>,
+    ) -> PyResult<R> {
+        self.owner
+            .with_heap(py, |heap| f(self.to_heap(py, heap)?, heap, &self.owner))
+    }
+}
+
+impl SlOperand for PyFrozenValue {
+    fn with_value<R>(
+        &self,
+        py: Python<'_>,
+        other: Option<&Bound<'_, PyAny>>,
+        f: impl for<'v> FnOnce(Value<'v>, &'v Heap, &HeapOwner) -> PyResult<R>,
+    ) -> PyResult<R> {
+        let owner = match other.and_then(|x| x.downcast::<PyValue>().ok()) {
+            Some(x) => x.borrow().owner.clone_ref(py),
+            None => HeapOwner::new_scratch(py)?,
+        };
+        // the results may reference this value
+        owner.keep_alive(py, self.0.owner())?;
+        owner.with_heap(py, |heap| f(self.frozen_value().to_value(), heap, &owner))
+    }
+}
+
+fn py_from_sl_op_result(
+    py: Python<'_>,
+    result: starlark::Result<Value<'_>>,
+    owner: &HeapOwner,
+) -> PyResult<PyObject> {
+    match result {
+        Ok(v) => py_from_sl_value_owned(py, v, Some(owner)),
+        Err(e) => Err(py_err_from_sl_op_error(py, e)),
+    }
+}
+
+fn unary_op(
+    py: Python<'_>,
+    this: &impl SlOperand,
+    op: impl for<'v> FnOnce(Value<'v>, &'v Heap) -> starlark::Result<Value<'v>>,
+) -> PyResult<PyObject> {
+    this.with_value(py, None, |this, heap, owner| {
+        py_from_sl_op_result(py, op(this, heap), owner)
+    })
+}
+
+fn binary_op(
+    this: &impl SlOperand,
+    other: &Bound<'_, PyAny>,
+    reflected: bool,
+    op: impl for<'v> FnOnce(Value<'v>, Value<'v>, &'v Heap) -> starlark::Result<Value<'v>>,
+) -> PyResult<PyObject> {
+    let py = other.py();
+    this.with_value(py, Some(other), |this, heap, owner| {
+        let other = sl_value_from_py(other, heap)?;
+        let result = if reflected {
+            op(other, this, heap)
+        } else {
+            op(this, other, heap)
+        };
+        py_from_sl_op_result(py, result, owner)
+    })
+}
+
+fn get_attr(py: Python<'_>, this: &impl SlOperand, name: &str) -> PyResult<PyObject> {
+    this.with_value(py, None, |this, heap, owner| {
+        match this.get_attr(name, heap) {
+            Ok(Some(v)) => py_from_sl_value_owned(py, v, Some(owner)),
+            Ok(None) => Err(PyAttributeError::new_err(format!(
+                "Starlark value of type `{}` has no attribute `{}`",
+                this.get_type(),
+                name
+            ))),
+            Err(e) => Err(py_err_from_sl_op_error(py, e)),
+        }
+    })
+}
+
+fn get_item(this: &impl SlOperand, key: &Bound<'_, PyAny>) -> PyResult<PyObject> {
+    let py = key.py();
+    this.with_value(py, Some(key), |this, heap, owner| {
+        let result = if let Ok(slice) = key.downcast::<PySlice>() {
+            let to_sl = |name: &str| -> PyResult<Option<Value>> {
+                let x = slice.getattr(name)?;
+                if x.is_none() {
+                    Ok(None)
+                } else {
+                    sl_value_from_py(&x, heap).map(Some)
+                }
+            };
+            this.slice(to_sl("start")?, to_sl("stop")?, to_sl("step")?, heap)
+        } else {
+            this.at(sl_value_from_py(key, heap)?, heap)
+        };
+        py_from_sl_op_result(py, result, owner)
+    })
+}
+
+fn iter<'py>(py: Python<'py>, this: &impl SlOperand) -> PyResult<Bound<'py, PyIterator>> {
+    let elems = this.with_value(py, None, |this, heap, owner| {
+        let mut tmp = Vec::new();
+        let it = this
+            .iterate(heap)
+            .map_err(|e| py_err_from_sl_op_error(py, e))?;
+        for v in it {
+            tmp.push(py_from_sl_value_owned(py, v, Some(owner))?);
+        }
+        Ok(tmp)
+    })?;
+    PyList::new_bound(py, elems).as_any().iter()
+}
+
+fn contains(this: &impl SlOperand, item: &Bound<'_, PyAny>) -> PyResult<bool> {
+    let py = item.py();
+    this.with_value(py, Some(item), |this, heap, _| {
+        this.is_in(sl_value_from_py(item, heap)?)
+            .map_err(|e| py_err_from_sl_op_error(py, e))
+    })
+}
+
+fn richcmp(this: &impl SlOperand, other: &Bound<'_, PyAny>, op: CompareOp) -> PyResult<bool> {
+    let py = other.py();
+    this.with_value(py, Some(other), |this, heap, _| {
+        let other = sl_value_from_py(other, heap)?;
+        let result = match op {
+            CompareOp::Eq => this.equals(other),
+            CompareOp::Ne => this.equals(other).map(|x| !x),
+            _ => this.compare(other).map(|ord| match op {
+                CompareOp::Lt => ord == Ordering::Less,
+                CompareOp::Le => ord != Ordering::Greater,
+                CompareOp::Gt => ord == Ordering::Greater,
+                CompareOp::Ge => ord != Ordering::Less,
+                CompareOp::Eq | CompareOp::Ne => unreachable!(),
+            }),
+        };
+        result.map_err(|e| py_err_from_sl_op_error(py, e))
+    })
+}
+
+/// Proxies the Python data model to the corresponding `Value` operations.
+macro_rules! value_protocol {
+    ($cls: ident) => {
+        #[pymethods]
+        impl $cls {
+            fn __getattr__(&self, py: Python<'_>, name: &str) -> PyResult<PyObject> {
+                get_attr(py, self, name)
+            }
+
+            fn __dir__(&self, py: Python<'_>) -> PyResult<Vec<String>> {
+                self.with_value(py, None, |this, _, _| Ok(this.dir_attr()))
+            }
+
+            fn __getitem__(&self, key: &Bound<'_, PyAny>) -> PyResult<PyObject> {
+                get_item(self, key)
+            }
+
+            fn __len__(&self, py: Python<'_>) -> PyResult<usize> {
+                self.with_value(py, None, |this, _, _| match this.length() {
+                    Ok(len) => Ok(len as usize),
+                    Err(e) => Err(py_err_from_sl_op_error(py, e)),
+                })
+            }
+
+            fn __iter__<'py>(&self, py: Python<'py>) -> PyResult<Bound<'py, PyIterator>> {
+                iter(py, self)
+            }
+
+            fn __contains__(&self, item: &Bound<'_, PyAny>) -> PyResult<bool> {
+                contains(self, item)
+            }
+
+            fn __richcmp__(&self, other: &Bound<'_, PyAny>, op: CompareOp) -> PyResult<bool> {
+                richcmp(self, other, op)
+            }
+
+            fn __hash__(&self, py: Python<'_>) -> PyResult<u32> {
+                self.with_value(py, None, |this, _, _| match this.get_hashed() {
+                    Ok(x) => Ok(x.hash().get()),
+                    // the only way hashing fails is an unhashable value
+                    Err(e) => Err(PyTypeError::new_err(e.to_string())),
+                })
+            }
+
+            fn __bool__(&self, py: Python<'_>) -> PyResult<bool> {
+                self.with_value(py, None, |this, _, _| Ok(this.to_bool()))
+            }
+
+            fn __str__(&self, py: Python<'_>) -> PyResult<String> {
+                self.with_value(py, None, |this, _, _| Ok(this.to_str()))
+            }
+
+            fn __pos__(&self, py: Python<'_>) -> PyResult<PyObject> {
+                unary_op(py, self, |x, heap| x.plus(heap))
+            }
+
+            fn __neg__(&self, py: Python<'_>) -> PyResult<PyObject> {
+                unary_op(py, self, |x, heap| x.minus(heap))
+            }
+
+            fn __invert__(&self, py: Python<'_>) -> PyResult<PyObject> {
+                unary_op(py, self, |x, heap| x.bit_not(heap))
+            }
+
+            fn __add__(&self, other: &Bound<'_, PyAny>) -> PyResult<PyObject> {
+                binary_op(self, other, false, |x, y, heap| x.add(y, heap))
+            }
+
+            fn __radd__(&self, other: &Bound<'_, PyAny>) -> PyResult<PyObject> {
+                binary_op(self, other, true, |x, y, heap| x.add(y, heap))
+            }
+
+            fn __sub__(&self, other: &Bound<'_, PyAny>) -> PyResult<PyObject> {
+                binary_op(self, other, false, |x, y, heap| x.sub(y, heap))
+            }
+
+            fn __rsub__(&self, other: &Bound<'_, PyAny>) -> PyResult<PyObject> {
+                binary_op(self, other, true, |x, y, heap| x.sub(y, heap))
+            }
+
+            fn __mul__(&self, other: &Bound<'_, PyAny>) -> PyResult<PyObject> {
+                binary_op(self, other, false, |x, y, heap| x.mul(y, heap))
+            }
+
+            fn __rmul__(&self, other: &Bound<'_, PyAny>) -> PyResult<PyObject> {
+                binary_op(self, other, true, |x, y, heap| x.mul(y, heap))
+            }
+
+            fn __truediv__(&self, other: &Bound<'_, PyAny>) -> PyResult<PyObject> {
+                binary_op(self, other, false, |x, y, heap| x.div(y, heap))
+            }
+
+            fn __rtruediv__(&self, other: &Bound<'_, PyAny>) -> PyResult<PyObject> {
+                binary_op(self, other, true, |x, y, heap| x.div(y, heap))
+            }
+
+            fn __floordiv__(&self, other: &Bound<'_, PyAny>) -> PyResult<PyObject> {
+                binary_op(self, other, false, |x, y, heap| x.floor_div(y, heap))
+            }
+
+            fn __rfloordiv__(&self, other: &Bound<'_, PyAny>) -> PyResult<PyObject> {
+                binary_op(self, other, true, |x, y, heap| x.floor_div(y, heap))
+            }
+
+            fn __mod__(&self, other: &Bound<'_, PyAny>) -> PyResult<PyObject> {
+                binary_op(self, other, false, |x, y, heap| x.percent(y, heap))
+            }
+
+            fn __rmod__(&self, other: &Bound<'_, PyAny>) -> PyResult<PyObject> {
+                binary_op(self, other, true, |x, y, heap| x.percent(y, heap))
+            }
+
+            fn __and__(&self, other: &Bound<'_, PyAny>) -> PyResult<PyObject> {
+                binary_op(self, other, false, |x, y, heap| x.bit_and(y, heap))
+            }
+
+            fn __rand__(&self, other: &Bound<'_, PyAny>) -> PyResult<PyObject> {
+                binary_op(self, other, true, |x, y, heap| x.bit_and(y, heap))
+            }
+
+            fn __or__(&self, other: &Bound<'_, PyAny>) -> PyResult<PyObject> {
+                binary_op(self, other, false, |x, y, heap| x.bit_or(y, heap))
+            }
+
+            fn __ror__(&self, other: &Bound<'_, PyAny>) -> PyResult<PyObject> {
+                binary_op(self, other, true, |x, y, heap| x.bit_or(y, heap))
+            }
+
+            fn __xor__(&self, other: &Bound<'_, PyAny>) -> PyResult<PyObject> {
+                binary_op(self, other, false, |x, y, heap| x.bit_xor(y, heap))
+            }
+
+            fn __rxor__(&self, other: &Bound<'_, PyAny>) -> PyResult<PyObject> {
+                binary_op(self, other, true, |x, y, heap| x.bit_xor(y, heap))
+            }
+
+            fn __lshift__(&self, other: &Bound<'_, PyAny>) -> PyResult<PyObject> {
+                binary_op(self, other, false, |x, y, heap| x.left_shift(y, heap))
+            }
+
+            fn __rlshift__(&self, other: &Bound<'_, PyAny>) -> PyResult<PyObject> {
+                binary_op(self, other, true, |x, y, heap| x.left_shift(y, heap))
+            }
+
+            fn __rshift__(&self, other: &Bound<'_, PyAny>) -> PyResult<PyObject> {
+                binary_op(self, other, false, |x, y, heap| x.right_shift(y, heap))
+            }
+
+            fn __rrshift__(&self, other: &Bound<'_, PyAny>) -> PyResult<PyObject> {
+                binary_op(self, other, true, |x, y, heap| x.right_shift(y, heap))
+            }
+        }
+    };
+}
+
+value_protocol!(PyFrozenValue);
+value_protocol!(PyValue);
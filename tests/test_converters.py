@@ -0,0 +1,123 @@
+import decimal
+import pathlib
+
+import pytest
+import xingque
+
+
+class Point:
+    def __init__(self, x: int, y: int) -> None:
+        self.x = x
+        self.y = y
+
+
+class Point3(Point):
+    def __init__(self, x: int, y: int, z: int) -> None:
+        super().__init__(x, y)
+        self.z = z
+
+
+def eval_with(**values):
+    gb = xingque.GlobalsBuilder.standard()
+    for k, v in values.items():
+        gb.set(k, v)
+    m = xingque.Module()
+    e = xingque.Evaluator(m)
+    return m, e, gb.build()
+
+
+def test_to_starlark():
+    xingque.register_converter(pathlib.PurePath, str)
+    xingque.register_converter(decimal.Decimal, str)
+    xingque.register_converter(Point, lambda p: {"x": p.x, "y": p.y})
+    try:
+        m, e, gs = eval_with(
+            p=pathlib.PurePosixPath("/a/b"),
+            d=decimal.Decimal("1.50"),
+            pts=[Point(1, 2), Point3(3, 4, 5)],
+        )
+        m.set("q", pathlib.PurePosixPath("c"))
+        ast = xingque.AstModule.parse(
+            "x.star",
+            "[type(p), p + '/c', d, [pt['x'] + pt['y'] for pt in pts], q]",
+        )
+        assert e.eval_module(ast, gs) == ["string", "/a/b/c", "1.50", [3, 7], "c"]
+
+        # the converter of the closest base class wins
+        xingque.register_converter(Point3, lambda p: [p.x, p.y, p.z])
+        _, e, gs = eval_with(pts=[Point(1, 2), Point3(3, 4, 5)])
+        ast = xingque.AstModule.parse("x.star", "pts")
+        assert e.eval_module(ast, gs) == [{"x": 1, "y": 2}, [3, 4, 5]]
+    finally:
+        assert xingque.unregister_converter(pathlib.PurePath)
+        assert xingque.unregister_converter(decimal.Decimal)
+        assert xingque.unregister_converter(Point)
+        xingque.unregister_converter(Point3)
+
+    assert not xingque.unregister_converter(Point)
+    _, e, gs = eval_with(p=Point(1, 2))
+    assert e.eval_module(xingque.AstModule.parse("x.star", "type(p)"), gs) == "pyobject"
+
+
+def test_to_starlark_returning_same_type():
+    xingque.register_converter(Point, lambda p: Point3(p.x, p.y, 0))
+    try:
+        with pytest.raises(ValueError, match="returned another instance"):
+            eval_with(p=Point(1, 2))
+    finally:
+        xingque.unregister_converter(Point)
+
+
+def test_to_starlark_cycle():
+    class A:
+        pass
+
+    class B:
+        pass
+
+    xingque.register_converter(A, lambda a: B())
+    xingque.register_converter(B, lambda b: A())
+    try:
+        with pytest.raises(RecursionError, match="nested too deeply"):
+            eval_with(a=A())
+    finally:
+        xingque.unregister_converter(A)
+        xingque.unregister_converter(B)
+
+
+def test_from_starlark():
+    seen = []
+
+    def struct_to_point(v):
+        seen.append(v)
+        if "z" in dir(v):
+            return NotImplemented
+        return Point(v.x, v.y)
+
+    with pytest.raises(TypeError, match="sl_type is required"):
+        xingque.register_converter(Point, lambda p: {"x": p.x, "y": p.y}, struct_to_point)
+
+    xingque.register_converter(
+        Point, lambda p: {"x": p.x, "y": p.y}, struct_to_point, sl_type="struct"
+    )
+    try:
+        m = xingque.Module()
+        e = xingque.Evaluator(m)
+        gs = xingque.Globals.extended_by([xingque.LibraryExtension.STRUCT_TYPE])
+        text = "p = struct(x = 1, y = 2)\np3 = struct(x = 1, y = 2, z = 3)\nf = lambda: 1\np"
+        result = e.eval_module(xingque.AstModule.parse("x.star", text), gs)
+        assert isinstance(result, Point)
+        assert (result.x, result.y) == (1, 2)
+        assert isinstance(m.get("p"), Point)
+        # values the converter declines are left alone
+        assert isinstance(m.get("p3"), xingque.Value)
+        # and so are values of other types, without consulting the converter
+        seen.clear()
+        assert isinstance(m.get("f"), xingque.Value)
+        assert seen == []
+
+        fm = m.freeze()
+        assert isinstance(fm.get("p"), Point)
+        assert isinstance(fm.get("f"), xingque.FrozenValue)
+    finally:
+        xingque.unregister_converter(Point)
@@ -0,0 +1,198 @@
+import pytest
+
+import xingque
+
+
+DOCS_TEXT = '''
+"""Module summary.
+
+Module details.
+"""
+
+def f(a: int, b: str | None = None, *args: int, c = [1, 2], **kwargs) -> list[str]:
+    """Does f.
+
+    Args:
+        a: the a
+        c: the c
+
+    Returns:
+        some strings
+    """
+    return []
+
+_private = 1
+X: int = 2
+'''
+
+
+def _dialect() -> xingque.Dialect:
+    d = xingque.Dialect.EXTENDED
+    d.enable_types = xingque.DialectTypes.ENABLE
+    return d
+
+
+def test_globals_documentation():
+    docs = xingque.Globals.standard().documentation()
+    assert docs.docs is None
+
+    length = docs.members["len"]
+    assert isinstance(length, xingque.DocFunction)
+    assert "length" in length.docs.summary
+    assert [p.kind for p in length.params] == [
+        xingque.DocParamKind.ARG,
+        xingque.DocParamKind.ONLY_POS_BEFORE,
+    ]
+    assert str(length.ret.typ) == "int"
+
+    assert isinstance(docs.members["True"], xingque.DocProperty)
+
+
+def test_ast_module_documentation():
+    am = xingque.AstModule.parse("test.star", DOCS_TEXT, _dialect())
+    docs = am.documentation()
+
+    assert docs.docs.summary == "Module summary."
+    assert docs.docs.details == "Module details."
+    assert list(docs.members) == ["f", "X"]
+
+    f = docs.members["f"]
+    assert f.docs.summary == "Does f."
+    assert f.docs.details is None
+    assert str(f.ret.typ) == "list[str]"
+    assert f.ret.docs.summary == "some strings"
+
+    params = {p.name: p for p in f.params}
+    assert list(params) == ["a", "b", "*args", "c", "**kwargs"]
+    assert str(params["a"].typ) == "int"
+    assert params["a"].docs.summary == "the a"
+    assert params["a"].default_value is None
+    assert str(params["b"].typ) == "None | str"
+    assert params["b"].default_value == "None"
+    assert params["*args"].kind == xingque.DocParamKind.ARGS
+    assert params["c"].typ.as_name() is None
+    assert params["c"].default_value == "[1, 2]"
+    assert params["c"].docs.summary == "the c"
+    assert params["**kwargs"].kind == xingque.DocParamKind.KWARGS
+
+    x = docs.members["X"]
+    assert isinstance(x, xingque.DocProperty)
+    assert str(x.typ) == "int"
+
+
+def test_ast_module_documentation_types():
+    calls: list = []
+
+    class Spy:
+        def __getattr__(self, name):
+            calls.append(name)
+            return int
+
+        def __getitem__(self, key):
+            calls.append(key)
+            return int
+
+        def __or__(self, other):
+            calls.append(other)
+            return int
+
+    gb = xingque.GlobalsBuilder.standard()
+    gb.set("spy", Spy())
+    gb.struct("ns", lambda b: b.struct("inner", lambda b2: b2.set("t", None)))
+    text = """
+def f(
+    a: dict[str, list[int]],
+    b: (int, str),
+    c: ns.inner.t,
+    d: spy[int],
+    e: spy.t,
+    f: spy | int,
+    g: unknown,
+): pass
+"""
+    am = xingque.AstModule.parse("test.star", text, _dialect())
+    params = am.documentation(gb.build()).members["f"].params
+    assert [str(p.typ) for p in params] == [
+        "dict[str, list[int]]",
+        "(int, str)",
+        "None",
+        "typing.Any",
+        "typing.Any",
+        "typing.Any",
+        "typing.Any",
+    ]
+    # the annotations are resolved without running any code
+    assert calls == []
+
+
+def test_frozen_module_documentation():
+    am = xingque.AstModule.parse("test.star", DOCS_TEXT, _dialect())
+    m = xingque.Module()
+    e = xingque.Evaluator(m)
+    e.eval_module(am, xingque.Globals.standard())
+    docs = m.freeze().documentation()
+
+    assert docs.docs.summary == "Module summary."
+    assert set(docs.members) == {"f", "X"}
+    f = docs.members["f"]
+    assert [p.name for p in f.params] == ["a", "b", "*args", "c", "**kwargs"]
+    assert f.ret.docs.summary == "some strings"
+
+
+def test_render_docs_markdown():
+    gb = xingque.GlobalsBuilder.standard()
+    list_type = dict(xingque.Globals.standard())["list"]
+
+    def foo(b):
+        b.set("answer", 42)
+        b.set("List", list_type)
+        b.struct("inner", lambda b2: b2.set("z", 1))
+
+    gb.struct("foo", foo)
+    pages = xingque.render_docs_markdown(gb.build(), "api/build")
+
+    # types get pages of their own too
+    types = {"bool", "dict", "float", "int", "list", "range", "str", "tuple", "type"}
+    assert set(pages) == {
+        "api/build/globals.md",
+        "api/build/foo.md",
+        "api/build/foo/List.md",
+        "api/build/foo/inner.md",
+    } | {f"api/build/{x}.md" for x in types}
+
+    globals_page = pages["api/build/globals.md"]
+    assert globals_page.startswith("# globals\n")
+    assert "## len\n\n```python\ndef len(" in globals_page
+    assert "## foo\n" not in globals_page
+
+    foo_page = pages["api/build/foo.md"]
+    assert foo_page.startswith("# `foo` type\n")
+    assert "## foo.answer\n" in foo_page
+    assert "foo.inner" not in foo_page
+
+    assert "## foo.inner.z\n" in pages["api/build/foo/inner.md"]
+
+    # and are listed where they are defined
+    assert "## foo.List\n" in foo_page
+    list_page = pages["api/build/foo/List.md"]
+    assert list_page.startswith("# `foo.List` type\n")
+    assert "## foo.List.append\n" in list_page
+    assert "## list.append\n" in pages["api/build/list.md"]
+
+    gb = xingque.GlobalsBuilder.standard()
+    gb.struct("globals", lambda b: b.set("x", 1))
+    with pytest.raises(ValueError, match="would overwrite the page api/globals.md"):
+        xingque.render_docs_markdown(gb.build(), "api")
+
+
+def test_render_docs_markdown_frozen_module():
+    am = xingque.AstModule.parse("test.star", DOCS_TEXT, _dialect())
+    m = xingque.Module()
+    e = xingque.Evaluator(m)
+    e.eval_module(am, xingque.Globals.standard())
+    pages = xingque.render_docs_markdown(m.freeze(), "")
+
+    assert list(pages) == ["globals.md"]
+    page = pages["globals.md"]
+    assert page.startswith("# globals\n\nModule summary.\n\nModule details.")
+    assert "#### Parameters\n\n* `a`: the a\n* `c`: the c\n" in page
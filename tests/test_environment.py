@@ -1,4 +1,5 @@
 import gc
+from typing import Optional
 
 import pytest
 import xingque
@@ -85,6 +86,120 @@ def test_globals_builder():
             assert v is kv[k]
 
 
+def _greet(
+    name: str,
+    /,
+    greeting: str = "Hello",
+    *rest: int,
+    punct: Optional[str] = None,
+    **kwargs,
+) -> str:
+    """Greets someone.
+
+    Args:
+        name: Who to greet.
+        punct: The punctuation.
+    """
+    return f"{greeting}, {name}{punct or ''}{list(rest)}{kwargs}"
+
+
+def test_globals_builder_set_function():
+    gb = xingque.GlobalsBuilder.standard()
+    gb.set_function("greet", _greet)
+    gb.set_function("add", lambda a, b=2: a + b, doc="Adds two numbers.")
+    g = gb.build()
+
+    def eval(text: str) -> object:
+        e = xingque.Evaluator()
+        return e.eval_module(xingque.AstModule.parse("test.star", text), g)
+
+    assert eval('greet("x")') == "Hello, x[]{}"
+    assert eval('greet("x", "Hi", 1, 2, punct="!", y=3)') == "Hi, x![1, 2]{'y': 3}"
+    assert eval('greet("x", punct="?")') == "Hello, x?[]{}"
+    assert eval("add(1)") == 3
+    assert eval("add(b=1, a=2)") == 3
+    assert eval("type(greet)") == "function"
+    assert eval("greet") is _greet
+
+    # argument binding is done by Starlark
+    with pytest.raises(xingque.EvalError) as excinfo:
+        eval('greet(name="x")')
+    assert excinfo.value.kind == xingque.ErrorKind.FUNCTION
+    assert "Missing positional-only parameter `name`" in excinfo.value.message
+    assert excinfo.value.span.resolve_span().begin.column == 0
+
+    docs = g.documentation().members["greet"]
+    assert docs.docs.summary == "Greets someone."
+    assert [(p.name, p.default_value) for p in docs.params] == [
+        ("name", None),
+        (None, None),
+        ("greeting", "'Hello'"),
+        ("*rest", None),
+        ("punct", "None"),
+        ("**kwargs", None),
+    ]
+    assert docs.params[1].kind == xingque.DocParamKind.ONLY_POS_BEFORE
+    assert str(docs.params[0].typ) == "str"
+    assert docs.params[0].docs.summary == "Who to greet."
+    assert str(docs.params[3].typ) == "int"
+    assert str(docs.params[4].typ) == "None | str"
+    assert str(docs.ret.typ) == "str"
+    assert g.documentation().members["add"].docs.summary == "Adds two numbers."
+
+    # the typechecker knows about the signature too
+    d = xingque.Dialect.EXTENDED
+    d.enable_types = xingque.DialectTypes.ENABLE
+
+    def typecheck(text: str) -> list:
+        errors, _, _, _ = xingque.AstModule.parse("test.star", text, d).typecheck(g)
+        return [(e.message, e.span.source_span) for e in errors]
+
+    assert typecheck("def f() -> str:\n    return greet('x', 'hi', 1, punct='!')\n") == []
+    # positional-only parameter
+    assert typecheck("def f():\n    return greet(1)\n") == [
+        ("Expected type `str` but got `int`", "1"),
+    ]
+    # named-only parameter
+    assert typecheck("def f():\n    return greet('x', punct=1)\n") == [
+        ("Expected type `None | str` but got `int`", "punct=1"),
+    ]
+    # *args
+    assert typecheck("def f():\n    return greet('x', 'hi', 'no')\n") == [
+        ("Expected type `int` but got `str`", "'no'"),
+    ]
+    # return type
+    assert typecheck("def f() -> int:\n    return greet('x')\n") == [
+        ("Expected type `int` but got `str`", "return greet('x')"),
+    ]
+
+
+def test_globals_builder_set_function_signature():
+    import inspect
+
+    def f(*args, **kwargs):
+        return (args, kwargs)
+
+    gb = xingque.GlobalsBuilder()
+    gb.struct(
+        "ns",
+        lambda b: b.set_function(
+            "f",
+            f,
+            signature=inspect.signature(lambda a, *, b=1: None),
+        ),
+    )
+    g = gb.build()
+
+    e = xingque.Evaluator()
+    assert e.eval_module(xingque.AstModule.parse("test.star", "ns.f(1, b=2)"), g) == (
+        (1,),
+        {"b": 2},
+    )
+    with pytest.raises(xingque.EvalError):
+        e = xingque.Evaluator()
+        e.eval_module(xingque.AstModule.parse("test.star", "ns.f(1, 2)"), g)
+
+
 def test_module_extra_value():
     m = xingque.Module()
     assert m.extra_value is None
@@ -0,0 +1,277 @@
+import pytest
+import xingque
+
+
+def test_parse_error():
+    with pytest.raises(xingque.ParseError) as excinfo:
+        xingque.AstModule.parse("test.star", "def f(:\n    pass\n")
+
+    e = excinfo.value
+    assert isinstance(e, xingque.StarlarkError)
+    assert isinstance(e.kind, xingque.ErrorKind)
+    assert e.span is not None
+    assert e.span.filename == "test.star"
+    assert e.span.resolve().begin_file_line().line == 0
+    assert e.call_stack.is_empty()
+
+
+def test_eval_error():
+    text = """
+def f(x):
+    return x + "a"
+
+f(1)
+"""
+
+    am = xingque.AstModule.parse("test.star", text)
+    e = xingque.Evaluator()
+    with pytest.raises(xingque.EvalError) as excinfo:
+        e.eval_module(am, xingque.Globals.standard())
+
+    err = excinfo.value
+    assert isinstance(err, xingque.StarlarkError)
+    assert err.kind == xingque.ErrorKind.VALUE
+    assert err.span is not None
+    assert err.span.source_span == 'x + "a"'
+    assert "+" in err.message
+    assert err.message in str(err)
+
+
+def test_eval_error_call_stack():
+    text = """
+def outer(x):
+    def inner(y):
+        return y + "a"
+    return inner(x)
+
+outer(1)
+"""
+
+    am = xingque.AstModule.parse("test.star", text)
+    e = xingque.Evaluator()
+    with pytest.raises(xingque.EvalError) as excinfo:
+        e.eval_module(am, xingque.Globals.standard())
+
+    err = excinfo.value
+    assert err.span.source_span == 'y + "a"'
+    frames = err.call_stack.frames
+    assert [(f.name, f.location.source_span) for f in frames] == [
+        ("outer", "outer(1)"),
+        ("inner", "inner(x)"),
+    ]
+
+
+def test_fail_error():
+    am = xingque.AstModule.parse("test.star", "fail('oops')")
+    e = xingque.Evaluator()
+    with pytest.raises(xingque.EvalError) as excinfo:
+        e.eval_module(am, xingque.Globals.standard())
+
+    assert excinfo.value.kind == xingque.ErrorKind.FAIL
+    assert "oops" in excinfo.value.message
+
+
+class ConfigMapping:
+    def __getitem__(self, key: str) -> object:
+        raise KeyError(key)
+
+
+def test_subscript_error():
+    gb = xingque.GlobalsBuilder.standard()
+    gb.set("seq", range(3))
+    gb.set("mapping", ConfigMapping())
+    g = gb.build()
+    e = xingque.Evaluator()
+
+    with pytest.raises(xingque.EvalError) as excinfo:
+        e.eval_module(xingque.AstModule.parse("test.star", "seq[5]"), g)
+    assert excinfo.value.kind == xingque.ErrorKind.VALUE
+    assert "Index `5` is out of bound" in excinfo.value.message
+
+    with pytest.raises(xingque.EvalError) as excinfo:
+        e.eval_module(xingque.AstModule.parse("test.star", 'mapping["x"]'), g)
+    assert excinfo.value.kind == xingque.ErrorKind.VALUE
+    assert 'Key `"x"` was not found' in excinfo.value.message
+
+
+def test_eval_function_error():
+    am = xingque.AstModule.parse("test.star", "def f(x):\n    return x // 0\n")
+    e = xingque.Evaluator()
+    e.eval_module(am, xingque.Globals.standard())
+
+    with pytest.raises(xingque.EvalError) as excinfo:
+        e.eval_function(e.module.get("f"), 1)
+    assert excinfo.value.span is not None
+
+
+class ConfigError(Exception):
+    pass
+
+
+def test_py_exception_propagation():
+    def validate(x):
+        if x < 0:
+            raise ConfigError("negative", x)
+        return x
+
+    text = """
+def check(x):
+    return validate(x)
+
+check(1)
+check(-1)
+"""
+
+    am = xingque.AstModule.parse("test.star", text)
+    gb = xingque.GlobalsBuilder.standard()
+    gb.set("validate", validate)
+    e = xingque.Evaluator()
+    with pytest.raises(ConfigError) as excinfo:
+        e.eval_module(am, gb.build())
+
+    err = excinfo.value
+    assert err.args == ("negative", -1)
+    assert err.__traceback__ is not None
+
+    cause = err.__cause__
+    assert isinstance(cause, xingque.EvalError)
+    assert cause.span is not None
+    assert cause.span.source_span == "validate(x)"
+    frames = cause.call_stack.frames
+    assert len(frames) == 2
+    assert frames[0].name == "check"
+    assert frames[0].location.source_span == "check(-1)"
+    assert frames[1].location.source_span == "validate(x)"
+
+
+def test_py_exception_propagation_operator():
+    class Foo:
+        def __add__(self, other):
+            raise ConfigError("add")
+
+    am = xingque.AstModule.parse("test.star", "foo + 1")
+    gb = xingque.GlobalsBuilder.standard()
+    gb.set("foo", Foo())
+    e = xingque.Evaluator()
+    with pytest.raises(ConfigError) as excinfo:
+        e.eval_module(am, gb.build())
+    assert isinstance(excinfo.value.__cause__, xingque.EvalError)
+
+
+def test_py_exception_propagation_eval_function():
+    def boom():
+        raise KeyError("boom")
+
+    e = xingque.Evaluator()
+    with pytest.raises(KeyError):
+        e.eval_function(boom)
+
+
+def test_iteration_error():
+    def gen():
+        yield 1
+        raise ConfigError("broken iterator")
+
+    gb = xingque.GlobalsBuilder.standard()
+    gb.set("g", gen())
+    gb.set("obj", object())
+    g = gb.build()
+    e = xingque.Evaluator()
+
+    text = """
+seen = []
+def f():
+    for x in g:
+        seen.append(x)
+f()
+after = True
+"""
+    m = xingque.Module()
+    e = xingque.Evaluator(m)
+    with pytest.raises(ConfigError, match="broken iterator") as excinfo:
+        e.eval_module(xingque.AstModule.parse("test.star", text), g)
+    assert isinstance(excinfo.value.__cause__, xingque.EvalError)
+    # the loop ends early, and the evaluation fails when it completes
+    assert m.get("seen") == [1]
+    assert m.get("after") is True
+
+    # or at the next statement, for evaluators checking limits
+    gb = xingque.GlobalsBuilder.standard()
+    gb.set("g", gen())
+    m = xingque.Module()
+    e = xingque.Evaluator(m)
+    e.set_step_limit(1000)
+    with pytest.raises(ConfigError, match="broken iterator") as excinfo:
+        e.eval_module(xingque.AstModule.parse("test.star", text), gb.build())
+    assert isinstance(excinfo.value.__cause__, xingque.EvalError)
+    assert m.get("seen") == [1]
+    assert m.get("after") is None
+
+    e = xingque.Evaluator()
+
+    with pytest.raises(TypeError, match="not iterable") as excinfo:
+        e.eval_module(xingque.AstModule.parse("test.star", "[x for x in obj]"), g)
+    assert isinstance(excinfo.value.__cause__, xingque.EvalError)
+
+
+def test_load_error():
+    text = "x = 1\nload('missing.star', 'y')\n"
+    e = xingque.Evaluator()
+    e.set_loader(xingque.DictFileLoader({}))
+    with pytest.raises(xingque.LoadError) as excinfo:
+        e.eval_module(xingque.AstModule.parse("test.star", text), xingque.Globals.standard())
+
+    err = excinfo.value
+    assert isinstance(err, xingque.EvalError)
+    assert err.kind == xingque.ErrorKind.OTHER
+    assert err.module_id == "missing.star"
+    assert err.import_stack == ["test.star"]
+    assert err.cycle is None
+    assert "missing.star" in err.message
+    assert err.span.filename == "test.star"
+    assert err.span.resolve_span().begin.line == 1
+
+
+def test_load_error_from_python_loader():
+    class Loader:
+        def load(self, path: str) -> xingque.FrozenModule:
+            raise ConfigError("no modules here")
+
+    text = "load('dep.star', 'y')\n"
+    e = xingque.Evaluator()
+    e.set_loader(Loader())
+    with pytest.raises(xingque.LoadError) as excinfo:
+        e.eval_module(xingque.AstModule.parse("test.star", text), xingque.Globals.standard())
+
+    err = excinfo.value
+    assert err.module_id == "dep.star"
+    assert err.import_stack == ["test.star"]
+    assert isinstance(err.__cause__, ConfigError)
+
+
+def test_load_error_from_loaded_module(tmp_path):
+    (tmp_path / "bad_eval.star").write_text("x = 1 // 0\n")
+    (tmp_path / "bad_parse.star").write_text("def f(:\n")
+    (tmp_path / "mid.star").write_text("load('bad_eval.star', 'x')\n")
+    loader = xingque.FilesystemLoader([tmp_path], xingque.Globals.standard())
+    e = xingque.Evaluator()
+    e.set_loader(loader)
+
+    with pytest.raises(xingque.LoadError, match="division by zero") as excinfo:
+        e.eval_module(
+            xingque.AstModule.parse("test.star", "load('mid.star', 'x')\n"),
+            xingque.Globals.standard(),
+        )
+    err = excinfo.value
+    assert err.module_id == "bad_eval.star"
+    assert err.import_stack == ["test.star", str(tmp_path.resolve() / "mid.star")]
+    assert isinstance(err.__cause__, xingque.EvalError)
+    assert err.__cause__.span.source_span == "1 // 0"
+
+    with pytest.raises(xingque.LoadError) as excinfo:
+        e.eval_module(
+            xingque.AstModule.parse("test.star", "load('bad_parse.star', 'f')\n"),
+            xingque.Globals.standard(),
+        )
+    assert excinfo.value.module_id == "bad_parse.star"
+    assert isinstance(excinfo.value.__cause__, xingque.ParseError)
@@ -0,0 +1,67 @@
+import xingque
+
+
+class _Rules(xingque.StarlarkLibrary):
+    """Build rules."""
+
+    VERSION = "1.0"
+
+    def __init__(self, prefix: str = "//") -> None:
+        self.prefix = prefix
+
+    def cc_library(self, name: str, srcs: list = []) -> str:
+        """Declares a C library."""
+        return self.prefix + ":" + name
+
+    @xingque.builtin(name="glob", doc="Globs files.")
+    def _glob(self, pattern: str) -> list:
+        return [pattern]
+
+    def _not_exposed(self) -> None:
+        pass
+
+    @staticmethod
+    @xingque.builtin
+    def helper(x: int) -> int:
+        return x + 1
+
+    class native(xingque.StarlarkLibrary):
+        ANSWER = 42
+
+        def package_name(self) -> str:
+            return "pkg"
+
+
+def _eval(globals: xingque.Globals, expr: str) -> object:
+    m = xingque.Module()
+    e = xingque.Evaluator(m)
+    return e.eval_module(xingque.AstModule.parse("test.star", expr), globals)
+
+
+def test_starlark_library():
+    g = _Rules("//foo").build()
+    names = set(g.names())
+    assert {"VERSION", "cc_library", "glob", "helper", "native", "len"} <= names
+    assert "_glob" not in names
+    assert "_not_exposed" not in names
+    assert g.docstring == "Build rules."
+
+    assert _eval(g, 'cc_library("a")') == "//foo:a"
+    assert _eval(g, 'glob("*.c")') == ["*.c"]
+    assert _eval(g, "helper(1)") == 2
+    assert _eval(g, "native.ANSWER") == 42
+    assert _eval(g, "native.package_name()") == "pkg"
+    assert _eval(g, "VERSION") == "1.0"
+
+    docs = g.documentation()
+    assert docs.members["glob"].docs.summary == "Globs files."
+    assert docs.members["cc_library"].docs.summary == "Declares a C library."
+    assert [p.name for p in docs.members["cc_library"].params] == ["name", "srcs"]
+
+
+def test_starlark_library_register():
+    gb = xingque.GlobalsBuilder()
+    gb.struct("rules", _Rules().register)
+    g = gb.build()
+    assert list(g.names()) == ["rules"]
+    assert _eval(g, 'rules.cc_library("a")') == "//:a"
@@ -0,0 +1,255 @@
+import _thread
+import pathlib
+import threading
+
+import pytest
+import xingque
+
+LOOP = """
+def spin(n):
+    for i in range(n):
+        x = i
+    return n
+
+def outer():
+    return spin(1000000000)
+
+outer()
+"""
+
+
+def test_step_limit():
+    e = xingque.Evaluator()
+    e.set_step_limit(100)
+    ast = xingque.AstModule.parse("loop.star", LOOP)
+    with pytest.raises(xingque.StarlarkResourceExhausted) as excinfo:
+        e.eval_module(ast, xingque.Globals.standard())
+
+    err = excinfo.value
+    assert isinstance(err, xingque.EvalError)
+    assert err.message == "step limit of 100 exceeded"
+    assert err.span.filename == "loop.star"
+    assert err.span.resolve_span().begin.line == 3
+    assert [f.name for f in err.call_stack.frames] == ["outer", "spin"]
+
+    # the evaluator stays usable, with a fresh budget
+    assert e.eval_module(xingque.AstModule.parse("x.star", "1"), xingque.Globals.standard()) == 1
+
+
+def test_step_limit_per_evaluation():
+    m = xingque.Module()
+    e = xingque.Evaluator(m)
+    e.set_step_limit(10)
+    gs = xingque.Globals.standard()
+    for _ in range(5):
+        assert e.eval_module(xingque.AstModule.parse("a.star", "x = 1\nx + 1"), gs) == 2
+
+    e.set_step_limit(None)
+    ast = xingque.AstModule.parse("b.star", "def f():\n  for i in range(10):\n    x = i\nf()")
+    assert e.eval_module(ast, gs) is None
+
+
+def test_timeout():
+    e = xingque.Evaluator()
+    e.set_timeout(0.1)
+    ast = xingque.AstModule.parse("loop.star", LOOP)
+    with pytest.raises(xingque.StarlarkResourceExhausted, match="timeout"):
+        e.eval_module(ast, xingque.Globals.standard())
+
+    with pytest.raises(ValueError):
+        xingque.Evaluator().set_timeout(-1)
+
+
+def test_limits_with_python_callbacks():
+    m = xingque.Module()
+    e = xingque.Evaluator(m)
+    e.set_step_limit(50)
+    ast = xingque.AstModule.parse("f.star", "def f(n):\n  for i in range(n):\n    x = i")
+    e.eval_module(ast, xingque.Globals.standard())
+
+    def call_back():
+        # callables called back from Starlark are evaluated with the calling
+        # evaluator, so they share its budget
+        m.get("f")(1000)
+
+    gb = xingque.GlobalsBuilder.standard()
+    gb.set("call_back", call_back)
+    ast = xingque.AstModule.parse("top.star", "call_back()")
+    with pytest.raises(xingque.StarlarkResourceExhausted) as excinfo:
+        e.eval_module(ast, gb.build())
+    assert excinfo.value.span.filename == "f.star"
+
+
+def test_heap_limit():
+    e = xingque.Evaluator()
+    e.set_heap_limit(1 << 20)
+    ast = xingque.AstModule.parse(
+        "mem.star",
+        "def grow():\n  s = []\n  for i in range(1000000):\n    s.append('x' * 1000)\ngrow()",
+    )
+    with pytest.raises(xingque.StarlarkResourceExhausted) as excinfo:
+        e.eval_module(ast, xingque.Globals.standard())
+    assert excinfo.value.message == f"heap limit of {1 << 20} bytes exceeded"
+    assert excinfo.value.span.resolve_span().begin.line == 3
+
+    e = xingque.Evaluator()
+    e.set_heap_limit(1 << 20)
+    assert e.eval_module(xingque.AstModule.parse("ok.star", "len('x' * 1000)"), xingque.Globals.standard()) == 1000
+
+
+def test_heap_limit_single_allocation():
+    # the limit is checked after the fact: a single operation allocates in
+    # full, and is caught at the next statement or when the evaluation
+    # completes
+    for text in ["x = 'x' * (16 << 20)\ny = 1", "x = 'x' * (16 << 20)"]:
+        e = xingque.Evaluator()
+        e.set_heap_limit(1 << 20)
+        with pytest.raises(xingque.StarlarkResourceExhausted) as excinfo:
+            e.eval_module(xingque.AstModule.parse("big.star", text), xingque.Globals.standard())
+        assert excinfo.value.message == f"heap limit of {1 << 20} bytes exceeded"
+
+    e = xingque.Evaluator()
+    ast = xingque.AstModule.parse("f.star", "lambda: 'x' * (16 << 20)")
+    f = e.eval_module(ast, xingque.Globals.standard())
+    e.set_heap_limit(1 << 20)
+    with pytest.raises(xingque.StarlarkResourceExhausted):
+        e.eval_function(f)
+
+
+def test_cancellation():
+    token = xingque.CancellationToken()
+    assert not token.cancelled
+    e = xingque.Evaluator()
+    e.set_cancellation_token(token)
+    gb = xingque.GlobalsBuilder.standard()
+    gb.set("cancel", token.cancel)
+    ast = xingque.AstModule.parse("c.star", "x = 1\ncancel()\ny = 2\n")
+    with pytest.raises(xingque.StarlarkCancelled) as excinfo:
+        e.eval_module(ast, gb.build())
+    assert token.cancelled
+    assert excinfo.value.message == "evaluation was cancelled"
+    assert excinfo.value.span.resolve_span().begin.line == 2
+
+
+def test_cancellation_from_another_thread():
+    token = xingque.CancellationToken()
+    e = xingque.Evaluator()
+    e.set_cancellation_token(token)
+    timer = threading.Timer(0.1, token.cancel)
+    timer.start()
+    ast = xingque.AstModule.parse("loop.star", LOOP)
+    with pytest.raises(xingque.StarlarkCancelled):
+        e.eval_module(ast, xingque.Globals.standard())
+    timer.join()
+
+
+def test_cancellation_by_signal():
+    token = xingque.CancellationToken(check_signals=True)
+    e = xingque.Evaluator()
+    e.set_cancellation_token(token)
+    timer = threading.Timer(0.1, _thread.interrupt_main)
+    timer.start()
+    ast = xingque.AstModule.parse("loop.star", LOOP)
+    with pytest.raises(KeyboardInterrupt) as excinfo:
+        e.eval_module(ast, xingque.Globals.standard())
+    timer.join()
+    assert token.cancelled
+    assert isinstance(excinfo.value.__cause__, xingque.StarlarkCancelled)
+
+
+def test_limits_of_loaded_modules(tmp_path: pathlib.Path):
+    (tmp_path / "loop.star").write_text(LOOP)
+    (tmp_path / "big.star").write_text("x = 'x' * (16 << 20)")
+    gs = xingque.Globals.standard()
+
+    e = xingque.Evaluator()
+    e.set_step_limit(100)
+    e.set_loader(xingque.FilesystemLoader([tmp_path], gs))
+    ast = xingque.AstModule.parse("top.star", "load('loop.star', 'outer')")
+    with pytest.raises(xingque.StarlarkResourceExhausted) as excinfo:
+        e.eval_module(ast, gs)
+    assert excinfo.value.span.filename == str(tmp_path.resolve() / "loop.star")
+
+    # the heap of each loaded module is limited on its own
+    e = xingque.Evaluator()
+    e.set_heap_limit(1 << 20)
+    e.set_loader(xingque.FilesystemLoader([tmp_path], gs))
+    ast = xingque.AstModule.parse("top.star", "load('big.star', 'x')")
+    with pytest.raises(xingque.StarlarkResourceExhausted, match="heap limit"):
+        e.eval_module(ast, gs)
+
+
+def test_limits_of_load_graph(tmp_path: pathlib.Path):
+    (tmp_path / "loop.star").write_text(LOOP)
+    (tmp_path / "c.star").write_text("cancel()\nx = 1")
+
+    # modules loaded from Python code called by Starlark share the budget of
+    # the calling evaluation, on the worker threads too
+    token = xingque.CancellationToken()
+    gb = xingque.GlobalsBuilder.standard()
+    gb.set("cancel", token.cancel)
+    loader = xingque.FilesystemLoader([tmp_path], gb.build())
+    gb = xingque.GlobalsBuilder.standard()
+    gb.set("load_graph", loader.load_graph)
+    gs = gb.build()
+
+    e = xingque.Evaluator()
+    e.set_step_limit(100)
+    ast = xingque.AstModule.parse("top.star", "load_graph(['loop.star'])")
+    with pytest.raises(xingque.StarlarkResourceExhausted, match="step limit"):
+        e.eval_module(ast, gs)
+
+    e = xingque.Evaluator()
+    e.set_cancellation_token(token)
+    ast = xingque.AstModule.parse("top.star", "load_graph(['c.star'])")
+    with pytest.raises(xingque.StarlarkCancelled):
+        e.eval_module(ast, gs)
+
+    # without limits, nothing stops the loaded modules
+    assert loader.load_graph(["c.star"])[str(tmp_path.resolve() / "c.star")].get("x") == 1
+
+
+def test_limits_of_code_defined_before():
+    gs = xingque.Globals.standard()
+    m = xingque.Module()
+    e = xingque.Evaluator(m)
+    e.eval_module(xingque.AstModule.parse("loop.star", LOOP.replace("outer()\n", "")), gs)
+    fm = m.freeze()
+
+    # functions defined before the limits were set are subject to them
+    e = xingque.Evaluator(xingque.Module())
+    e.eval_module(xingque.AstModule.parse("f.star", "def f():\n  for i in range(1000):\n    x = i"), gs)
+    e.set_step_limit(100)
+    with pytest.raises(xingque.StarlarkResourceExhausted):
+        e.eval_module(xingque.AstModule.parse("top.star", "f()"), gs)
+
+    # and so are functions of modules loaded in any way
+    e = xingque.Evaluator()
+    e.set_step_limit(100)
+    e.set_loader(xingque.DictFileLoader({"loop.star": fm}))
+    ast = xingque.AstModule.parse("top.star", "load('loop.star', 'outer')\nouter()")
+    with pytest.raises(xingque.StarlarkResourceExhausted) as excinfo:
+        e.eval_module(ast, gs)
+    assert excinfo.value.span.filename == "loop.star"
+
+
+def test_limits_of_python_loaders():
+    gs = xingque.Globals.standard()
+
+    class Loader:
+        def load(self, path):
+            # evaluated with an evaluator of its own, without limits
+            m = xingque.Module()
+            xingque.Evaluator(m).eval_module(xingque.AstModule.parse(path, LOOP), gs)
+            return m.freeze()
+
+    e = xingque.Evaluator()
+    e.set_timeout(0.1)
+    e.set_loader(Loader())
+    ast = xingque.AstModule.parse("top.star", "load('loop.star', 'outer')")
+    with pytest.raises(xingque.LoadError) as excinfo:
+        e.eval_module(ast, gs)
+    cause = excinfo.value.__cause__
+    assert isinstance(cause, xingque.StarlarkResourceExhausted)
+    assert "timeout" in cause.message
+    assert cause.span.filename == "loop.star"
@@ -0,0 +1,256 @@
+import pathlib
+import threading
+
+import pytest
+import xingque
+
+
+def write_files(root: pathlib.Path, files: dict) -> None:
+    for name, content in files.items():
+        path = root / name
+        path.parent.mkdir(parents=True, exist_ok=True)
+        path.write_text(content)
+
+
+def test_filesystem_loader(tmp_path: pathlib.Path):
+    write_files(
+        tmp_path / "main",
+        {
+            "main.star": "load('//lib:defs.star', 'ab')\nresult = ab * 2\n",
+            "lib/defs.star": (
+                "load(':a.star', 'a')\n"
+                "load('//lib/sub:b.star', 'b')\n"
+                "ab = a * b\n"
+            ),
+            "lib/a.star": "a = 7",
+        },
+    )
+    write_files(
+        tmp_path / "extra",
+        {
+            "lib/sub/b.star": "load('//:c.star', 'c')\nb = c\n",
+            "c.star": "c = 3",
+        },
+    )
+
+    loader = xingque.FilesystemLoader(
+        [tmp_path / "main", str(tmp_path / "extra")],
+        xingque.Globals.standard(),
+    )
+    assert loader.roots == [str(tmp_path / "main"), str(tmp_path / "extra")]
+    with pytest.raises(xingque.LoadError, match="cannot find") as excinfo:
+        loader.load("//lib:nonexistent.star")
+    assert excinfo.value.module_id == "//lib:nonexistent.star"
+    assert excinfo.value.import_stack == []
+
+    # labels relative to the first root don't search the other roots
+    with pytest.raises(xingque.EvalError):
+        loader.load("lib/sub/b.star")
+
+    # it's fine to load the same module multiple times
+    assert loader.load("//:main.star").get("result") == 42
+    assert loader.load("main.star").get("result") == 42
+
+    # and as the loader of an evaluator
+    m = xingque.Module()
+    e = xingque.Evaluator(m)
+    e.set_loader(loader)
+    ast = xingque.AstModule.parse("x.star", "load('//lib:defs.star', 'ab')\nab + 1")
+    assert e.eval_module(ast, xingque.Globals.standard()) == 22
+
+
+def test_filesystem_loader_outside_roots(tmp_path: pathlib.Path):
+    write_files(
+        tmp_path,
+        {
+            "root/sub/a.star": "a = 1",
+            "secret.star": "secret = 1",
+        },
+    )
+    (tmp_path / "root" / "link.star").symlink_to(tmp_path / "secret.star")
+    loader = xingque.FilesystemLoader([tmp_path / "root"], xingque.Globals.standard())
+    assert loader.load("sub/a.star").get("a") == 1
+
+    for label in [
+        "../secret.star",
+        "sub/../../secret.star",
+        "//..:secret.star",
+        "//sub:../../secret.star",
+        str(tmp_path / "secret.star"),
+    ]:
+        with pytest.raises(xingque.LoadError, match="without `..`"):
+            loader.load(label)
+    with pytest.raises(xingque.LoadError, match="outside of the roots"):
+        loader.load("link.star")
+
+
+def test_filesystem_loader_cache(tmp_path: pathlib.Path):
+    write_files(tmp_path, {"a.star": "a = 1"})
+    loader = xingque.FilesystemLoader([tmp_path], xingque.Globals.standard())
+    assert loader.load("a.star").get("a") == 1
+
+    write_files(tmp_path, {"a.star": "a = 2"})
+    assert loader.load("./a.star").get("a") == 1
+    loader.clear_cache()
+    assert loader.load("a.star").get("a") == 2
+
+
+def test_filesystem_loader_cycle(tmp_path: pathlib.Path):
+    write_files(
+        tmp_path,
+        {
+            "a.star": "load('b.star', 'b')\na = 1",
+            "b.star": "load('c.star', 'c')\nb = 1",
+            "c.star": "load('b.star', 'b')\nc = 1",
+        },
+    )
+    loader = xingque.FilesystemLoader([tmp_path], xingque.Globals.standard())
+    e = xingque.Evaluator()
+    e.set_loader(loader)
+    ast = xingque.AstModule.parse("top.star", "load('a.star', 'a')")
+    with pytest.raises(xingque.LoadError) as excinfo:
+        e.eval_module(ast, xingque.Globals.standard())
+
+    err = excinfo.value
+    real_path = tmp_path.resolve()
+    b, c = str(real_path / "b.star"), str(real_path / "c.star")
+    assert err.module_id == "b.star"
+    assert err.cycle == [b, c, b]
+    assert err.message == f"load cycle detected: {b} -> {c} -> {b}"
+    assert err.import_stack == ["top.star", str(real_path / "a.star"), b, c]
+    assert err.span.filename == c
+    assert err.span.resolve_span().begin.line == 0
+
+
+def test_filesystem_loader_concurrent_loads(tmp_path: pathlib.Path):
+    write_files(tmp_path, {"slow.star": "evaluated()\nx = 1"})
+    entered = threading.Event()
+    release = threading.Event()
+    evaluations = []
+
+    def evaluated():
+        evaluations.append(None)
+        entered.set()
+        assert release.wait(10)
+
+    gb = xingque.GlobalsBuilder.standard()
+    gb.set("evaluated", evaluated)
+    loader = xingque.FilesystemLoader([tmp_path], gb.build())
+    results = []
+    threads = [threading.Thread(target=lambda: results.append(loader.load("slow.star"))) for _ in range(2)]
+    threads[0].start()
+    assert entered.wait(10)
+    # the second load waits for the module being evaluated by the first
+    threads[1].start()
+    threads[1].join(0.2)
+    assert threads[1].is_alive()
+    release.set()
+    for t in threads:
+        t.join(10)
+
+    assert len(evaluations) == 1
+    assert [m.get("x") for m in results] == [1, 1]
+
+
+def test_filesystem_loader_concurrent_cycle(tmp_path: pathlib.Path):
+    # each thread evaluates one module of the cycle, then waits for the other
+    barrier = threading.Barrier(2)
+    synced = []
+
+    def sync():
+        if not synced:
+            barrier.wait(10)
+            synced.append(None)
+
+    write_files(
+        tmp_path,
+        {
+            "a.star": "sync()\nload('b.star', 'b')\na = 1",
+            "b.star": "sync()\nload('a.star', 'a')\nb = 1",
+        },
+    )
+    gb = xingque.GlobalsBuilder.standard()
+    gb.set("sync", sync)
+    loader = xingque.FilesystemLoader([tmp_path], gb.build())
+    errors = []
+
+    def load(label):
+        try:
+            loader.load(label)
+        except xingque.LoadError as e:
+            errors.append(e)
+
+    threads = [threading.Thread(target=load, args=(x,)) for x in ["a.star", "b.star"]]
+    for t in threads:
+        t.start()
+    for t in threads:
+        t.join(10)
+
+    # the cycle is reported instead of deadlocking
+    assert not any(t.is_alive() for t in threads)
+    assert len(errors) == 2
+    assert all("load cycle detected" in str(e) for e in errors)
+
+
+def test_filesystem_loader_graph(tmp_path: pathlib.Path):
+    write_files(
+        tmp_path,
+        {
+            "top.star": "load('left.star', 'l')\nload('right.star', 'r')\nx = l + r",
+            "left.star": "load('base.star', 'b')\nwait()\nl = b + 1",
+            "right.star": "load('base.star', 'b')\nwait()\nr = b + 2",
+            "base.star": "b = 10",
+            "other.star": "o = 5",
+        },
+    )
+    # the two sides of the diamond only pass the barrier if they are
+    # evaluated at the same time
+    barrier = threading.Barrier(2, timeout=10)
+    gb = xingque.GlobalsBuilder.standard()
+    gb.set("wait", lambda: barrier.wait() and None)
+    loader = xingque.FilesystemLoader([tmp_path], gb.build())
+
+    modules = loader.load_graph(["top.star", "other.star"], max_workers=2)
+    real_path = tmp_path.resolve()
+    assert sorted(modules) == sorted(
+        str(real_path / x)
+        for x in ["top.star", "left.star", "right.star", "base.star", "other.star"]
+    )
+    assert modules[str(real_path / "top.star")].get("x") == 23
+    assert modules[str(real_path / "other.star")].get("o") == 5
+
+    # the modules are cached like the ones loaded one by one
+    assert loader.load("left.star").get("l") == 11
+    assert list(loader.load_graph(["base.star"])) == [str(real_path / "base.star")]
+
+    with pytest.raises(ValueError):
+        loader.load_graph(["top.star"], max_workers=0)
+
+
+def test_filesystem_loader_graph_errors(tmp_path: pathlib.Path):
+    write_files(
+        tmp_path,
+        {
+            "a.star": "load('b.star', 'b')\na = 1",
+            "b.star": "load('a.star', 'a')\nb = 1",
+            "c.star": "load('missing.star', 'm')\nc = 1",
+            "d.star": "load('e.star', 'e')\nd = 1",
+            "e.star": "e = 1 // 0",
+        },
+    )
+    loader = xingque.FilesystemLoader([tmp_path], xingque.Globals.standard())
+
+    real_path = tmp_path.resolve()
+    with pytest.raises(xingque.LoadError) as excinfo:
+        loader.load_graph(["a.star"])
+    cycle = [str(real_path / x) for x in ["a.star", "b.star", "a.star"]]
+    assert excinfo.value.cycle == cycle
+    assert excinfo.value.import_stack == cycle[:2]
+
+    with pytest.raises(xingque.LoadError) as excinfo:
+        loader.load_graph(["c.star"])
+    assert excinfo.value.module_id == "missing.star"
+    assert excinfo.value.import_stack == [str(real_path / "c.star")]
+
+    with pytest.raises(xingque.EvalError, match="division by zero"):
+        loader.load_graph(["d.star"])
@@ -1,5 +1,7 @@
 import functools
 
+import pytest
+
 import xingque
 
 
@@ -271,3 +273,177 @@ bitnot = ~foo
     assert m.get("bitor") == "or:123"
     assert m.get("bitxor") == "xor:123"
     assert m.get("bitnot") == "invert"
+
+
+def test_subscript_forwarding():
+    class Seq:
+        def __init__(self) -> None:
+            self.data = list(range(10))
+            self.keys_seen: list[object] = []
+
+        def __getitem__(self, key: object) -> object:
+            self.keys_seen.append(key)
+            return self.data[key]  # type: ignore[index]
+
+        def __setitem__(self, key: int, value: int) -> None:
+            self.data[key] = value
+
+    text = """
+item = seq[3]
+neg_item = seq[-1]
+seq[0] = 100
+sliced = seq[1:8:2]
+sliced_open = seq[:3]
+"""
+
+    seq = Seq()
+    am = xingque.AstModule.parse("test.star", text)
+    gb = xingque.GlobalsBuilder.standard()
+    gb.set("seq", seq)
+    m = xingque.Module()
+    e = xingque.Evaluator(m)
+    e.eval_module(am, gb.build())
+
+    assert m.get("item") == 3
+    assert m.get("neg_item") == 9
+    assert seq.data[0] == 100
+    assert m.get("sliced") == [1, 3, 5, 7]
+    assert m.get("sliced_open") == [100, 1, 2]
+    assert seq.keys_seen[-2:] == [slice(1, 8, 2), slice(None, 3, None)]
+
+
+def test_iteration_forwarding():
+    pulled: list[int] = []
+
+    def gen():
+        for i in range(5):
+            pulled.append(i)
+            yield i
+
+    text = """
+def first_two(xs):
+    result = []
+    for x in xs:
+        if len(result) == 2:
+            break
+        result.append(x)
+    return result
+
+from_range = [x * 2 for x in rng]
+from_gen = first_two(g)
+listed = list(rng)
+"""
+
+    am = xingque.AstModule.parse("test.star", text)
+    gb = xingque.GlobalsBuilder.standard()
+    gb.set("rng", range(3))
+    gb.set("g", gen())
+    m = xingque.Module()
+    e = xingque.Evaluator(m)
+    e.eval_module(am, gb.build())
+
+    assert m.get("from_range") == [0, 2, 4]
+    assert m.get("from_gen") == [0, 1]
+    # items are pulled lazily
+    assert pulled == [0, 1, 2]
+    assert m.get("listed") == [0, 1, 2]
+
+
+def test_iteration_of_infinite_iterators():
+    def naturals():
+        i = 0
+        while True:
+            yield i
+            i += 1
+
+    text = """
+def until(xs, n):
+    result = []
+    for x in xs:
+        if x == n:
+            break
+        result.append(x)
+    return result
+
+below_3 = until(g, 3)
+"""
+    gb = xingque.GlobalsBuilder.standard()
+    gb.set("g", naturals())
+    m = xingque.Module()
+    e = xingque.Evaluator(m)
+    e.eval_module(xingque.AstModule.parse("test.star", text), gb.build())
+    assert m.get("below_3") == [0, 1, 2]
+
+    # items count as steps, so limits bound comprehensions over them too
+    gb = xingque.GlobalsBuilder.standard()
+    gb.set("g", naturals())
+    e = xingque.Evaluator()
+    e.set_step_limit(1000)
+    ast = xingque.AstModule.parse("test.star", "xs = [x for x in g]\nlen(xs)")
+    with pytest.raises(xingque.StarlarkResourceExhausted, match="step limit"):
+        e.eval_module(ast, gb.build())
+
+
+def test_call_starlark_callables():
+    text = """
+def square(x):
+    print("square", x)
+    return x * x
+
+add = lambda a, b = 2: a + b
+square_5 = partial(square, 5)
+via_python = apply(square, 3)
+nested = apply(lambda x: apply(square, x), 3)
+r = range(3)
+"""
+
+    def apply(f, v):
+        # reuses the evaluator calling into Python
+        return f(v) + 1
+
+    gb = xingque.GlobalsBuilder.extended_by(
+        [xingque.LibraryExtension.PRINT, xingque.LibraryExtension.PARTIAL]
+    )
+    gb.set("apply", apply)
+    g = gb.build()
+    m = xingque.Module()
+    e = xingque.Evaluator(m)
+    e.eval_module(xingque.AstModule.parse("test.star", text), g)
+    assert m.get("via_python") == 10
+    assert m.get("nested") == 11
+
+    fm = m.freeze()
+    square = fm.get("square")
+    assert isinstance(square, xingque.CallableFrozenValue)
+    assert square(4) == 16
+    # only callable values can be called
+    assert not callable(fm.get("r"))
+    assert fm.get("add")(1, b=10) == 11
+    assert fm.get("square_5")() == 25
+    assert dict(g)["len"]([1, 2, 3]) == 3
+
+    class PrintHandler:
+        def __init__(self) -> None:
+            self.lines: list[str] = []
+
+        def println(self, text: str) -> None:
+            self.lines.append(text)
+
+    ph = PrintHandler()
+    e2 = xingque.Evaluator()
+    e2.set_print_handler(ph)
+    with e2:
+        assert square(6) == 36
+    assert ph.lines == ["square 6"]
+
+    # evaluators have to be exited in the reverse order they were entered
+    e3 = xingque.Evaluator()
+    e2.__enter__()
+    e3.__enter__()
+    with pytest.raises(RuntimeError):
+        e2.__exit__(None, None, None)
+    e3.__exit__(None, None, None)
+    e2.__exit__(None, None, None)
+
+    with pytest.raises(xingque.EvalError):
+        square()
@@ -0,0 +1,214 @@
+import dataclasses
+import enum
+import types
+import typing
+
+import pytest
+import xingque
+
+
+@dataclasses.dataclass(frozen=True)
+class Endpoint:
+    host: str
+    port: int
+
+
+@dataclasses.dataclass
+class Config:
+    name: str
+    endpoints: typing.List[Endpoint]
+    tags: typing.ClassVar[typing.List[str]] = []
+
+
+@dataclasses.dataclass
+class Link:
+    src: Endpoint
+    dst: Endpoint
+
+
+class Size(typing.NamedTuple):
+    width: int
+    height: int
+
+
+EXTENSIONS = [
+    xingque.LibraryExtension.STRUCT_TYPE,
+    xingque.LibraryExtension.RECORD_TYPE,
+    xingque.LibraryExtension.ENUM_TYPE,
+]
+
+
+def eval_with(code, evaluator=None, **values):
+    e = evaluator or xingque.Evaluator()
+    # the globals are converted with the conversion settings of the evaluator
+    with e:
+        gb = xingque.GlobalsBuilder.extended_by(EXTENSIONS)
+        for k, v in values.items():
+            gb.set(k, v)
+        gs = gb.build()
+    return e.eval_module(xingque.AstModule.parse("x.star", code), gs)
+
+
+def struct_evaluator(**kwargs):
+    e = xingque.Evaluator()
+    e.set_struct_conversion(True, **kwargs)
+    return e
+
+
+def test_struct_conversion_disabled():
+    assert eval_with("type(c)", c=Endpoint("a", 1)) == "pyobject"
+    assert eval_with("type(s)", s=types.SimpleNamespace(a=1)) == "pyobject"
+    assert isinstance(eval_with("struct(a = 1)"), xingque.Value)
+
+
+def test_to_struct():
+    c = Config("x", [Endpoint("a", 1), Endpoint("b", 2)])
+    code = "[type(c), c.name, [e.host + ':' + str(e.port) for e in c.endpoints], dir(c)]"
+    assert eval_with(code, struct_evaluator(), c=c) == [
+        "struct",
+        "x",
+        ["a:1", "b:2"],
+        ["endpoints", "name"],
+    ]
+    code = "[type(s), s.width * s.height, {s: 1}[s]]"
+    assert eval_with(code, struct_evaluator(), s=Size(2, 3)) == ["struct", 6, 1]
+    # arguments and results of Python functions called during evaluations too
+    e = struct_evaluator()
+    f = e.eval_module(xingque.AstModule.parse("f.star", "lambda s: s.width"), xingque.Globals())
+    assert e.eval_function(f, Size(2, 3)) == 2
+
+
+def test_from_struct():
+    result = eval_with("struct(a = 1, b = struct(c = [2]))", struct_evaluator())
+    assert result == types.SimpleNamespace(a=1, b=types.SimpleNamespace(c=[2]))
+    # namespaces round-trip as structs
+    assert eval_with("[type(s), s.b.c]", struct_evaluator(), s=result) == ["struct", [2]]
+    # records of unregistered types become dicts
+    code = "R = record(a = int, b = field(list, []))\nR(a = 1)"
+    assert eval_with(code, struct_evaluator()) == {"a": 1, "b": []}
+
+    e = struct_evaluator(struct_type=dict)
+    assert eval_with("s", e, s=Size(2, 3)) == {"width": 2, "height": 3}
+
+
+def test_struct_conversion_per_evaluator():
+    e = struct_evaluator(struct_type=dict)
+    # other evaluators are unaffected
+    assert isinstance(eval_with("struct(a = 1)"), xingque.Value)
+    # the struct type is kept unless given
+    e.set_struct_conversion(False)
+    assert isinstance(eval_with("struct(a = 1)", e), xingque.Value)
+    e.set_struct_conversion(True)
+    assert eval_with("struct(a = 1)", e) == {"a": 1}
+
+
+def test_record():
+    m = xingque.Module()
+    e = xingque.Evaluator(m)
+    gs = xingque.Globals.extended_by(EXTENSIONS)
+    code = """
+Endpoint = record(host = str, port = int)
+Link = record(src = Endpoint, dst = Endpoint)
+E = enum('a')
+f = lambda: 1
+"""
+    e.eval_module(xingque.AstModule.parse("types.star", code), gs)
+    fm = m.freeze()
+
+    for name in ("f", "E"):
+        with pytest.raises(TypeError, match="expected a frozen record type"):
+            xingque.register_record_type(Endpoint, fm.get(name))
+
+    xingque.register_record_type(Endpoint, fm.get("Endpoint"))
+    xingque.register_record_type(Link, fm.get("Link"))
+    try:
+        code = "[type(e), e.host, e.port, e == Endpoint(host = 'a', port = 1)]"
+        assert eval_with(code, e=Endpoint("a", 1), Endpoint=fm.get("Endpoint")) == [
+            "record",
+            "a",
+            1,
+            True,
+        ]
+        # records of the type convert back
+        assert eval_with("[e]", e=Endpoint("a", 1)) == [Endpoint("a", 1)]
+        # field types are checked
+        with pytest.raises(xingque.EvalError):
+            eval_with("e", e=Endpoint("a", "1"))
+
+        # nested records are converted along with the enclosing one
+        link = Link(Endpoint("a", 1), Endpoint("b", 2))
+        code = "[type(l), l.src.host, l.dst.port, l.src == l.dst, l]"
+        assert eval_with(code, l=link) == ["record", "a", 2, False, link]
+    finally:
+        assert xingque.unregister_record_type(Link)
+        assert xingque.unregister_record_type(Endpoint)
+
+    assert not xingque.unregister_record_type(Endpoint)
+
+
+class Color(enum.Enum):
+    RED = "red"
+    GREEN = "green"
+
+
+def test_enum():
+    m = xingque.Module()
+    e = xingque.Evaluator(m)
+    gs = xingque.Globals.extended_by(EXTENSIONS)
+    code = "Color = enum('red', 'green')\nSize = record(w = int)"
+    e.eval_module(xingque.AstModule.parse("types.star", code), gs)
+    fm = m.freeze()
+
+    with pytest.raises(TypeError, match="expected a frozen enum type"):
+        xingque.register_enum_type(Color, fm.get("Size"))
+
+    xingque.register_enum_type(Color, fm.get("Color"))
+    try:
+        code = "[type(c), c.value, c.index, c == Color('green'), Color('red')]"
+        assert eval_with(code, c=Color.GREEN, Color=fm.get("Color")) == [
+            "enum",
+            "green",
+            1,
+            True,
+            Color.RED,
+        ]
+
+        # enum values of modules yet to be frozen convert too
+        gb = xingque.GlobalsBuilder.extended_by(EXTENSIONS)
+        gb.set("Color", fm.get("Color"))
+        m2 = xingque.Module()
+        e2 = xingque.Evaluator(m2)
+        code = "c = Color('green')\nd = {'c': c}\nc"
+        assert e2.eval_module(xingque.AstModule.parse("c.star", code), gb.build()) == Color.GREEN
+        assert m2.get("c") == Color.GREEN
+        assert m2.get("d") == {"c": Color.GREEN}
+    finally:
+        assert xingque.unregister_enum_type(Color)
+
+    # values of unregistered enum types are left opaque
+    assert isinstance(eval_with("C = enum('a')\nC('a')"), xingque.Value)
+
+
+def test_strict_conversion():
+    def strict_eval(code, **values):
+        gb = xingque.GlobalsBuilder.extended_by(EXTENSIONS)
+        for k, v in values.items():
+            gb.set(k, v)
+        e = xingque.Evaluator()
+        e.set_strict_conversion(True)
+        return e.eval_module(xingque.AstModule.parse("x.star", code), gb.build())
+
+    with pytest.raises(TypeError, match="of type 'struct' to Python"):
+        strict_eval("struct(f = [1])")
+    with pytest.raises(TypeError, match="of type 'function' to Python"):
+        strict_eval("[lambda: 1]")
+    # values passed to Python during the evaluation too
+    with pytest.raises(TypeError, match="of type 'function' to Python"):
+        strict_eval("f(lambda: 1)", f=lambda x: x)
+
+    e = struct_evaluator()
+    e.set_strict_conversion(True)
+    assert eval_with("struct(f = [1])", e) == types.SimpleNamespace(f=[1])
+
+    # other evaluators are unaffected
+    assert isinstance(eval_with("lambda: 1"), xingque.Value)
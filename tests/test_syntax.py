@@ -0,0 +1,121 @@
+import xingque
+
+
+LINT_TEXT = """
+load("foo.star", "bar")
+
+def f(x):
+    y = 1
+    return x
+"""
+
+
+def test_lint():
+    am = xingque.AstModule.parse("test.star", LINT_TEXT, xingque.Dialect.EXTENDED)
+    lints = am.lint()
+    names = {x.short_name for x in lints}
+    assert "unused-load" in names
+    assert "unused-assign" in names
+
+    for x in lints:
+        assert isinstance(x.severity, xingque.EvalSeverity)
+        assert isinstance(x.serious, bool)
+        assert x.location.filename == "test.star"
+        assert x.resolved_location.file == "test.star"
+        assert x.problem
+        assert x.problem in str(x)
+
+    unused_load = next(x for x in lints if x.short_name == "unused-load")
+    assert unused_load.resolved_location.span.begin.line == 1
+
+
+def test_lint_disabled():
+    am = xingque.AstModule.parse("test.star", LINT_TEXT, xingque.Dialect.EXTENDED)
+    lints = am.lint(disabled={"unused-load"})
+    names = {x.short_name for x in lints}
+    assert "unused-load" not in names
+    assert "unused-assign" in names
+
+    assert {x.short_name for x in am.lint({"unused-load"})} == names
+
+
+def test_lint_globals():
+    am = xingque.AstModule.parse("test.star", "print(undefined_thing)")
+    assert not am.lint()
+
+    lints = am.lint(globals={"print"})
+    assert [x.short_name for x in lints] == ["using-undefined"]
+
+
+TYPECHECK_TEXT = """
+def f(x: int) -> str:
+    return x
+
+def g() -> int:
+    return 1
+
+y = g()
+"""
+
+
+def test_typecheck():
+    am = xingque.AstModule.parse("test.star", TYPECHECK_TEXT, xingque.Dialect.EXTENDED)
+    errors, type_map, interface, approximations = am.typecheck(
+        xingque.Globals.standard()
+    )
+
+    assert len(errors) == 1
+    e = errors[0]
+    assert isinstance(e, xingque.TypeCheckError)
+    assert isinstance(e, xingque.StarlarkError)
+    assert e.span is not None
+    assert e.span.source_span == "return x"
+    assert "str" in e.message
+
+    assert str(type_map)
+
+    assert "g" in interface
+    assert interface.keys() == ["f", "g", "y"]
+    assert list(interface) == ["f", "g", "y"]
+    assert "nonexistent" not in interface
+    assert interface.get("nonexistent") is None
+    g_ty = interface.get("g")
+    assert isinstance(g_ty, xingque.Ty)
+    assert g_ty == interface.get("g")
+    assert hash(g_ty) == hash(interface.get("g"))
+    assert str(g_ty)
+
+    assert isinstance(approximations, list)
+
+    # the AstModule can be typechecked again, and still be evaluated
+    errors, _, _, _ = am.typecheck(xingque.Globals.standard())
+    assert len(errors) == 1
+    e = xingque.Evaluator()
+    e.eval_module(am, xingque.Globals.standard())
+    assert e.module.get("y") == 1
+
+
+def test_typecheck_loads():
+    lib = xingque.AstModule.parse(
+        "lib.star", "def h() -> str:\n    return 'a'\n", xingque.Dialect.EXTENDED
+    )
+    errors, _, lib_interface, _ = lib.typecheck(xingque.Globals.standard())
+    assert not errors
+    assert list(lib_interface) == ["h"]
+    assert list(xingque.Interface()) == []
+
+    text = """
+load("lib.star", "h")
+
+def k() -> int:
+    return h()
+"""
+    am = xingque.AstModule.parse("test.star", text, xingque.Dialect.EXTENDED)
+    errors, _, interface, _ = am.typecheck(
+        xingque.Globals.standard(), {"lib.star": lib_interface}
+    )
+    assert len(errors) == 1
+    assert errors[0].span.source_span == "return h()"
+    # loaded names are bound by the module too
+    assert list(interface) == ["h", "k"]
+    assert interface.keys() == ["h", "k"]
@@ -0,0 +1,49 @@
+import threading
+
+import pytest
+import xingque
+
+
+def test_eval_releases_gil():
+    started = threading.Event()
+    finished = threading.Event()
+    gb = xingque.GlobalsBuilder.standard()
+    gb.set("started", lambda: started.set())
+    globals = gb.build()
+
+    m = xingque.Module()
+    m.set("x", 1)
+    ast = xingque.AstModule.parse(
+        "busy.star",
+        "def f():\n  started()\n  n = 0\n  for i in range(3000000):\n    n += i\n  return n\nf()",
+    )
+    results = []
+
+    def run():
+        e = xingque.Evaluator(m)
+        results.append(e.eval_module(ast, globals))
+        finished.set()
+
+    t = threading.Thread(target=run)
+    t.start()
+    assert started.wait(10)
+    # the pure-Starlark loop runs without the GIL, so we get to run meanwhile,
+    # but the module cannot be used by us until the evaluation is done
+    assert not finished.is_set()
+    with pytest.raises(RuntimeError, match="another thread"):
+        m.get("x")
+    t.join()
+
+    assert results == [sum(range(3000000))]
+    assert m.get("x") == 1
+    m.freeze()
+
+
+def test_module_cannot_be_frozen_while_evaluated():
+    m = xingque.Module()
+    gb = xingque.GlobalsBuilder.standard()
+    gb.set("freeze", lambda: m.freeze())
+    e = xingque.Evaluator(m)
+    with pytest.raises(RuntimeError, match="while being evaluated"):
+        e.eval_module(xingque.AstModule.parse("x.star", "freeze()"), gb.build())
+    assert m.freeze() is not None
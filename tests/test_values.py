@@ -1,3 +1,6 @@
+import gc
+
+import pytest
 import xingque
 
 
@@ -10,3 +13,195 @@ def test_empty_heap():
     s = h.allocated_summary()
     assert s.summary() == {}
     assert s.total_allocated_bytes == 0
+
+
+def test_value_magic_methods():
+    text = """
+s = struct(a = 1, b = [1, 2], c = struct(d = "x"))
+r = range(10)
+"""
+
+    g = xingque.Globals.extended_by([xingque.LibraryExtension.STRUCT_TYPE])
+    m = xingque.Module()
+    e = xingque.Evaluator(m)
+    e.eval_module(xingque.AstModule.parse("test.star", text), g)
+
+    s = m.get("s")
+    assert isinstance(s, xingque.Value)
+    assert s.a == 1
+    assert s.b == [1, 2]
+    assert s.c.d == "x"
+    assert dir(s) == ["a", "b", "c"]
+    assert not hasattr(s, "nonexistent")
+    assert str(s) == 'struct(a=1, b=[1, 2], c=struct(d="x"))'
+    assert s == s
+    assert s != s.c
+    assert bool(s)
+    with pytest.raises(TypeError):
+        hash(s)
+    with pytest.raises(TypeError):
+        s + 1
+
+    r = m.get("r")
+    assert len(r) == 10
+    assert r[3] == 3
+    assert str(r[2:8:2]) == "range(2, 8, 2)"
+    assert list(r) == list(range(10))
+    assert 5 in r
+    assert 11 not in r
+    with pytest.raises(IndexError):
+        r[100]
+
+
+def test_frozen_value_magic_methods():
+    gb = xingque.GlobalsBuilder.standard()
+    gb.struct("ns", lambda b: b.set("x", 42))
+    g = gb.build()
+    ns = dict(g)["ns"]
+    assert isinstance(ns, xingque.FrozenValue)
+    assert ns.x == 42
+    assert dir(ns) == ["x"]
+    assert ns == ns
+    with pytest.raises(TypeError, match="not hashable"):
+        hash(ns)
+
+
+def test_value_heap_tracking():
+    g = xingque.Globals.extended_by([xingque.LibraryExtension.STRUCT_TYPE])
+    m = xingque.Module()
+    e = xingque.Evaluator(m)
+    e.eval_module(xingque.AstModule.parse("test.star", "s = struct(a = 1)"), g)
+    s = m.get("s")
+    del e
+
+    # values cannot be moved across heaps
+    m2 = xingque.Module()
+    with pytest.raises(ValueError):
+        m2.set("s", s)
+    m.set("t", s)
+    assert m.get("t") == s
+
+    # the value keeps its module alive
+    del m
+    assert s.a == 1
+
+    # but becomes unusable once the module is frozen
+    m = xingque.Module()
+    e = xingque.Evaluator(m)
+    e.eval_module(xingque.AstModule.parse("test.star", "s = struct(a = 1)"), g)
+    s = m.get("s")
+    del e
+    m.freeze()
+    with pytest.raises(RuntimeError):
+        s.a
+    assert repr(s) == "<Starlark value from a frozen Module>"
+
+
+def test_value_survives_gc():
+    g = xingque.Globals.extended_by([xingque.LibraryExtension.STRUCT_TYPE])
+    m = xingque.Module()
+    e = xingque.Evaluator(m)
+    # not referenced by the module, so only Python keeps it alive
+    s = e.eval_module(xingque.AstModule.parse("a.star", "struct(a = [1, 2], b = 'x' * 3)"), g)
+    m.extra_value = [3]
+
+    # garbage collections happen between top-level statements
+    text = "x = list(range(100000))\n" * 50
+    e.eval_module(xingque.AstModule.parse("b.star", text), g)
+    assert s.a == [1, 2]
+    assert s.b == "xxx"
+    assert m.extra_value == [3]
+
+
+def test_frozen_value_keeps_heap_alive():
+    def make_module():
+        g = xingque.Globals.extended_by([xingque.LibraryExtension.STRUCT_TYPE])
+        m = xingque.Module()
+        e = xingque.Evaluator(m)
+        text = "s = struct(a = [1, 2])\ndef f(x): return x * 2\n"
+        e.eval_module(xingque.AstModule.parse("test.star", text), g)
+        return m.freeze()
+
+    fm = make_module()
+    s = fm.get("s")
+    f = fm.get("f")
+    del fm
+    gc.collect()
+    assert isinstance(s, xingque.FrozenValue)
+    assert s.a == [1, 2]
+    assert f(21) == 42
+
+    # values can be moved to other heaps, keeping their heaps alive there
+    gb = xingque.GlobalsBuilder.standard()
+    gb.set("s", s)
+    g = gb.build()
+    m = xingque.Module()
+    m.set("f", f)
+    del s, f
+    gc.collect()
+    e = xingque.Evaluator(m)
+    assert e.eval_module(xingque.AstModule.parse("u.star", "f(s.a)"), g) == [1, 2, 1, 2]
+
+    gb = xingque.GlobalsBuilder.standard()
+    gb.struct("ns", lambda b: b.set("x", [1]))
+    ns = dict(gb.build())["ns"]
+    gc.collect()
+    assert ns.x == [1]
+
+
+def test_set_conversion():
+    gb = xingque.GlobalsBuilder.extended_by([xingque.LibraryExtension.SET_TYPE])
+    gb.set("fs", frozenset(["b", "c", "a"]))
+    gb.set("make", lambda: {8, 7})
+    m = xingque.Module()
+    m.set("s", {3, 1, 2})
+    m.set("mixed", {1, "a"})
+    e = xingque.Evaluator(m)
+    code = """
+s.add(4)
+made = set([5, 6])
+returned = make()
+returned.add(9)
+def size(x):
+    return len(x)
+[type(s), type(fs), list(fs), 'a' in fs, 4 in s, len(mixed)]
+"""
+    g = gb.build()
+    result = e.eval_module(xingque.AstModule.parse("test.star", code), g)
+    # the elements are added in order if they can be sorted, as Starlark sets
+    # are ordered
+    assert result == ["set", "set", ["a", "b", "c"], True, True, 2]
+
+    # and Starlark sets come back as Python sets
+    assert m.get("s") == {1, 2, 3, 4}
+    assert m.get("made") == {5, 6}
+    # sets from Python are made on the heap they are used on
+    assert m.get("returned") == {7, 8, 9}
+    assert e.eval_function(m.get("size"), {1, 2}) == 2
+    fm = m.freeze()
+    assert fm.get("s") == {1, 2, 3, 4}
+    assert fm.get("mixed") == {1, "a"}
+    assert dict(g)["fs"] == {"a", "b", "c"}
+
+
+def test_set_conversion_errors():
+    class Unordered:
+        def __lt__(self, other: object) -> bool:
+            raise RuntimeError("boom")
+
+        def __hash__(self) -> int:
+            return 0
+
+    # only the elements not being comparable is expected
+    m = xingque.Module()
+    with pytest.raises(RuntimeError, match="boom"):
+        m.set("s", {Unordered(), Unordered()})
+
+
+def test_bytes_conversion():
+    m = xingque.Module()
+    for v in (b"abc", bytearray(b"abc")):
+        with pytest.raises(TypeError, match="has no bytes type"):
+            m.set("b", v)
+    with pytest.raises(TypeError, match="bytes cannot be converted"):
+        xingque.GlobalsBuilder.standard().set("b", [b"abc"])
@@ -1,8 +1,46 @@
-from typing import Callable, Iterable, Iterator, Protocol, Self
+import enum
+import inspect
+import os
+from typing import Callable, Iterable, Iterator, Protocol, Self, TypeVar, overload
 
 VERSION: str
 STARLARK_RUST_VERSION: str
 
+# starlark::analysis
+
+class EvalSeverity:
+    """A standardised set of severities."""
+
+    ERROR: EvalSeverity
+    """An error while the program was being parsed."""
+
+    WARNING: EvalSeverity
+    """The program parsed, but might not execute without error."""
+
+    ADVICE: EvalSeverity
+    """This could be changed, but is not a blocking problem."""
+
+    DISABLED: EvalSeverity
+    """This should be ignored"""
+
+class Lint:
+    """A lint produced by `AstModule.lint`."""
+
+    @property
+    def location(self) -> FileSpan: ...
+    @property
+    def resolved_location(self) -> ResolvedFileSpan: ...
+    @property
+    def short_name(self) -> str: ...
+    @property
+    def severity(self) -> EvalSeverity: ...
+    @property
+    def serious(self) -> bool: ...
+    @property
+    def problem(self) -> str: ...
+    @property
+    def original(self) -> str: ...
+
 # starlark::codemap
 
 class CodeMap:
@@ -92,6 +130,116 @@ class Span:
     def __contains__(self, pos: Pos | int) -> bool: ...
     def contains(self, pos: Pos | int) -> bool: ...
 
+# starlark::docs
+
+class DocString:
+    """The documentation provided by a user for a specific module, object, function, etc."""
+
+    @property
+    def summary(self) -> str: ...
+    @property
+    def details(self) -> str | None: ...
+
+class DocParamKind:
+    """The kinds of `DocParam`."""
+
+    ARG: DocParamKind
+    """A regular parameter that may or may not have a default value."""
+
+    NO_ARGS: DocParamKind
+    """Represents the "*" argument."""
+
+    ONLY_POS_BEFORE: DocParamKind
+    """Represents the "/" argument from PEP 570."""
+
+    ARGS: DocParamKind
+    """Represents the "*args" style of argument."""
+
+    KWARGS: DocParamKind
+    """Represents the "**kwargs" style of argument."""
+
+class DocParam:
+    """An argument accepted by a function, or one of the `*` and `/` markers."""
+
+    @property
+    def kind(self) -> DocParamKind: ...
+    @property
+    def name(self) -> str | None: ...
+    @property
+    def docs(self) -> DocString | None: ...
+    @property
+    def typ(self) -> Ty | None: ...
+    @property
+    def default_value(self) -> str | None: ...
+
+class DocReturn:
+    """Details about the return value of a function."""
+
+    @property
+    def docs(self) -> DocString | None: ...
+    @property
+    def typ(self) -> Ty: ...
+
+class DocFunction:
+    """Documents a single function."""
+
+    @property
+    def docs(self) -> DocString | None: ...
+    @property
+    def params(self) -> list[DocParam]: ...
+    @property
+    def ret(self) -> DocReturn: ...
+
+class DocProperty:
+    """A single property of an object. These are explicitly not functions."""
+
+    @property
+    def docs(self) -> DocString | None: ...
+    @property
+    def typ(self) -> Ty: ...
+
+class DocType:
+    """Documents a type, with its methods and properties."""
+
+    @property
+    def docs(self) -> DocString | None: ...
+    @property
+    def members(self) -> dict[str, DocFunction | DocProperty]: ...
+    @property
+    def ty(self) -> Ty: ...
+    @property
+    def constructor(self) -> DocFunction | None: ...
+
+class DocModule:
+    """Documents a full module."""
+
+    @property
+    def docs(self) -> DocString | None: ...
+    @property
+    def members(
+        self,
+    ) -> dict[str, DocFunction | DocProperty | DocType | DocModule]: ...
+
+def render_docs_markdown(
+    module: Globals | FrozenModule,
+    prefix: str,
+) -> dict[str, str]: ...
+
+# converters
+
+def register_converter(
+    py_type: type,
+    to_starlark: Callable[[object], object],
+    from_starlark: Callable[[object], object] | None = None,
+    *,
+    sl_type: str | None = None,
+) -> None: ...
+def unregister_converter(py_type: type) -> bool: ...
+def register_record_type(py_type: type, record_type: FrozenValue) -> None: ...
+def unregister_record_type(py_type: type) -> bool: ...
+def register_enum_type(py_type: type[enum.Enum], enum_type: FrozenValue) -> None: ...
+def unregister_enum_type(py_type: type[enum.Enum]) -> bool: ...
+
 # starlark::environment
 
 class FrozenModule:
@@ -101,7 +249,7 @@ class FrozenModule:
     def get(self, name: str) -> object | None: ...
     def names(self) -> Iterator[str]: ...
     def describe(self) -> str: ...
-    # TODO: documentation
+    def documentation(self) -> DocModule: ...
     # TODO: aggregated_heap_profile_info
     @property
     def extra_value(self) -> object | None: ...
@@ -117,7 +265,7 @@ class Globals:
     def describe(self) -> str: ...
     @property
     def docstring(self) -> str | None: ...
-    # TODO: documentation
+    def documentation(self) -> DocModule: ...
 
 class GlobalsBuilder:
     def __init__(self) -> None: ...
@@ -132,7 +280,14 @@ class GlobalsBuilder:
     ) -> Self: ...
     def build(self) -> Globals: ...
     def set(self, name: str, value: object) -> None: ...
-    # TODO: set_function
+    def set_function(
+        self,
+        name: str,
+        f: Callable[..., object],
+        *,
+        signature: inspect.Signature | None = None,
+        doc: str | None = None,
+    ) -> None: ...
 
 class _SubGlobalsBuilder:
     def struct(self, name: str, f: Callable[[_SubGlobalsBuilder], None]) -> None: ...
@@ -141,6 +296,14 @@ class _SubGlobalsBuilder:
         self, name: str, f: Callable[[_SubGlobalsBuilder], None]
     ) -> Self: ...
     def set(self, name: str, value: object) -> None: ...
+    def set_function(
+        self,
+        name: str,
+        f: Callable[..., object],
+        *,
+        signature: inspect.Signature | None = None,
+        doc: str | None = None,
+    ) -> None: ...
 
 class LibraryExtension:
     STRUCT_TYPE: LibraryExtension
@@ -152,6 +315,10 @@ class LibraryExtension:
     ENUM_TYPE: LibraryExtension
     """Definitions to support the `enum` type, the `enum()` constructor."""
 
+    NAMESPACE_TYPE: LibraryExtension
+    """Add a function `namespace()` which acts much like `struct()` but is clear about it's
+    intended use and stricter"""
+
     MAP: LibraryExtension
     """A function `map(f, xs)` which applies `f` to each element of `xs` and returns the result."""
 
@@ -176,6 +343,12 @@ class LibraryExtension:
     PPRINT: LibraryExtension
     """Add a function `pprint(x)` which pretty-prints to stderr."""
 
+    PSTR: LibraryExtension
+    """Add a function `pstr` which is a pretty-printed version of `str`."""
+
+    PREPR: LibraryExtension
+    """Add a function `prepr` which is a pretty-printed version of `repr`."""
+
     BREAKPOINT: LibraryExtension
     """Add a function `breakpoint()` which will drop into a console-module evaluation prompt."""
 
@@ -194,6 +367,9 @@ class LibraryExtension:
     """Add a function `call_stack()` which returns a string representation of
     the current call stack."""
 
+    SET_TYPE: LibraryExtension
+    """Definitions to support the `set` type, the `set()` constructor."""
+
 class Module:
     extra_value: object | None = None
     def __init__(self) -> None: ...
@@ -202,6 +378,23 @@ class Module:
     def set(self, name: str, value: object) -> None: ...
     def freeze(self) -> FrozenModule: ...
 
+class StarlarkLibrary:
+    def __init__(self, *args: object, **kwargs: object) -> None: ...
+    def register(self, builder: GlobalsBuilder | _SubGlobalsBuilder) -> None: ...
+    def build(self, builder: GlobalsBuilder | None = None) -> Globals: ...
+
+_F = TypeVar("_F", bound=Callable[..., object])
+
+@overload
+def builtin(f: _F) -> _F: ...
+@overload
+def builtin(
+    *,
+    name: str | None = None,
+    doc: str | None = None,
+    signature: inspect.Signature | None = None,
+) -> Callable[[_F], _F]: ...
+
 # starlark::errors
 
 class Frame:
@@ -211,6 +404,88 @@ class Frame:
     def location(self) -> FileSpan | None: ...
     # TODO: write_two_lines
 
+class ErrorKind:
+    """The different kinds of errors that can be produced by starlark."""
+
+    FAIL: ErrorKind
+    """An explicit `fail` invocation."""
+
+    STACK_OVERFLOW: ErrorKind
+    """Starlark call stack overflow."""
+
+    VALUE: ErrorKind
+    """An error approximately associated with a value.
+
+    Includes unsupported operations, missing attributes, things of that sort.
+    """
+
+    FUNCTION: ErrorKind
+    """Errors relating to the way a function is called (wrong number of args, etc.)"""
+
+    SCOPE: ErrorKind
+    """Out of scope variables and similar."""
+
+    PARSER: ErrorKind
+    """Syntax error."""
+
+    FREEZE: ErrorKind
+    """Freeze errors."""
+
+    INTERNAL: ErrorKind
+    """Indicates a logic bug in starlark."""
+
+    NATIVE: ErrorKind
+    """Error from user provided native function."""
+
+    OTHER: ErrorKind
+    """Fallback option."""
+
+class StarlarkError(Exception):
+    """Base class of all errors originating from Starlark."""
+
+    kind: ErrorKind
+    span: FileSpan | None
+    """The primary location of the error, if known."""
+    message: str
+    """The error message without any diagnostic information."""
+    call_stack: CallStack
+    """The Starlark call stack at the point of error, if recorded."""
+
+class ParseError(StarlarkError):
+    """Error raised when Starlark source code fails to parse."""
+
+class EvalError(StarlarkError):
+    """Error raised when evaluation of Starlark code fails."""
+
+class StarlarkResourceExhausted(EvalError):
+    """Error raised when evaluation is aborted for exceeding its execution budget.
+
+    `span` is the location of the statement about to be executed at the time."""
+
+class StarlarkCancelled(EvalError):
+    """Error raised when evaluation is aborted because it is cancelled.
+
+    `span` is the location of the statement about to be executed at the time."""
+
+class LoadError(EvalError):
+    """Error raised when a module cannot be loaded.
+
+    `span` is the location of the offending `load()` statement."""
+
+    module_id: str
+    """The module that cannot be loaded, as given to the `load()` statement."""
+    import_stack: list[str]
+    """The modules being loaded at the time, outermost first."""
+    cycle: list[str] | None
+    """The modules involved in the load cycle, if it is one, with the first one
+    repeated at the end."""
+
+class TypeCheckError(StarlarkError):
+    """Error found by the static typechecker."""
+
+class InternalError(StarlarkError):
+    """Error indicating a logic bug in starlark-rust."""
+
 # starlark::eval
 
 class _FileLoader(Protocol):
@@ -223,6 +498,32 @@ class DictFileLoader:
     def __init__(self, modules: dict[str, FrozenModule]) -> None: ...
     def load(self, path: str) -> FrozenModule: ...
 
+class CancellationToken:
+    def __init__(self, *, check_signals: bool = False) -> None: ...
+    def cancel(self) -> None: ...
+    @property
+    def cancelled(self) -> bool: ...
+    @property
+    def check_signals(self) -> bool: ...
+
+class FilesystemLoader:
+    def __init__(
+        self,
+        roots: Iterable[str | os.PathLike[str]],
+        globals: Globals,
+        dialect: Dialect = Dialect.STANDARD,
+    ) -> None: ...
+    @property
+    def roots(self) -> list[str]: ...
+    def load(self, path: str) -> FrozenModule: ...
+    def load_graph(
+        self,
+        paths: Iterable[str],
+        *,
+        max_workers: int | None = None,
+    ) -> dict[str, FrozenModule]: ...
+    def clear_cache(self) -> None: ...
+
 class CallStack:
     @property
     def frames(self) -> list[Frame]: ...
@@ -252,6 +553,17 @@ class Evaluator:
     # TODO: frozen_heap
     # TODO: set_module_variable_at_some_point (is this okay to expose?)
     def set_max_callstack_size(self, stack_size: int) -> None: ...
+    def set_strict_conversion(self, strict: bool) -> None: ...
+    def set_struct_conversion(
+        self,
+        enabled: bool,
+        *,
+        struct_type: Callable[..., object] | None = None,
+    ) -> None: ...
+    def set_step_limit(self, limit: int | None) -> None: ...
+    def set_timeout(self, seconds: float | None) -> None: ...
+    def set_heap_limit(self, limit: int | None) -> None: ...
+    def set_cancellation_token(self, token: CancellationToken | None) -> None: ...
     def eval_module(self, ast: AstModule, globals: Globals) -> object: ...
     def eval_function(
         self,
@@ -259,6 +571,8 @@ class Evaluator:
         *args: object,
         **kwargs: object,
     ) -> object: ...
+    def __enter__(self) -> Self: ...
+    def __exit__(self, *args: object) -> None: ...
 
 class ProfileMode:
     """How to profile starlark code."""
@@ -353,11 +667,97 @@ class AstModule:
     @property
     def stmt_locations(self) -> list[FileSpan]: ...
     def replace_binary_operators(self, replace: dict[str, str]) -> None: ...
+    def lint(
+        self,
+        disabled: set[str] | None = None,
+        *,
+        globals: set[str] | None = None,
+    ) -> list[Lint]: ...
+    def documentation(self, globals: Globals | None = None) -> DocModule: ...
+    def typecheck(
+        self,
+        globals: Globals,
+        loads: dict[str, Interface] | None = None,
+    ) -> tuple[list[TypeCheckError], TypeMap, Interface, list[Approximation]]: ...
+
+# starlark::typing
+
+class Ty:
+    """A Starlark type."""
+
+    def __eq__(self, other: object) -> bool: ...
+    def __hash__(self) -> int: ...
+    def as_name(self) -> str | None: ...
+
+class Interface:
+    """Interface representing the types of all bindings in a module."""
+
+    def __init__(self) -> None: ...
+    def get(self, name: str) -> Ty | None: ...
+    def keys(self) -> list[str]: ...
+    def __contains__(self, name: str) -> bool: ...
+    def __iter__(self) -> Iterator[str]: ...
+
+class TypeMap:
+    """Structure containing all the inferred types."""
+
+class Approximation:
+    """A typing operation wasn't able to produce a precise result,
+    so made some kind of approximation.
+    """
+
+    def __eq__(self, other: object) -> bool: ...
+    def __hash__(self) -> int: ...
+    @property
+    def category(self) -> str: ...
+    @property
+    def message(self) -> str: ...
 
 # starlark::values
 
 class FrozenValue:
-    pass
+    def __getattr__(self, name: str) -> object: ...
+    def __dir__(self) -> list[str]: ...
+    def __getitem__(self, key: object) -> object: ...
+    def __len__(self) -> int: ...
+    def __iter__(self) -> Iterator[object]: ...
+    def __contains__(self, item: object) -> bool: ...
+    def __eq__(self, other: object) -> bool: ...
+    def __ne__(self, other: object) -> bool: ...
+    def __lt__(self, other: object) -> bool: ...
+    def __le__(self, other: object) -> bool: ...
+    def __gt__(self, other: object) -> bool: ...
+    def __ge__(self, other: object) -> bool: ...
+    def __hash__(self) -> int: ...
+    def __bool__(self) -> bool: ...
+    def __pos__(self) -> object: ...
+    def __neg__(self) -> object: ...
+    def __invert__(self) -> object: ...
+    def __add__(self, other: object) -> object: ...
+    def __radd__(self, other: object) -> object: ...
+    def __sub__(self, other: object) -> object: ...
+    def __rsub__(self, other: object) -> object: ...
+    def __mul__(self, other: object) -> object: ...
+    def __rmul__(self, other: object) -> object: ...
+    def __truediv__(self, other: object) -> object: ...
+    def __rtruediv__(self, other: object) -> object: ...
+    def __floordiv__(self, other: object) -> object: ...
+    def __rfloordiv__(self, other: object) -> object: ...
+    def __mod__(self, other: object) -> object: ...
+    def __rmod__(self, other: object) -> object: ...
+    def __and__(self, other: object) -> object: ...
+    def __rand__(self, other: object) -> object: ...
+    def __or__(self, other: object) -> object: ...
+    def __ror__(self, other: object) -> object: ...
+    def __xor__(self, other: object) -> object: ...
+    def __rxor__(self, other: object) -> object: ...
+    def __lshift__(self, other: object) -> object: ...
+    def __rlshift__(self, other: object) -> object: ...
+    def __rshift__(self, other: object) -> object: ...
+    def __rrshift__(self, other: object) -> object: ...
+
+class CallableFrozenValue(FrozenValue):
+    def __call__(self, *args: object, **kwargs: object) -> object: ...
 
 class HeapSummary:
     def summary(self) -> dict[str, tuple[int, int]]: ...
@@ -375,4 +775,45 @@ class Heap:
     def allocated_summary(self) -> HeapSummary: ...
 
 class Value:
-    pass
+    def __getattr__(self, name: str) -> object: ...
+    def __dir__(self) -> list[str]: ...
+    def __getitem__(self, key: object) -> object: ...
+    def __len__(self) -> int: ...
+    def __iter__(self) -> Iterator[object]: ...
+    def __contains__(self, item: object) -> bool: ...
+    def __eq__(self, other: object) -> bool: ...
+    def __ne__(self, other: object) -> bool: ...
+    def __lt__(self, other: object) -> bool: ...
+    def __le__(self, other: object) -> bool: ...
+    def __gt__(self, other: object) -> bool: ...
+    def __ge__(self, other: object) -> bool: ...
+    def __hash__(self) -> int: ...
+    def __bool__(self) -> bool: ...
+    def __pos__(self) -> object: ...
+    def __neg__(self) -> object: ...
+    def __invert__(self) -> object: ...
+    def __add__(self, other: object) -> object: ...
+    def __radd__(self, other: object) -> object: ...
+    def __sub__(self, other: object) -> object: ...
+    def __rsub__(self, other: object) -> object: ...
+    def __mul__(self, other: object) -> object: ...
+    def __rmul__(self, other: object) -> object: ...
+    def __truediv__(self, other: object) -> object: ...
+    def __rtruediv__(self, other: object) -> object: ...
+    def __floordiv__(self, other: object) -> object: ...
+    def __rfloordiv__(self, other: object) -> object: ...
+    def __mod__(self, other: object) -> object: ...
+    def __rmod__(self, other: object) -> object: ...
+    def __and__(self, other: object) -> object: ...
+    def __rand__(self, other: object) -> object: ...
+    def __or__(self, other: object) -> object: ...
+    def __ror__(self, other: object) -> object: ...
+    def __xor__(self, other: object) -> object: ...
+    def __rxor__(self, other: object) -> object: ...
+    def __lshift__(self, other: object) -> object: ...
+    def __rlshift__(self, other: object) -> object: ...
+    def __rshift__(self, other: object) -> object: ...
+    def __rrshift__(self, other: object) -> object: ...
+
+class CallableValue(Value):
+    def __call__(self, *args: object, **kwargs: object) -> object: ...